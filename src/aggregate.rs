@@ -0,0 +1,326 @@
+//! Aggregating the numeric values of a series, as a whole or in buckets of a fixed length
+//!
+//! Values are aggregated while they are read, so nothing but the current bucket is kept in
+//! memory. Percentiles are estimated with the P² algorithm of Jain and Chlamtac, which keeps
+//! five markers per percentile instead of all values.
+
+use std::fmt;
+use std::io;
+
+use series::Series;
+
+/// How the values of a bucket are combined into one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Function {
+    Min,
+    Max,
+    Avg,
+    Sum,
+    Count,
+    /// Like `p95`, given as a fraction between 0 and 1
+    Percentile(f64),
+}
+
+impl Function {
+    /// Parses `min`, `max`, `avg`, `sum`, `count`, `median` or a percentile like `p95` or `p99.9`
+    pub fn from_name(s: &str) -> Option<Function> {
+        match s {
+            "min" => Some(Function::Min),
+            "max" => Some(Function::Max),
+            "avg" => Some(Function::Avg),
+            "sum" => Some(Function::Sum),
+            "count" => Some(Function::Count),
+            "median" => Some(Function::Percentile(0.5)),
+            _ => {
+                let p = s.strip_prefix('p')?.parse::<f64>().ok()?;
+                if p > 0.0 && p < 100.0 {
+                    Some(Function::Percentile(p / 100.0))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match *self {
+            Function::Min => String::from("min"),
+            Function::Max => String::from("max"),
+            Function::Avg => String::from("avg"),
+            Function::Sum => String::from("sum"),
+            Function::Count => String::from("count"),
+            Function::Percentile(p) => format!("p{}", p * 100.0),
+        }
+    }
+}
+
+/// An estimate of a percentile, updated with every value
+#[derive(Debug, Clone)]
+struct P2 {
+    p: f64,
+    /// The first five values, sorted once there are five
+    initial: Vec<f64>,
+    /// The heights of the markers
+    q: [f64; 5],
+    /// The positions of the markers
+    n: [f64; 5],
+    /// The desired positions of the markers, and how they change with every value
+    desired: [f64; 5],
+    increment: [f64; 5],
+}
+
+impl P2 {
+    fn new(p: f64) -> P2 {
+        P2 {
+            p,
+            initial: Vec::with_capacity(5),
+            q: [0.0; 5],
+            n: [1.0, 2.0, 3.0, 4.0, 5.0],
+            desired: [1.0, 1.0 + 2.0 * p, 1.0 + 4.0 * p, 3.0 + 2.0 * p, 5.0],
+            increment: [0.0, p / 2.0, p, (1.0 + p) / 2.0, 1.0],
+        }
+    }
+
+    fn add(&mut self, x: f64) {
+        if self.initial.len() < 5 {
+            self.initial.push(x);
+            if self.initial.len() == 5 {
+                self.initial.sort_by(|a, b| a.total_cmp(b));
+                self.q.copy_from_slice(&self.initial);
+            }
+            return;
+        }
+
+        // The cell the value falls into, extending the outer markers if needed
+        let k = if x < self.q[0] {
+            self.q[0] = x;
+            0
+        } else if x >= self.q[4] {
+            self.q[4] = x;
+            3
+        } else {
+            (0..4).find(|&i| x < self.q[i + 1]).unwrap_or(3)
+        };
+        for i in k + 1..5 {
+            self.n[i] += 1.0;
+        }
+        for i in 0..5 {
+            self.desired[i] += self.increment[i];
+        }
+
+        // Moves the inner markers towards their desired positions
+        for i in 1..4 {
+            let d = self.desired[i] - self.n[i];
+            if (d >= 1.0 && self.n[i + 1] - self.n[i] > 1.0) || (d <= -1.0 && self.n[i - 1] - self.n[i] < -1.0) {
+                let d = d.signum();
+                let (q, n) = (&self.q, &self.n);
+                let parabolic = q[i] + d / (n[i + 1] - n[i - 1])
+                    * ((n[i] - n[i - 1] + d) * (q[i + 1] - q[i]) / (n[i + 1] - n[i])
+                       + (n[i + 1] - n[i] - d) * (q[i] - q[i - 1]) / (n[i] - n[i - 1]));
+                self.q[i] = if q[i - 1] < parabolic && parabolic < q[i + 1] {
+                    parabolic
+                } else {
+                    let j = if d > 0.0 { i + 1 } else { i - 1 };
+                    q[i] + d * (q[j] - q[i]) / (n[j] - n[i])
+                };
+                self.n[i] += d;
+            }
+        }
+    }
+
+    fn value(&self) -> Option<f64> {
+        if self.initial.len() == 5 {
+            return Some(self.q[2]);
+        }
+        // Too few values for markers, so they are interpolated
+        let mut values = self.initial.clone();
+        values.sort_by(|a, b| a.total_cmp(b));
+        let rank = self.p * (values.len() as f64 - 1.0);
+        let (low, high) = (*values.get(rank.floor() as usize)?, values[rank.ceil() as usize]);
+        Some(low + (high - low) * rank.fract())
+    }
+}
+
+/// The state of all functions over the values of a bucket
+#[derive(Debug, Clone)]
+struct Aggregate {
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+    percentiles: Vec<P2>,
+}
+
+impl Aggregate {
+    fn new(functions: &[Function]) -> Aggregate {
+        Aggregate {
+            count: 0,
+            sum: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            percentiles: functions.iter().filter_map(|f| match *f {
+                Function::Percentile(p) => Some(P2::new(p)),
+                _ => None,
+            }).collect(),
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        for p in &mut self.percentiles {
+            p.add(value);
+        }
+    }
+
+    fn values(&self, functions: &[Function]) -> Vec<f64> {
+        let mut percentiles = self.percentiles.iter();
+        functions.iter().map(|f| match *f {
+            Function::Min => self.min,
+            Function::Max => self.max,
+            Function::Avg => self.sum / self.count as f64,
+            Function::Sum => self.sum,
+            Function::Count => self.count as f64,
+            Function::Percentile(_) => percentiles.next().and_then(P2::value).unwrap_or(f64::NAN),
+        }).collect()
+    }
+}
+
+/// The aggregated values of a series per bucket, as the start of the bucket and the value of
+/// each function. Buckets without numeric values are left out.
+pub struct Buckets {
+    series: Series,
+    functions: Vec<Function>,
+    /// The length of a bucket, `None` for a single one starting at `from`
+    step: Option<i64>,
+    from: i64,
+    /// The start of the current bucket and its state
+    current: Option<(i64, Aggregate)>,
+}
+
+impl Buckets {
+    /// Aggregates `series`, whose values start at `from`. Buckets of `step` seconds are aligned
+    /// to the epoch, so a bucket of `1d` starts at midnight UTC.
+    pub fn new(series: Series, functions: &[Function], step: Option<i64>, from: i64) -> Buckets {
+        Buckets {
+            series,
+            functions: functions.to_vec(),
+            step,
+            from,
+            current: None,
+        }
+    }
+
+    fn emit(&self, (start, aggregate): (i64, Aggregate)) -> (i64, Vec<f64>) {
+        (start, aggregate.values(&self.functions))
+    }
+}
+
+impl fmt::Debug for Buckets {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Buckets")
+            .field("functions", &self.functions)
+            .field("step", &self.step)
+            .field("from", &self.from)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Iterator for Buckets {
+    type Item = io::Result<(i64, Vec<f64>)>;
+
+    fn next(&mut self) -> Option<io::Result<(i64, Vec<f64>)>> {
+        loop {
+            let sample = match self.series.next() {
+                Some(Ok(sample)) => sample,
+                Some(Err(e)) => return Some(Err(e)),
+                None => return self.current.take().map(|c| Ok(self.emit(c))),
+            };
+            let value = match sample.value.trim().parse::<f64>() {
+                Ok(v) if v.is_finite() => v,
+                _ => continue,
+            };
+            let time = sample.time.sec;
+            let start = match self.step {
+                Some(step) => time.div_euclid(step) * step,
+                None => if self.from == i64::MIN { time } else { self.from },
+            };
+
+            match self.current {
+                Some((current, ref mut aggregate)) if start == current || self.step.is_none() => {
+                    aggregate.add(value);
+                }
+                Some((current, _)) if start < current => {
+                    warn!("Skipping value of {} at {}, it is older than the bucket at {}", sample.key, time, current);
+                }
+                _ => {
+                    let mut aggregate = Aggregate::new(&self.functions);
+                    aggregate.add(value);
+                    if let Some(done) = self.current.replace((start, aggregate)) {
+                        return Some(Ok(self.emit(done)));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use aggregate::{Buckets, Function, P2};
+    use output::Sample;
+
+    #[test]
+    fn functions() {
+        assert_eq!(Function::from_name("avg"), Some(Function::Avg));
+        assert_eq!(Function::from_name("median"), Some(Function::Percentile(0.5)));
+        assert_eq!(Function::from_name("p95"), Some(Function::Percentile(0.95)));
+        assert_eq!(Function::from_name("p99.9").map(|f| f.name()), Some(String::from("p99.9")));
+        assert_eq!(Function::from_name("p100"), None);
+        assert_eq!(Function::from_name("mean"), None);
+    }
+
+    #[test]
+    fn percentiles() {
+        let mut few = P2::new(0.5);
+        assert_eq!(few.value(), None);
+        for &x in &[4.0, 1.0, 3.0, 2.0] {
+            few.add(x);
+        }
+        assert_eq!(few.value(), Some(2.5));
+
+        let mut median = P2::new(0.5);
+        let mut p90 = P2::new(0.9);
+        for i in 0..10000 {
+            let x = ((i * 7919) % 10000) as f64;
+            median.add(x);
+            p90.add(x);
+        }
+        assert!((median.value().unwrap() - 5000.0).abs() < 100.0);
+        assert!((p90.value().unwrap() - 9000.0).abs() < 100.0);
+    }
+
+    #[test]
+    fn buckets() {
+        let series = || Box::new([(0, "4"), (30, "2"), (59, "Charging"), (60, "9"), (200, "1\n"), (150, "7")]
+            .iter()
+            .map(|&(time, value)| Ok(Sample::new("os.battery", 1454328000 + time, value)))
+            .collect::<Vec<_>>()
+            .into_iter());
+        let functions = [Function::Min, Function::Avg, Function::Count];
+        let buckets = Buckets::new(series(), &functions, Some(60), i64::MIN)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(buckets, vec![(1454328000, vec![2.0, 3.0, 2.0]),
+                                 (1454328060, vec![9.0, 9.0, 1.0]),
+                                 (1454328180, vec![1.0, 1.0, 1.0])]);
+
+        let all = Buckets::new(series(), &[Function::Max, Function::Sum], None, 1454327000)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(all, vec![(1454327000, vec![9.0, 23.0])]);
+    }
+}
@@ -1,20 +1,78 @@
-
 use std::thread;
 use std::time::Duration;
-use std::fs::{File, OpenOptions};
-use std::process::Command;
-use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::flag;
 
+use compact;
 use conf::Config;
-use time::get_time;
+use counter::{Counter, Counters};
+use dashboard::Dashboard;
+use derived::Derived;
+use time::{get_time, Timespec};
 use item::ItemKind;
+use output::{Outputs, Sample};
+use source;
+
+/// How often outputs are given the chance to write out buffered samples
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What a run of an item sends back
+#[derive(Debug)]
+struct Run {
+    time: Timespec,
+    item: String,
+    counter: Option<Counter>,
+    /// The values recorded, or why there are none
+    results: Result<Vec<(String, String)>, String>,
+}
 
 pub fn start(mut conf: Config) {
     // We would deamonize here if necessary
 
-    loop {
+    let mut outputs = match Outputs::open(&conf.outputs, conf.items.iter().chain(conf.derived.iter())) {
+        Ok(o) => o,
+        Err(e) => {
+            error!("{}", e);
+            return println!("{}", e);
+        }
+    };
+
+    let dashboard = match conf.dashboard {
+        Some(ref d) => match Dashboard::start(d, &conf) {
+            Ok(d) => Some(d),
+            Err(e) => {
+                error!("Could not start the dashboard: {}", e);
+                return println!("Could not start the dashboard: {}", e);
+            }
+        },
+        None => None,
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    let mut derived = Derived::new(&conf.derived);
+    let mut counters = Counters::default();
+    // Held while writing, so compacting never replaces a file in the middle of it
+    let writing = Arc::new(Mutex::new(()));
+    let mut compaction: Option<(i64, Option<thread::JoinHandle<()>>)> = None;
+
+    // Stops the loop below so buffered samples are written, a second signal stops right away
+    let stop = Arc::new(AtomicBool::new(false));
+    for &signal in &[SIGTERM, SIGINT] {
+        let registered = flag::register_conditional_shutdown(signal, 1, stop.clone())
+            .and_then(|_| flag::register(signal, stop.clone()));
+        if let Err(e) = registered {
+            error!("Could not handle signal {}: {}", signal, e);
+        }
+    }
+
+    while !stop.load(Ordering::Relaxed) {
         loop {
-            let cur_time = get_time().sec;
+            let now = get_time();
+            let cur_time = now.sec;
             if let Some(c) = conf.items.peek() {
                 if c.next_time > cur_time {
                     break;
@@ -31,74 +89,85 @@ pub fn start(mut conf: Config) {
 
             let mut shell = String::new();
 
-            let mut output_folder = conf.general.output.clone();
+            let sender = sender.clone();
 
             if let ItemKind::Shell(_) = clone.kind {
                 shell = conf.general.shell.clone();
             }
 
             thread::spawn(move || {
-                let mut result = String::new();
-                match clone.kind {
-                    ItemKind::File(ref path) => {
-                        let mut f = match File::open(path) {
-                            Ok(f) => f,
-                            Err(e) => return error!("Could not open file: {}\n{}", path.display(), e),
-                        };
-                        match f.read_to_string(&mut result) {
-                            Ok(_) => (),
-                            Err(e) => return error!("Could read output from file: {},\n{}", path.display(), e),
-                        }
-                    }
-                    ItemKind::Command(ref path, ref args) => {
-                        let mut output = Command::new(path);
-                        output.args(args);
-                        for (k,v) in clone.env {
-                            output.env(k, v);
-                        }
-                        let output = match output.output() {
-                            Ok(f) => f,
-                            Err(e) => return error!("Could not run command: {}\n{}", path.display(), e)
-                        };
-                        result = match String::from_utf8(output.stdout) {
-                            Ok(r) => r,
-                            Err(e) => return error!("Could not read output from command: {}\n{}", path.display(), e)
-                        }
+                let results = source::gather(&clone, &shell, cur_time);
+                // The receiver only goes away when we are shutting down anyway
+                let _ = sender.send(Run {
+                    time: now,
+                    item: clone.key,
+                    counter: clone.counter,
+                    results,
+                });
+            });
+        }
+
+        let received = match conf.items.peek() {
+            Some(c) => {
+                let timeout = Duration::from_secs((c.next_time - get_time().sec).max(0) as u64);
+                receiver.recv_timeout(timeout.min(FLUSH_INTERVAL))
+            }
+            None => receiver.recv_timeout(FLUSH_INTERVAL),
+        };
+
+        let guard = writing.lock().unwrap_or_else(|e| e.into_inner());
+        match received {
+            Ok(run) => {
+                if let Some(ref d) = dashboard {
+                    d.ran(&run.item, run.time.sec, run.results.as_ref().err().map(|e| &e[..]));
+                }
+                let results = match run.results {
+                    Ok(r) => r,
+                    Err(e) => {
+                        error!("{}", e);
+                        Vec::new()
                     }
-                    ItemKind::Shell(ref command) => {
-                        let mut output = Command::new(shell);
-                        output.arg("-c");
-                        output.arg(command);
-                        for (k,v) in clone.env {
-                            output.env(k, v);
-                        }
-                        let output = match output.output() {
-                            Ok(f) => f,
-                            Err(e) => return error!("Could not run shell command: {}\n{}", command, e)
-                        };
-                        result = match String::from_utf8(output.stdout) {
-                            Ok(r) => r,
-                            Err(e) => return error!("Could not read output from shell command: {}\n{}", command, e)
-                        }
+                };
+                for (key, result) in results {
+                    let result = match run.counter {
+                        Some(ref c) => match counters.rate(c, &key, run.time.sec, &result) {
+                            Some(r) => r,
+                            None => continue,
+                        },
+                        None => result,
+                    };
+                    let dependents = derived.update(&key, run.time.sec, &result);
+                    outputs.write(&run.item, &Sample { time: run.time, key, value: result });
+                    for (key, result) in dependents {
+                        outputs.write(&key, &Sample { time: run.time, key: key.clone(), value: result });
                     }
                 }
-                debug!("{}={}", clone.key, result);
-                output_folder.push(clone.key);
-                match OpenOptions::new().write(true).append(true).create(true).open(&output_folder)
-                    .and_then(|mut file| {
-                        file.write(&format!("{} {}", cur_time, &result).as_bytes()[..])
-                    })
-                    {
-                        Ok(_) => (),
-                        Err(e) => {
-                            error!("Error creating file {}, {}", output_folder.display(), e)
-                        }
-                    }
-            });
+            }
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => unreachable!("we keep a sender ourselves"),
         }
-        if let Some(c) = conf.items.peek() {
-            thread::sleep(Duration::from_secs((c.next_time - get_time().sec) as u64));
+        outputs.flush();
+        drop(guard);
+
+        if let Some(interval) = conf.compact.as_ref().and_then(|c| c.interval) {
+            let now = get_time().sec;
+            let due = match compaction {
+                Some((next, ref running)) => now >= next && running.as_ref().is_none_or(|t| t.is_finished()),
+                None => true,
+            };
+            if due {
+                compaction = Some((now + interval, compact::spawn(&conf, writing.clone())));
+            }
         }
     }
+    // Compacting stopped halfway would leave its temporary files behind
+    if let Some((_, Some(running))) = compaction {
+        if !running.is_finished() {
+            info!("Stopping, waiting for compacting to finish");
+        }
+        let _ = running.join();
+    }
+    info!("Stopping, writing out buffered samples");
+    let _guard = writing.lock().unwrap_or_else(|e| e.into_inner());
+    outputs.finish();
 }
-
@@ -0,0 +1,589 @@
+//! Compacting old values in the files of file outputs
+//!
+//! Numeric values older than `keep_raw` are averaged per bucket of `step`, aligned to the
+//! epoch like the buckets of `query --step`. A bucket with a single value is left as it is, so
+//! compacting again only touches what became old since. Other values are kept as they are.
+//!
+//! Files are rewritten next to the original and renamed over it, so they can be read at any
+//! time. Records the running Antikoerper appends to a file while it is compacted are copied
+//! over while holding the lock it holds while writing, so none are lost. Other processes
+//! writing to the directory of a file output hold a shared lock on a file in it, the
+//! `compact` subcommand refuses to run while they do.
+
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use time::{self, Timespec};
+use toml;
+
+use conf::Config;
+use item::valid_key;
+use output::{OutputConfig, OutputKind, Sample};
+use output::file::{self, replaced, Layout, Records};
+use output::rotate::{self, Compression, Segment};
+use units;
+
+/// Which values are compacted and when
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CompactConfig {
+    /// How long values are kept as they were recorded, in seconds
+    pub keep_raw: i64,
+    /// The length of the buckets older values are averaged in, in seconds
+    pub step: i64,
+    /// How often the running Antikoerper compacts, `None` for only with the subcommand
+    pub interval: Option<i64>,
+    /// The names of the file outputs to compact, `None` for all
+    pub outputs: Option<Vec<String>>,
+}
+
+impl Default for CompactConfig {
+    fn default() -> CompactConfig {
+        CompactConfig {
+            keep_raw: 7 * 24 * 60 * 60,
+            step: 5 * 60,
+            interval: None,
+            outputs: None,
+        }
+    }
+}
+
+impl CompactConfig {
+    pub fn from_toml(table: &toml::Table) -> Result<CompactConfig, String> {
+        let default = CompactConfig::default();
+        let duration = |name: &str, example: &str| match table.get(name) {
+            Some(v) => units::duration_from_toml(v).map(Some).ok_or_else(|| {
+                format!("compact: {} has to be a positive number of seconds or a duration like {}", name, example)
+            }),
+            None => Ok(None),
+        };
+        Ok(CompactConfig {
+            keep_raw: duration("keep_raw", "7d")?.unwrap_or(default.keep_raw),
+            step: duration("step", "5m")?.unwrap_or(default.step),
+            interval: duration("interval", "1d")?,
+            outputs: match table.get("outputs") {
+                Some(toml::Value::Array(names)) => Some(names.iter().map(|n| match *n {
+                    toml::Value::String(ref s) => Ok(s.clone()),
+                    _ => Err(String::from("compact: outputs has to be an array of output names")),
+                }).collect::<Result<Vec<_>, _>>()?),
+                Some(_) => return Err(String::from("compact: outputs has to be an array of output names")),
+                None => None,
+            },
+        })
+    }
+
+    /// Until when values are compacted at `now`, the start of the bucket `keep_raw` ago
+    pub fn cutoff(&self, now: i64) -> i64 {
+        (now - self.keep_raw).div_euclid(self.step) * self.step
+    }
+}
+
+/// The samples of a series, with the numeric values from before `cutoff` averaged per bucket
+struct Compacted<I> {
+    samples: I,
+    cutoff: i64,
+    step: i64,
+    /// The start of the current bucket and its samples
+    bucket: Option<(i64, Vec<Sample>)>,
+    /// What is ready to be returned
+    ready: VecDeque<Sample>,
+    done: bool,
+    /// How many samples were read and how many buckets had their values averaged
+    read: u64,
+    merged: u64,
+}
+
+impl<I: Iterator<Item = io::Result<Sample>>> Compacted<I> {
+    fn new(samples: I, cutoff: i64, step: i64) -> Compacted<I> {
+        Compacted {
+            samples,
+            cutoff,
+            step,
+            bucket: None,
+            ready: VecDeque::new(),
+            done: false,
+            read: 0,
+            merged: 0,
+        }
+    }
+
+    /// Makes the samples of the current bucket ready, averaging them if there are several
+    fn close(&mut self) {
+        let (start, samples) = match self.bucket.take() {
+            Some(b) => b,
+            None => return,
+        };
+        let values = samples.iter()
+            .filter_map(|s| s.value.trim().parse::<f64>().ok().filter(|v| v.is_finite()))
+            .collect::<Vec<_>>();
+        if values.len() < 2 {
+            self.ready.extend(samples);
+            return;
+        }
+        self.merged += 1;
+        self.ready.push_back(Sample {
+            time: Timespec::new(start, 0),
+            key: samples[0].key.clone(),
+            value: (values.iter().sum::<f64>() / values.len() as f64).to_string(),
+        });
+        let numeric = |s: &Sample| s.value.trim().parse::<f64>().is_ok_and(|v| v.is_finite());
+        self.ready.extend(samples.into_iter().filter(|s| !numeric(s)));
+    }
+}
+
+impl<I: Iterator<Item = io::Result<Sample>>> Iterator for Compacted<I> {
+    type Item = io::Result<Sample>;
+
+    fn next(&mut self) -> Option<io::Result<Sample>> {
+        loop {
+            if let Some(sample) = self.ready.pop_front() {
+                return Some(Ok(sample));
+            }
+            if self.done {
+                return None;
+            }
+            let sample = match self.samples.next() {
+                Some(Ok(sample)) => sample,
+                Some(Err(e)) => return Some(Err(e)),
+                None => {
+                    self.done = true;
+                    self.close();
+                    continue;
+                }
+            };
+            self.read += 1;
+            let time = sample.time.sec;
+            if time >= self.cutoff {
+                self.close();
+                self.ready.push_back(sample);
+                continue;
+            }
+            let start = time.div_euclid(self.step) * self.step;
+            if self.bucket.as_ref().map(|&(s, _)| s) != Some(start) {
+                self.close();
+                self.bucket = Some((start, Vec::new()));
+            }
+            if let Some((_, ref mut samples)) = self.bucket {
+                samples.push(sample);
+            }
+        }
+    }
+}
+
+/// How many bytes at the start of a file of `len` bytes are complete lines, as a record may be
+/// in the middle of being appended
+fn complete(file: &File, len: u64) -> io::Result<u64> {
+    let tail = len.min(64 * 1024);
+    let mut buffer = vec![0; tail as usize];
+    file.read_exact_at(&mut buffer, len - tail)?;
+    Ok(match buffer.iter().rposition(|&b| b == b'\n') {
+        Some(i) => len - tail + i as u64 + 1,
+        None => 0,
+    })
+}
+
+/// The records of the first `len` bytes of `file`
+fn records(file: &File, key: &str, compression: Option<Compression>, len: u64) -> io::Result<Records<BufReader<Box<dyn Read>>>> {
+    let mut file = file.try_clone()?;
+    file.seek(SeekFrom::Start(0))?;
+    let reader = match compression {
+        Some(c) => c.reader(file)?,
+        None => Box::new(file.take(len)),
+    };
+    Records::new(BufReader::new(reader), key)
+}
+
+/// `path` with `suffix` appended to its name
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Rewrites the file of `key` at `path` with the numeric values from before `cutoff` averaged
+/// per bucket of `step`. Returns how many values it had before and after, or `None` if there was
+/// nothing to compact.
+pub fn compact_file(path: &Path, key: &str, compression: Option<Compression>, cutoff: i64, step: i64,
+                    writing: &Mutex<()>) -> io::Result<Option<(u64, u64)>>
+{
+    let file = File::open(path)?;
+    let len = match compression {
+        Some(_) => u64::MAX,
+        None => complete(&file, file.metadata()?.len())?,
+    };
+
+    // Most of the time everything old was compacted before, that is found out without writing
+    let mut found = Compacted::new(records(&file, key, compression, len)?, cutoff, step);
+    loop {
+        match found.next() {
+            Some(Ok(ref sample)) if found.merged == 0 && sample.time.sec < cutoff => (),
+            Some(Ok(_)) | None => break,
+            Some(Err(e)) => return Err(e),
+        }
+    }
+    if found.merged == 0 {
+        return Ok(None);
+    }
+
+    let tmp = with_suffix(path, ".compact.tmp");
+    let rewrite = || -> io::Result<(u64, u64)> {
+        let records = records(&file, key, compression, len)?;
+        let (format, timestamp) = records.format();
+        let mut out = BufWriter::new(File::create(&tmp)?);
+        out.write_all(format.header(timestamp).as_bytes())?;
+        let mut compacted = Compacted::new(records, cutoff, step);
+        let mut written = 0;
+        for sample in &mut compacted {
+            out.write_all(format.record(timestamp, &sample?).as_bytes())?;
+            written += 1;
+        }
+
+        let (done, _writing) = match compression {
+            Some(compression) => {
+                out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+                let compressed = with_suffix(&tmp, ".compressed");
+                compression.compress(&tmp, &compressed)?;
+                fs::remove_file(&tmp)?;
+                (compressed, None)
+            }
+            None => {
+                // Nothing is appended from here on until the file is replaced
+                let writing = writing.lock().unwrap_or_else(|e| e.into_inner());
+                let (current, opened) = (fs::metadata(path)?, file.metadata()?);
+                if (current.dev(), current.ino()) != (opened.dev(), opened.ino()) {
+                    return Err(io::Error::other("it was replaced while compacting"));
+                }
+                let mut rest = file.try_clone()?;
+                rest.seek(SeekFrom::Start(len))?;
+                io::copy(&mut rest, &mut out)?;
+                out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+                (tmp.clone(), Some(writing))
+            }
+        };
+
+        // Rotating by period goes by when a file was last appended to
+        let metadata = file.metadata()?;
+        fs::set_permissions(&done, metadata.permissions())?;
+        OpenOptions::new().write(true).open(&done)?.set_modified(metadata.modified()?)?;
+        fs::rename(&done, path)?;
+        // The file outputs of this process append to the new file from now on
+        replaced();
+        Ok((compacted.read, written))
+    };
+    let result = rewrite();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+        let _ = fs::remove_file(with_suffix(&tmp, ".compressed"));
+    }
+    result.map(Some)
+}
+
+/// Compacts the files of `keys`, including their rotated segments, in the file outputs of
+/// `config` among `outputs`. Returns the files rewritten, with how many values they had before
+/// and after, and those that could not be.
+pub fn compact(config: &CompactConfig, outputs: &[OutputConfig], keys: &[String], now: i64, writing: &Mutex<()>)
+               -> Vec<(PathBuf, io::Result<(u64, u64)>)>
+{
+    let cutoff = config.cutoff(now);
+    let mut compacted = Vec::new();
+    for (_, directory, layout) in file_outputs(config, outputs) {
+        for key in keys {
+            let path = match layout.path(directory, key) {
+                Some(p) => p,
+                None => continue,
+            };
+            let mut files = match rotate::segments(&path) {
+                Ok(s) => s,
+                Err(e) => {
+                    compacted.push((path, Err(e)));
+                    continue;
+                }
+            };
+            if path.exists() {
+                files.push(Segment {
+                    path,
+                    end: i64::MAX,
+                    compression: None,
+                });
+            }
+            for file in files {
+                // Left behind if we were stopped while compacting the file
+                let tmp = with_suffix(&file.path, ".compact.tmp");
+                let _ = fs::remove_file(with_suffix(&tmp, ".compressed"));
+                let _ = fs::remove_file(&tmp);
+                match compact_file(&file.path, key, file.compression, cutoff, config.step, writing) {
+                    Ok(Some(counts)) => compacted.push((file.path, Ok(counts))),
+                    Ok(None) => (),
+                    // It was compressed or removed after rotating meanwhile
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                    Err(e) => compacted.push((file.path, Err(e))),
+                }
+            }
+        }
+    }
+    compacted
+}
+
+/// The names, directories and layouts of the file outputs among `outputs` that are compacted
+fn file_outputs<'a>(config: &CompactConfig, outputs: &'a [OutputConfig]) -> Vec<(&'a str, &'a Path, Layout)> {
+    outputs.iter()
+        .filter(|o| config.outputs.as_ref().is_none_or(|names| names.contains(&o.name)))
+        .filter_map(|o| match o.kind {
+            OutputKind::File { ref directory, layout, .. } => Some((&o.name[..], directory.as_path(), layout)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Locks the directories of the file outputs among `outputs` that are compacted, so nothing
+/// else writes to them until the returned files are closed. Fails if something already does.
+fn lock(config: &CompactConfig, outputs: &[OutputConfig]) -> Result<Vec<File>, String> {
+    let mut locks = Vec::new();
+    for (name, directory, _) in file_outputs(config, outputs) {
+        if !directory.exists() {
+            continue;
+        }
+        let lock = file::lock_file(directory)
+            .map_err(|e| format!("Could not lock the files of output {}: {}", name, e))?;
+        match lock.try_lock() {
+            Ok(()) => locks.push(lock),
+            Err(TryLockError::WouldBlock) => return Err(format!(
+                "The files of output {} are being written to, by a running Antikörper or an import. \
+                 Stop it first, or let it compact them with the interval of the compact section", name)),
+            Err(TryLockError::Error(e)) => return Err(format!("Could not lock the files of output {}: {}", name, e)),
+        }
+    }
+    Ok(locks)
+}
+
+/// Compacts in the background, logging what was compacted
+pub fn spawn(config: &Config, writing: Arc<Mutex<()>>) -> Option<JoinHandle<()>> {
+    let settings = config.compact.clone()?;
+    let outputs = config.outputs.clone();
+    let keys = known_keys(config);
+    Some(thread::spawn(move || {
+        for (path, result) in compact(&settings, &outputs, &keys, time::get_time().sec, &writing) {
+            match result {
+                Ok((before, after)) => info!("Compacted {} from {} to {} values", path.display(), before, after),
+                Err(e) => error!("Could not compact {}: {}", path.display(), e),
+            }
+        }
+    }))
+}
+
+/// The keys of all configured items
+pub fn known_keys(config: &Config) -> Vec<String> {
+    let mut keys = config.items.iter().chain(config.derived.iter())
+        .flat_map(|i| i.known_keys())
+        .collect::<Vec<_>>();
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+pub fn subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("compact")
+        .about("Averages old numeric values in the files of file outputs")
+        .arg(Arg::with_name("key")
+             .value_name("KEY")
+             .help("The keys to compact, by default those of all items")
+             .multiple(true))
+        .arg(Arg::with_name("keep_raw")
+             .long("keep-raw")
+             .value_name("DURATION")
+             .help("Leaves the values of the last DURATION as they are, by default as configured or 7d")
+             .takes_value(true))
+        .arg(Arg::with_name("step")
+             .long("step")
+             .value_name("DURATION")
+             .help("Averages older values in buckets of DURATION, by default as configured or 5m")
+             .takes_value(true))
+        .arg(Arg::with_name("in")
+             .long("in")
+             .value_name("OUTPUT")
+             .help("Only compacts the files of the file output OUTPUT")
+             .takes_value(true))
+}
+
+pub fn run(config: &Config, matches: &ArgMatches) -> Result<(), String> {
+    let mut settings = config.compact.clone().unwrap_or_default();
+    let duration = |name: &str| match matches.value_of(name) {
+        Some(s) => units::parse_duration(s).filter(|&d| d > 0).map(Some)
+            .ok_or_else(|| format!("Invalid duration for --{}: {}", name.replace('_', "-"), s)),
+        None => Ok(None),
+    };
+    settings.keep_raw = duration("keep_raw")?.unwrap_or(settings.keep_raw);
+    settings.step = duration("step")?.unwrap_or(settings.step);
+    if let Some(name) = matches.value_of("in") {
+        match config.outputs.iter().find(|o| o.name == name) {
+            Some(&OutputConfig { kind: OutputKind::File { .. }, .. }) => settings.outputs = Some(vec![name.to_owned()]),
+            Some(_) => return Err(format!("Output {} is not a file output", name)),
+            None => return Err(format!("There is no output named {}", name)),
+        }
+    }
+    let keys = match matches.values_of("key") {
+        Some(keys) => keys.map(|k| if valid_key(k) { Ok(k.to_owned()) } else { Err(format!("{} is not a valid key", k)) })
+            .collect::<Result<Vec<_>, _>>()?,
+        None => known_keys(config),
+    };
+
+    // Nothing else writes while we hold these, so the lock for writing is ours alone
+    let _locks = lock(&settings, &config.outputs)?;
+    let mut failed = 0;
+    for (path, result) in compact(&settings, &config.outputs, &keys, time::get_time().sec, &Mutex::new(())) {
+        match result {
+            Ok((before, after)) => println!("{}: {} values, {} after compacting", path.display(), before, after),
+            Err(e) => {
+                eprintln!("Could not compact {}: {}", path.display(), e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(format!("{} files could not be compacted", failed));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::BufReader;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use compact::{compact, compact_file, lock, CompactConfig, Compacted};
+    use conf::General;
+    use output::{Output, OutputConfig, Sample};
+    use output::file::{FileOutput, Layout, Records, Writing};
+    use output::format::{Format, Timestamp};
+    use output::rotate::Rotation;
+
+    #[test]
+    fn averages_old_buckets() {
+        let samples = [(0, "4"), (30, "2"), (59, "Charging"), (60, "9"), (200, "1"), (230, "3"), (300, "5"), (310, "7")]
+            .iter()
+            .map(|&(time, value)| Ok(Sample::new("os.battery", 1454328000 + time, value)))
+            .collect::<Vec<_>>();
+        let mut compacted = Compacted::new(samples.into_iter(), 1454328300, 60);
+        let values = (&mut compacted)
+            .map(|s| s.map(|s| (s.time.sec - 1454328000, s.value)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(values, [(0, String::from("3")), (59, String::from("Charging")), (60, String::from("9")),
+                            (180, String::from("2")), (300, String::from("5")), (310, String::from("7"))]);
+        assert_eq!((compacted.read, compacted.merged), (8, 2));
+    }
+
+    #[test]
+    fn rewrites_files() {
+        let dir = env::temp_dir().join("antikoerper-compact");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("os.battery");
+        fs::write(&path, "1454328000 4\n1454328030 2\n1454328060 9\n1454328300 5\n1454328310 7\n1454328320 6")
+            .unwrap();
+
+        let writing = Mutex::new(());
+        assert_eq!(compact_file(&path, "os.battery", None, 1454328300, 60, &writing).unwrap(), Some((5, 4)));
+        // The record still being appended is kept as it is
+        assert_eq!(fs::read_to_string(&path).unwrap(),
+                   "# antikoerper text v1 epoch\n1454328000 3\n1454328060 9\n1454328300 5\n1454328310 7\n1454328320 6");
+        assert_eq!(compact_file(&path, "os.battery", None, 1454328300, 60, &writing).unwrap(), None);
+        assert!(!dir.join("os.battery.compact.tmp").exists());
+
+        // Temporary files of a compaction that was stopped are removed
+        fs::write(dir.join("os.battery.compact.tmp"), "1454328000 4\n").unwrap();
+        fs::write(dir.join("os.battery.compact.tmp.compressed"), "").unwrap();
+        let general = General {
+            shell: String::from("sh"),
+            output: dir.clone(),
+        };
+        let outputs = [OutputConfig::default(&general)];
+        let keys = [String::from("os.battery")];
+        assert!(compact(&CompactConfig::default(), &outputs, &keys, 1454328400, &writing).is_empty());
+        assert!(!dir.join("os.battery.compact.tmp").exists());
+        assert!(!dir.join("os.battery.compact.tmp.compressed").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_values_appended_meanwhile() {
+        let dir = env::temp_dir().join("antikoerper-compact-appending");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("os.battery");
+        let old = (0..20000).map(|i| format!("{} {}\n", 1454328000 + i, i % 100)).collect::<String>();
+        fs::write(&path, old).unwrap();
+
+        // Appends like a running Antikoerper does, until compacting is done
+        let writing = Arc::new(Mutex::new(()));
+        let done = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let (dir, writing, done) = (dir.clone(), writing.clone(), done.clone());
+            thread::spawn(move || {
+                let immediate = Writing {
+                    flush_interval: Duration::from_secs(0),
+                    ..Writing::default()
+                };
+                let mut output = FileOutput::new(&dir, Layout::Flat, Format::Text, Timestamp::Epoch,
+                                                 Rotation::default(), immediate).unwrap();
+                let mut appended = 0;
+                loop {
+                    let finished = done.load(Ordering::SeqCst);
+                    let _writing = writing.lock().unwrap();
+                    output.write(&Sample::new("os.battery", 1454400000 + appended, "50\n")).unwrap();
+                    appended += 1;
+                    if finished {
+                        return appended;
+                    }
+                }
+            })
+        };
+        let compacted = compact_file(&path, "os.battery", None, 1454400000, 60, &writing).unwrap();
+        done.store(true, Ordering::SeqCst);
+        let appended = writer.join().unwrap();
+        assert!(compacted.is_some());
+
+        let times = Records::new(BufReader::new(File::open(&path).unwrap()), "os.battery").unwrap()
+            .map(|s| s.unwrap().time.sec)
+            .filter(|&t| t >= 1454400000)
+            .collect::<Vec<_>>();
+        assert_eq!(times, (1454400000..1454400000 + appended).collect::<Vec<_>>());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_while_written_to() {
+        let dir = env::temp_dir().join("antikoerper-compact-locked");
+        let _ = fs::remove_dir_all(&dir);
+        let general = General {
+            shell: String::from("sh"),
+            output: dir.clone(),
+        };
+        let outputs = [OutputConfig::default(&general)];
+        let config = CompactConfig::default();
+        // There is nothing to compact yet
+        assert!(lock(&config, &outputs).unwrap().is_empty());
+
+        let output = FileOutput::new(&dir, Layout::Flat, Format::Text, Timestamp::Epoch,
+                                     Rotation::default(), Writing::default()).unwrap();
+        assert!(lock(&config, &outputs).is_err());
+        drop(output);
+        let locks = lock(&config, &outputs).unwrap();
+        assert_eq!(locks.len(), 1);
+        drop(locks);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
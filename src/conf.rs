@@ -1,16 +1,28 @@
 extern crate xdg;
 
-use std::collections::BinaryHeap;
+use std::collections::{BTreeSet, BinaryHeap};
+use std::error::Error;
 use std::io::Read;
 use std::path::PathBuf;
 
 use toml;
-use item::Item;
+use compact::CompactConfig;
+use dashboard::DashboardConfig;
+use derived;
+use item::{Item, ItemKind};
+use output::{OutputConfig, OutputKind};
+use output::file::Layout;
+use report::ReportConfig;
 
 /// The Configuration of Antikoerper
 #[derive(Debug, Clone)]
 pub struct Config {
     pub items: BinaryHeap<Item>,
+    pub derived: Vec<Item>,
+    pub outputs: Vec<OutputConfig>,
+    pub reports: Vec<ReportConfig>,
+    pub dashboard: Option<DashboardConfig>,
+    pub compact: Option<CompactConfig>,
     pub general: General,
 }
 
@@ -27,14 +39,24 @@ enum ConfigErrorKind {
     MissingItems,
     ErrorItems,
     DuplicateItem(String),
+    InvalidDerived(String),
+    ErrorOutputs,
+    DuplicateOutput(String),
+    UnknownOutput(String, String),
+    NestedKeyConflict(String, String),
+    ErrorReports,
+    DuplicateReport(String),
+    InvalidDashboard(String),
+    InvalidCompact(String),
     MismatchedShellType,
     MismatchedOutputType,
+    MismatchedOutputsType,
 }
 
 #[derive(Debug)]
 pub struct ConfigError {
     kind: ConfigErrorKind,
-    cause: Option<Box<::std::error::Error>>,
+    cause: Option<Box<dyn Error>>,
 }
 
 impl ::std::fmt::Display for ConfigError {
@@ -45,8 +67,20 @@ impl ::std::fmt::Display for ConfigError {
             ConfigErrorKind::MissingItems => write!(f, "no items section"),
             ConfigErrorKind::ErrorItems => write!(f, "some items have errors"),
             ConfigErrorKind::DuplicateItem(ref s) => write!(f, "duplicate key: {}", s),
+            ConfigErrorKind::InvalidDerived(ref s) => write!(f, "{}", s),
+            ConfigErrorKind::ErrorOutputs => write!(f, "some outputs have errors"),
+            ConfigErrorKind::DuplicateOutput(ref s) => write!(f, "duplicate output: {}", s),
+            ConfigErrorKind::UnknownOutput(ref k, ref o) => write!(f, "{}: unknown output {}", k, o),
+            ConfigErrorKind::NestedKeyConflict(ref a, ref b) => {
+                write!(f, "{} would be both a file and the directory of {} in a nested output", a, b)
+            }
+            ConfigErrorKind::ErrorReports => write!(f, "some reports have errors"),
+            ConfigErrorKind::DuplicateReport(ref s) => write!(f, "duplicate report: {}", s),
+            ConfigErrorKind::InvalidDashboard(ref s) => write!(f, "{}", s),
+            ConfigErrorKind::InvalidCompact(ref s) => write!(f, "{}", s),
             ConfigErrorKind::MismatchedShellType => write!(f, "general.shell has to be a string"),
-            ConfigErrorKind::MismatchedOutputType => write!(f, "general.output has to be a path")
+            ConfigErrorKind::MismatchedOutputType => write!(f, "general.output has to be a path"),
+            ConfigErrorKind::MismatchedOutputsType => write!(f, "outputs has to be an array of tables, written as [[outputs]]")
         }
     }
 }
@@ -69,10 +103,10 @@ impl From<toml::ParserError> for ConfigError {
     }
 }
 
-pub fn load(r: &mut Read, o: PathBuf) -> Result<Config, ConfigError> {
+pub fn load(r: &mut dyn Read, o: PathBuf) -> Result<Config, ConfigError> {
     let content = {
         let mut buffer = String::new();
-        try!(r.read_to_string(&mut buffer));
+        r.read_to_string(&mut buffer)?;
         buffer
     };
 
@@ -87,10 +121,10 @@ pub fn load(r: &mut Read, o: PathBuf) -> Result<Config, ConfigError> {
     debug!("{:#?}", parsed);
 
     let general = match parsed.get("general") {
-        Some(&toml::Value::Table(ref v)) => {
+        Some(toml::Value::Table(v)) => {
             General {
                 shell: match v.get("shell") {
-                    Some(&toml::Value::String(ref s)) => s.clone(),
+                    Some(toml::Value::String(s)) => s.clone(),
                     Some(_) => return Err(ConfigError {
                         kind: ConfigErrorKind::MismatchedShellType,
                         cause: None,
@@ -105,7 +139,7 @@ pub fn load(r: &mut Read, o: PathBuf) -> Result<Config, ConfigError> {
                 output : match xdg::BaseDirectories::with_prefix("antikoerper").unwrap()
                     .create_data_directory(if o == PathBuf::new() {
                         match v.get("output") {
-                            Some(&toml::Value::String(ref s)) => PathBuf::from(s.clone()),
+                            Some(toml::Value::String(s)) => PathBuf::from(s.clone()),
                             Some(_) => return Err(ConfigError {
                                 kind: ConfigErrorKind::MismatchedOutputType,
                                 cause: None,
@@ -142,7 +176,7 @@ pub fn load(r: &mut Read, o: PathBuf) -> Result<Config, ConfigError> {
     trace!("Output path is: {:#?}", general.output);
 
     let items = match parsed.get("items") {
-        Some(&toml::Value::Array(ref t)) => t,
+        Some(toml::Value::Array(t)) => t,
         _ => return Err(ConfigError {
             kind: ConfigErrorKind::MissingItems,
             cause: None
@@ -170,8 +204,7 @@ pub fn load(r: &mut Read, o: PathBuf) -> Result<Config, ConfigError> {
 
     let mut it = items.iter().map(|x| x.as_ref().unwrap().key.clone()).collect::<Vec<_>>();
     it.sort();
-    let mut it = it.windows(2).map(|x| if x[0] == x[1] { Some(x[0].clone()) } else { None })
-        .filter_map(|x| x);
+    let mut it = it.windows(2).filter_map(|x| if x[0] == x[1] { Some(x[0].clone()) } else { None });
 
     if let Some(n) = it.next() {
         return Err(ConfigError {
@@ -181,9 +214,186 @@ pub fn load(r: &mut Read, o: PathBuf) -> Result<Config, ConfigError> {
     }
 
 
+    let (derived, items) = items.into_iter().map(|x| x.unwrap())
+        .partition::<Vec<_>, _>(|x| matches!(x.kind, ItemKind::Derived(_)));
+
+    let all = items.iter().chain(derived.iter()).cloned().collect::<Vec<_>>();
+    if let Err(e) = derived::validate(&all) {
+        return Err(ConfigError {
+            kind: ConfigErrorKind::InvalidDerived(e),
+            cause: None
+        })
+    }
+
+    let mismatched = || ConfigError {
+        kind: ConfigErrorKind::MismatchedOutputsType,
+        cause: None
+    };
+    let outputs = match parsed.get("outputs") {
+        Some(toml::Value::Array(t)) => t.iter().map(|v| match *v {
+            toml::Value::Table(ref v) => Ok(OutputConfig::from_toml(v, &general)),
+            _ => Err(mismatched()),
+        }).collect::<Result<Vec<_>, _>>()?,
+        Some(_) => return Err(mismatched()),
+        None => vec![Ok(OutputConfig::default(&general))],
+    };
+
+    for err in outputs.iter().filter(|x| x.is_err()) {
+        if let Err(ref x) = *err {
+            println!("{}", x);
+        }
+    }
+
+    if let Some(e) = outputs.iter().filter_map(|x| x.clone().err()).next() {
+        return Err(ConfigError {
+            kind: ConfigErrorKind::ErrorOutputs,
+            cause: Some(Box::new(e))
+        });
+    }
+
+    let outputs = outputs.into_iter().map(|x| x.unwrap()).collect::<Vec<_>>();
+
+    let mut names = outputs.iter().map(|x| &x.name).collect::<Vec<_>>();
+    names.sort();
+    if let Some(n) = names.windows(2).find(|x| x[0] == x[1]) {
+        return Err(ConfigError {
+            kind: ConfigErrorKind::DuplicateOutput(n[0].clone()),
+            cause: None
+        })
+    }
+
+    for item in &all {
+        for name in item.outputs.iter().flat_map(|o| o.iter()) {
+            if !names.contains(&name) {
+                return Err(ConfigError {
+                    kind: ConfigErrorKind::UnknownOutput(item.key.clone(), name.clone()),
+                    cause: None
+                })
+            }
+        }
+    }
+
+    // With nested layouts a key cannot be the start of another key, as its file would have
+    // to be the directory of the other key at the same time.
+    for output in &outputs {
+        match output.kind {
+            OutputKind::File { layout: Layout::Nested, .. } => (),
+            _ => continue,
+        }
+        let keys = all.iter()
+            .filter(|i| i.outputs.as_ref().is_none_or(|o| o.contains(&output.name)))
+            .flat_map(|i| i.known_keys())
+            .collect::<BTreeSet<_>>();
+        for key in &keys {
+            for (i, _) in key.match_indices('.') {
+                if keys.contains(&key[..i]) {
+                    return Err(ConfigError {
+                        kind: ConfigErrorKind::NestedKeyConflict(key[..i].to_owned(), key.clone()),
+                        cause: None
+                    })
+                }
+            }
+        }
+    }
+
+    let reports = match parsed.get("reports") {
+        Some(toml::Value::Array(t)) => t.iter().filter_map(|v| {
+            if let toml::Value::Table(ref v) = *v {
+                Some(ReportConfig::from_toml(v, &general))
+            } else {
+                None
+            }
+        }).collect::<Vec<_>>(),
+        _ => Vec::new(),
+    };
+
+    for err in reports.iter().filter(|x| x.is_err()) {
+        if let Err(ref x) = *err {
+            println!("{}", x);
+        }
+    }
+
+    if let Some(e) = reports.iter().filter_map(|x| x.clone().err()).next() {
+        return Err(ConfigError {
+            kind: ConfigErrorKind::ErrorReports,
+            cause: Some(Box::new(e))
+        });
+    }
+
+    let reports = reports.into_iter().map(|x| x.unwrap()).collect::<Vec<_>>();
+
+    let mut report_names = reports.iter().map(|x| &x.name).collect::<Vec<_>>();
+    report_names.sort();
+    if let Some(n) = report_names.windows(2).find(|x| x[0] == x[1]) {
+        return Err(ConfigError {
+            kind: ConfigErrorKind::DuplicateReport(n[0].clone()),
+            cause: None
+        })
+    }
+
+    for report in &reports {
+        for source in report.charts.iter().filter_map(|c| c.source.as_ref()) {
+            if !names.contains(&source) {
+                return Err(ConfigError {
+                    kind: ConfigErrorKind::UnknownOutput(format!("report {}", report.name), source.clone()),
+                    cause: None
+                })
+            }
+        }
+    }
+
+    let dashboard = match parsed.get("dashboard") {
+        Some(toml::Value::Table(t)) => match DashboardConfig::from_toml(t) {
+            Ok(d) => Some(d),
+            Err(e) => return Err(ConfigError {
+                kind: ConfigErrorKind::InvalidDashboard(e),
+                cause: None
+            }),
+        },
+        Some(_) => return Err(ConfigError {
+            kind: ConfigErrorKind::InvalidDashboard(String::from("dashboard has to be a table")),
+            cause: None
+        }),
+        None => None,
+    };
+
+    let compact = match parsed.get("compact") {
+        Some(toml::Value::Table(t)) => match CompactConfig::from_toml(t) {
+            Ok(c) => Some(c),
+            Err(e) => return Err(ConfigError {
+                kind: ConfigErrorKind::InvalidCompact(e),
+                cause: None
+            }),
+        },
+        Some(_) => return Err(ConfigError {
+            kind: ConfigErrorKind::InvalidCompact(String::from("compact has to be a table")),
+            cause: None
+        }),
+        None => None,
+    };
+
+    for name in compact.iter().flat_map(|c| c.outputs.iter().flatten()) {
+        match outputs.iter().find(|o| &o.name == name) {
+            Some(&OutputConfig { kind: OutputKind::File { .. }, .. }) => (),
+            Some(_) => return Err(ConfigError {
+                kind: ConfigErrorKind::InvalidCompact(format!("compact: output {} is not a file output", name)),
+                cause: None
+            }),
+            None => return Err(ConfigError {
+                kind: ConfigErrorKind::UnknownOutput(String::from("compact"), name.clone()),
+                cause: None
+            }),
+        }
+    }
+
     Ok(Config {
-        items: BinaryHeap::from(items.iter().cloned().map(|x| x.unwrap()).collect::<Vec<_>>()),
-        general: general,
+        items: BinaryHeap::from(items),
+        derived,
+        outputs,
+        reports,
+        dashboard,
+        compact,
+        general,
     })
 }
 
@@ -191,9 +401,21 @@ pub fn load(r: &mut Read, o: PathBuf) -> Result<Config, ConfigError> {
 mod tests {
     extern crate xdg;
 
+    use std::time::Duration;
     use std::path::PathBuf;
 
     use conf;
+    use output::OutputKind;
+    use output::file::{Fsync, Layout, Writing};
+    use output::format::{Format, Timestamp};
+    use output::rotate::{Compression, Period, Rotation};
+    use output::influx::Endpoint;
+    use output::mqtt::{MqttConfig, Will};
+    use output::remote::Delivery;
+    use output::rrd::{Archive, Consolidation};
+    use compact::CompactConfig;
+    use dashboard::DashboardConfig;
+    use report::{ChartConfig, ReportConfig};
 
     #[test]
     fn load() {
@@ -237,6 +459,371 @@ mod tests {
         }
     }
 
+    #[test]
+    fn derived_items() {
+        let data = "[[items]]
+         key = \"mem.used\"
+         interval = 10
+         shell = \"free -b | awk '/Mem/ { print $3 }'\"
+
+         [[items]]
+         key = \"mem.used_mb\"
+         derived = \"mem.used / 1024 / 1024\"
+";
+
+        let config = conf::load(&mut data.as_bytes(), PathBuf::from("")).unwrap();
+        assert_eq!(config.items.len(), 1);
+        assert_eq!(config.derived.len(), 1);
+        assert_eq!(config.derived[0].key, "mem.used_mb");
+
+        let data = "[[items]]
+         key = \"mem.used_mb\"
+         derived = \"mem.usd / 1024 / 1024\"
+";
+
+        match conf::load(&mut data.as_bytes(), PathBuf::from("")) {
+            Err(conf::ConfigError{ kind: conf::ConfigErrorKind::InvalidDerived(e), ..}) => {
+                assert_eq!(e, "mem.used_mb: unknown key mem.usd");
+            },
+            _ => {
+                panic!("Wrong Error!")
+            }
+        }
+    }
+
+    #[test]
+    fn outputs() {
+        let data = "[general]
+        output = \"/tmp/test\"
+
+        [[outputs]]
+        name = \"archive\"
+        type = \"file\"
+        directory = \"archive\"
+
+        [[outputs]]
+        name = \"scratch\"
+        type = \"file\"
+        directory = \"/tmp/scratch\"
+        format = \"jsonl\"
+        timestamp = \"rfc3339\"
+        rotate = \"daily\"
+        max_size = \"10M\"
+        compress = \"zstd\"
+        max_age = \"30d\"
+        flush_interval = 0
+        fsync = \"always\"
+
+        [[outputs]]
+        name = \"db\"
+        type = \"sqlite\"
+        batch_interval = \"1m\"
+
+        [[outputs]]
+        name = \"rrd\"
+        type = \"rrd\"
+        archives = [\"10s:1d\", \"1h:1y\"]
+        consolidation = [\"avg\", \"max\"]
+
+        [[outputs]]
+        name = \"influx\"
+        type = \"influxdb\"
+        url = \"udp://localhost:8089\"
+        tags = { host = \"laptop\" }
+        batch_size = 10
+        spool = true
+
+        [[outputs]]
+        name = \"mqtt\"
+        type = \"mqtt\"
+        address = \"broker\"
+        qos = 1
+        retain = true
+        will = { topic = \"antikoerper/{host}/status\", message = \"offline\", retain = true }
+        spool = false
+
+        [[items]]
+        key = \"os.battery\"
+        interval = 60
+        shell = \"acpi\"
+        outputs = [\"archive\"]
+        ";
+
+        let config = conf::load(&mut data.as_bytes(), PathBuf::new()).unwrap();
+        assert_eq!(config.outputs.len(), 6);
+        assert_eq!(config.outputs[0].kind,
+                   OutputKind::File {
+                       directory: PathBuf::from("/tmp/test/archive"),
+                       layout: Layout::Flat,
+                       format: Format::Text,
+                       timestamp: Timestamp::Epoch,
+                       rotation: Rotation::default(),
+                       writing: Writing::default(),
+                   });
+        assert_eq!(config.outputs[1].kind,
+                   OutputKind::File {
+                       directory: PathBuf::from("/tmp/scratch"),
+                       layout: Layout::Flat,
+                       format: Format::JsonLines,
+                       timestamp: Timestamp::Rfc3339,
+                       rotation: Rotation {
+                           period: Some(Period::Daily),
+                           max_size: Some(10 * 1024 * 1024),
+                           compression: Some(Compression::Zstd),
+                           max_age: Some(30 * 24 * 60 * 60),
+                           max_total: None,
+                       },
+                       writing: Writing {
+                           flush_interval: Duration::from_secs(0),
+                           fsync: Fsync::Always,
+                           max_open: 64,
+                       },
+                   });
+        assert_eq!(config.outputs[2].kind,
+                   OutputKind::Sqlite {
+                       path: PathBuf::from("/tmp/test/antikoerper.db"),
+                       batch_size: 100,
+                       batch_interval: Duration::from_secs(60),
+                       max_queue: 100_000,
+                   });
+        assert_eq!(config.outputs[3].kind,
+                   OutputKind::Rrd {
+                       directory: PathBuf::from("/tmp/test"),
+                       archives: vec![Archive { step: 10, rows: 8640 }, Archive { step: 3600, rows: 8760 }],
+                       consolidations: vec![Consolidation::Average, Consolidation::Max],
+                   });
+        assert_eq!(config.outputs[4].kind,
+                   OutputKind::Influx {
+                       endpoint: Endpoint::Udp(String::from("localhost:8089")),
+                       token: None,
+                       tags: vec![(String::from("host"), String::from("laptop"))],
+                       delivery: Delivery {
+                           batch_size: 10,
+                           batch_interval: Duration::from_secs(10),
+                           max_queue: 100_000,
+                           spool: Some(PathBuf::from("/tmp/test/.spool/influx")),
+                           max_spool: 100 * 1024 * 1024,
+                       },
+                   });
+        assert_eq!(config.outputs[5].kind,
+                   OutputKind::Mqtt {
+                       config: MqttConfig {
+                           address: String::from("broker:1883"),
+                           client_id: None,
+                           username: None,
+                           password: None,
+                           topic: String::from("antikoerper/{host}/{key}"),
+                           qos: 1,
+                           retain: true,
+                           will: Some(Will {
+                               topic: String::from("antikoerper/{host}/status"),
+                               message: String::from("offline"),
+                               qos: 0,
+                               retain: true,
+                           }),
+                           keep_alive: 60,
+                       },
+                       delivery: Delivery::default(),
+                   });
+
+        let data = "[[items]]
+        key = \"os.battery\"
+        interval = 60
+        shell = \"acpi\"
+        outputs = [\"archive\"]
+        ";
+
+        match conf::load(&mut data.as_bytes(), PathBuf::new()) {
+            Err(conf::ConfigError{ kind: conf::ConfigErrorKind::UnknownOutput(k, o), ..}) => {
+                assert_eq!(k, "os.battery");
+                assert_eq!(o, "archive");
+            },
+            _ => {
+                panic!("Wrong Error!")
+            }
+        }
+
+        // A single table instead of an array of them is not silently ignored
+        for outputs in &["[outputs]\nname = \"db\"\ntype = \"sqlite\"", "outputs = \"db\""] {
+            let data = format!("{}\n[[items]]\nkey = \"os.battery\"\ninterval = 60\nshell = \"acpi\"\n", outputs);
+            match conf::load(&mut data.as_bytes(), PathBuf::new()) {
+                Err(conf::ConfigError{ kind: conf::ConfigErrorKind::MismatchedOutputsType, ..}) => (),
+                _ => panic!("Wrong Error!"),
+            }
+        }
+    }
+
+    #[test]
+    fn reports() {
+        let data = "[general]
+        output = \"/tmp/test\"
+
+        [[items]]
+        key = \"os.battery\"
+        interval = 60
+        shell = \"acpi\"
+
+        [[reports]]
+        name = \"daily\"
+        title = \"Daily usage\"
+        since = \"2d\"
+
+        [[reports.charts]]
+        keys = [\"os.battery\", \"os.usage\"]
+
+        [[reports.charts]]
+        title = \"Battery this week\"
+        keys = [\"os.battery\"]
+        since = \"1w\"
+        step = \"1h\"
+        source = \"default\"
+        ";
+
+        let config = conf::load(&mut data.as_bytes(), PathBuf::new()).unwrap();
+        assert_eq!(config.reports, vec![ReportConfig {
+            name: String::from("daily"),
+            title: String::from("Daily usage"),
+            path: PathBuf::from("/tmp/test/.reports/daily.html"),
+            charts: vec![
+                ChartConfig {
+                    title: String::from("os.battery, os.usage"),
+                    keys: vec![String::from("os.battery"), String::from("os.usage")],
+                    since: 2 * 24 * 60 * 60,
+                    step: None,
+                    source: None,
+                },
+                ChartConfig {
+                    title: String::from("Battery this week"),
+                    keys: vec![String::from("os.battery")],
+                    since: 7 * 24 * 60 * 60,
+                    step: Some(60 * 60),
+                    source: Some(String::from("default")),
+                },
+            ],
+        }]);
+
+        let data = data.replace("\"default\"", "\"archive\"");
+        match conf::load(&mut data.as_bytes(), PathBuf::new()) {
+            Err(conf::ConfigError{ kind: conf::ConfigErrorKind::UnknownOutput(k, o), ..}) => {
+                assert_eq!(k, "report daily");
+                assert_eq!(o, "archive");
+            },
+            _ => {
+                panic!("Wrong Error!")
+            }
+        }
+
+        let data = data.replace("keys = [\"os.battery\"]", "keys = []");
+        match conf::load(&mut data.as_bytes(), PathBuf::new()) {
+            Err(conf::ConfigError{ kind: conf::ConfigErrorKind::ErrorReports, cause: Some(e) }) => {
+                assert_eq!(e.to_string(), "report daily: keys of a chart has to be a non-empty array of keys");
+            },
+            _ => {
+                panic!("Wrong Error!")
+            }
+        }
+    }
+
+    #[test]
+    fn dashboard() {
+        let data = "[dashboard]
+        refresh = \"30s\"
+
+        [[items]]
+        key = \"os.battery\"
+        interval = 60
+        shell = \"acpi\"
+        ";
+
+        let config = conf::load(&mut data.as_bytes(), PathBuf::from("/tmp/test")).unwrap();
+        assert_eq!(config.dashboard, Some(DashboardConfig {
+            listen: String::from("127.0.0.1:9470"),
+            since: 6 * 60 * 60,
+            refresh: 30,
+        }));
+
+        let data = data.replace("refresh = \"30s\"", "listen = \"localhost\"");
+        match conf::load(&mut data.as_bytes(), PathBuf::from("/tmp/test")) {
+            Err(conf::ConfigError{ kind: conf::ConfigErrorKind::InvalidDashboard(e), ..}) => {
+                assert_eq!(e, "dashboard: listen has to be like 127.0.0.1:9470");
+            },
+            _ => {
+                panic!("Wrong Error!")
+            }
+        }
+    }
+
+    #[test]
+    fn compact() {
+        let data = "[compact]
+        keep_raw = \"30d\"
+        interval = \"1d\"
+        outputs = [\"files\"]
+
+        [[outputs]]
+        name = \"files\"
+        type = \"file\"
+
+        [[outputs]]
+        name = \"database\"
+        type = \"sqlite\"
+
+        [[items]]
+        key = \"os.battery\"
+        interval = 60
+        shell = \"acpi\"
+        ";
+
+        let config = conf::load(&mut data.as_bytes(), PathBuf::from("/tmp/test")).unwrap();
+        assert_eq!(config.compact, Some(CompactConfig {
+            keep_raw: 30 * 24 * 60 * 60,
+            step: 5 * 60,
+            interval: Some(24 * 60 * 60),
+            outputs: Some(vec![String::from("files")]),
+        }));
+
+        let data = data.replace("[\"files\"]", "[\"database\"]");
+        match conf::load(&mut data.as_bytes(), PathBuf::from("/tmp/test")) {
+            Err(conf::ConfigError{ kind: conf::ConfigErrorKind::InvalidCompact(e), ..}) => {
+                assert_eq!(e, "compact: output database is not a file output");
+            },
+            _ => {
+                panic!("Wrong Error!")
+            }
+        }
+    }
+
+    #[test]
+    fn nested_conflicts() {
+        let data = "[[outputs]]
+        name = \"nested\"
+        type = \"file\"
+        layout = \"nested\"
+
+        [[items]]
+        key = \"mem.used\"
+        interval = 60
+        shell = \"free\"
+
+        [[items]]
+        key = \"mem.used.pct\"
+        derived = \"mem.used / 100\"
+        ";
+
+        match conf::load(&mut data.as_bytes(), PathBuf::from("/tmp/test")) {
+            Err(conf::ConfigError{ kind: conf::ConfigErrorKind::NestedKeyConflict(a, b), ..}) => {
+                assert_eq!(a, "mem.used");
+                assert_eq!(b, "mem.used.pct");
+            },
+            _ => {
+                panic!("Wrong Error!")
+            }
+        }
+
+        let data = data.replace("\"nested\"\n", "\"flat\"\n");
+        assert!(conf::load(&mut data.as_bytes(), PathBuf::from("/tmp/test")).is_ok());
+    }
+
     #[test]
     fn output_dir() {
         let data = "[general]
@@ -264,7 +851,7 @@ mod tests {
         ";
         let config = conf::load(&mut data.as_bytes(), PathBuf::new()).unwrap();
         let xdg_default_dir = match xdg::BaseDirectories::with_prefix("antikoerper").unwrap()
-            .create_data_directory(&PathBuf::new()) {
+            .create_data_directory(PathBuf::new()) {
                 Ok(s) => s,
                 Err(e) => {
                     println!("Error: {}", e);
@@ -0,0 +1,90 @@
+//! Conversion of monotonically increasing counters into per-second rates
+
+use std::collections::BTreeMap;
+
+/// How a counter item behaves
+#[derive(Debug, Clone, Copy, Eq, PartialEq, PartialOrd)]
+pub struct Counter {
+    /// The width of the counter in bits if it wraps around to 0 when overflowing
+    pub bits: Option<u32>,
+}
+
+/// The previous value of every counter key
+#[derive(Debug, Default)]
+pub struct Counters {
+    previous: BTreeMap<String, (i64, String)>,
+}
+
+impl Counters {
+    /// Records `value` of `key` and returns its rate per second since the previous value.
+    ///
+    /// Nothing is returned for the first value of a key, or if the value is not a number.
+    /// A value smaller than the previous one is either a wraparound, if the counter has a known
+    /// width and the previous value was in the upper half of its range, or a reset, in which
+    /// case the counter is assumed to have started again from 0.
+    pub fn rate(&mut self, counter: &Counter, key: &str, time: i64, value: &str) -> Option<String> {
+        let value = value.trim();
+        if value.parse::<f64>().is_err() {
+            debug!("Value of counter {} is not a number: {}", key, value);
+            return None;
+        }
+
+        // Samples that are not newer than the previous one are ignored, keeping the previous one
+        let (previous_time, previous) = match self.previous.get(key) {
+            Some(&(previous_time, _)) if time <= previous_time => return None,
+            _ => self.previous.insert(key.to_owned(), (time, value.to_owned()))?,
+        };
+
+        let increase = match (previous.parse::<u64>(), value.parse::<u64>()) {
+            (Ok(p), Ok(v)) if v >= p => (v - p) as f64,
+            (Ok(p), Ok(v)) => match counter.bits {
+                Some(bits) if p as u128 >= 1 << (bits - 1) && (p as u128) < 1 << bits => {
+                    ((1u128 << bits) - p as u128 + v as u128) as f64
+                }
+                _ => {
+                    debug!("Counter {} has been reset", key);
+                    v as f64
+                }
+            },
+            _ => {
+                let (p, v) = (previous.parse::<f64>().ok()?, value.parse::<f64>().ok()?);
+                if v >= p { v - p } else { v }
+            }
+        };
+
+        Some(format!("{}\n", increase / (time - previous_time) as f64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use counter::{Counter, Counters};
+
+    #[test]
+    fn rates() {
+        let mut counters = Counters::default();
+        let plain = Counter { bits: None };
+        let wrapping = Counter { bits: Some(32) };
+
+        assert_eq!(counters.rate(&plain, "a", 0, "100\n"), None);
+        assert_eq!(counters.rate(&plain, "a", 10, "150\n"), Some(String::from("5\n")));
+        // Not newer than the previous value, which is still 150
+        assert_eq!(counters.rate(&plain, "a", 10, "170\n"), None);
+        // Reset
+        assert_eq!(counters.rate(&plain, "a", 20, "40\n"), Some(String::from("4\n")));
+        assert_eq!(counters.rate(&plain, "a", 30, "garbage\n"), None);
+        assert_eq!(counters.rate(&plain, "a", 30, "40.5"), Some(String::from("0.05\n")));
+
+        assert_eq!(counters.rate(&wrapping, "b", 0, "4294967290"), None);
+        // Wraparound
+        assert_eq!(counters.rate(&wrapping, "b", 2, "4"), Some(String::from("5\n")));
+        // Too small for a wraparound, so a reset
+        assert_eq!(counters.rate(&wrapping, "b", 4, "2"), Some(String::from("1\n")));
+
+        // Out of order samples do not replace the previous value
+        assert_eq!(counters.rate(&plain, "c", 0, "0"), None);
+        assert_eq!(counters.rate(&plain, "c", 10, "100"), Some(String::from("10\n")));
+        assert_eq!(counters.rate(&plain, "c", 5, "50"), None);
+        assert_eq!(counters.rate(&plain, "c", 20, "300"), Some(String::from("20\n")));
+    }
+}
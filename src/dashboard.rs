@@ -0,0 +1,290 @@
+//! A dashboard served over HTTP while Antikoerper runs
+//!
+//! The page lists all items with when they last ran and whether that failed, and draws a chart
+//! of every key, updated every few seconds. It gets everything from a small JSON API, which
+//! reads values the same way the `query` subcommand does:
+//!
+//! - `GET /api/items`, the items and their status
+//! - `GET /api/series?key=os.battery&since=6h`, the values of a key, taking `since`, `from`,
+//!   `to`, `aggregate`, `step` and `source` like `query`
+
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use rustc_serialize::json::Json;
+use time;
+use toml;
+
+use aggregate::{Buckets, Function};
+use conf::Config;
+use item::Item;
+use output::http::{Request, Server};
+use query;
+use series;
+use units;
+
+/// The page, `{{since}}` and `{{refresh}}` are replaced by the configured values
+const PAGE: &str = include_str!("dashboard.html");
+
+/// How the dashboard is served
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DashboardConfig {
+    /// The address to listen on, like `127.0.0.1:9470`
+    pub listen: String,
+    /// How far back charts go, in seconds
+    pub since: i64,
+    /// How often the page updates, in seconds
+    pub refresh: i64,
+}
+
+impl DashboardConfig {
+    pub fn from_toml(table: &toml::Table) -> Result<DashboardConfig, String> {
+        Ok(DashboardConfig {
+            listen: match table.get("listen") {
+                Some(toml::Value::String(s)) if s.parse::<SocketAddr>().is_ok() => s.clone(),
+                Some(_) => return Err(String::from("dashboard: listen has to be like 127.0.0.1:9470")),
+                None => String::from("127.0.0.1:9470"),
+            },
+            since: match table.get("since") {
+                Some(v) => units::duration_from_toml(v).ok_or_else(|| {
+                    String::from("dashboard: since has to be a positive number of seconds or a duration like 6h")
+                })?,
+                None => 6 * 60 * 60,
+            },
+            refresh: match table.get("refresh") {
+                Some(v) => units::duration_from_toml(v).ok_or_else(|| {
+                    String::from("dashboard: refresh has to be a positive number of seconds or a duration like 10s")
+                })?,
+                None => 10,
+            },
+        })
+    }
+}
+
+/// What is known about the last runs of an item
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Status {
+    /// When it last ran
+    last_run: Option<i64>,
+    /// When it last failed and why
+    last_error: Option<(i64, String)>,
+}
+
+type Statuses = Arc<Mutex<BTreeMap<String, Status>>>;
+
+/// `s` as a JSON string
+fn string(s: &str) -> String {
+    Json::String(s.to_owned()).to_string()
+}
+
+/// The items with their status as JSON
+fn items(items: &[Item], statuses: &BTreeMap<String, Status>) -> String {
+    let items = items.iter().map(|item| {
+        let status = statuses.get(&item.key).cloned().unwrap_or_default();
+        let keys = item.known_keys().iter().map(|k| string(k)).collect::<Vec<_>>();
+        let error = match status.last_error {
+            Some((time, ref message)) => format!("{{\"time\":{},\"message\":{}}}", time, string(message)),
+            None => String::from("null"),
+        };
+        format!("{{\"key\":{},\"kind\":{},\"interval\":{},\"keys\":[{}],\"last_run\":{},\"last_error\":{}}}",
+                string(&item.key), string(item.kind.name()), item.interval, keys.join(","),
+                status.last_run.map(|t| t.to_string()).unwrap_or_else(|| String::from("null")), error)
+    }).collect::<Vec<_>>();
+    format!("[{}]\n", items.join(",\n"))
+}
+
+/// Decodes a component of a query string
+fn decode(s: &str) -> String {
+    let hex = |b: u8| (b as char).to_digit(16);
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                (Some(high), Some(low)) => {
+                    decoded.push((high * 16 + low) as u8);
+                    i += 2;
+                }
+                _ => decoded.push(b'%'),
+            },
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// The values of the key the query string `request` asks for as JSON
+fn series(config: &Config, request: &str) -> Result<Vec<u8>, String> {
+    let parameters = request.split('&')
+        .filter_map(|p| p.split_once('='))
+        .map(|(name, value)| (decode(name), decode(value)))
+        .collect::<BTreeMap<_, _>>();
+    let get = |name: &str| parameters.get(name).map(|v| &v[..]);
+
+    let key = get("key").ok_or_else(|| String::from("key is missing"))?;
+    let (from, to) = query::parse_range(get("since"), get("from"), get("to"), time::get_time().sec)?;
+    let functions = match get("aggregate") {
+        Some(names) => names.split(',')
+            .map(|n| Function::from_name(n).ok_or_else(|| format!("Unknown aggregate function: {}", n)))
+            .collect::<Result<Vec<_>, _>>()?,
+        None => Vec::new(),
+    };
+    let step = match get("step") {
+        Some(s) => Some(units::parse_duration(s).filter(|&d| d > 0)
+                        .ok_or_else(|| format!("Invalid duration for step: {}", s))?),
+        None => None,
+    };
+
+    let output = series::source(config, key, get("source"))?;
+    let samples = series::read(output, key, from, to)
+        .map_err(|e| format!("Could not read {} from output {}: {}", key, output.name, e))?;
+    let mut body = Vec::new();
+    let printed = if functions.is_empty() {
+        query::print(&mut body, "json", samples)
+    } else {
+        query::print_buckets(&mut body, "json", &functions, Buckets::new(samples, &functions, step, from))
+    };
+    printed.map_err(|e| format!("Could not read {} from output {}: {}", key, output.name, e))?;
+    Ok(body)
+}
+
+/// Answers a single request
+fn serve(stream: TcpStream, page: &str, config: &Config, statuses: &Statuses) -> io::Result<()> {
+    let request = Request::read(&stream)?;
+    let (status, content_type, body) = match (&request.method[..], &request.path[..]) {
+        ("GET", "/") => ("200 OK", "text/html; charset=utf-8", page.as_bytes().to_vec()),
+        ("GET", "/api/items") => {
+            let mut all = config.items.iter().chain(config.derived.iter()).cloned().collect::<Vec<_>>();
+            all.sort_by(|a, b| a.key.cmp(&b.key));
+            let body = items(&all, &statuses.lock().unwrap());
+            ("200 OK", "application/json", body.into_bytes())
+        }
+        ("GET", "/api/series") => match series(config, &request.query) {
+            Ok(body) => ("200 OK", "application/json", body),
+            Err(e) => ("400 Bad Request", "application/json", format!("{{\"error\":{}}}\n", string(&e)).into_bytes()),
+        },
+        ("GET", _) => ("404 Not Found", "text/plain", b"Not found\n".to_vec()),
+        _ => ("405 Method Not Allowed", "text/plain", b"Only GET is allowed\n".to_vec()),
+    };
+    request.respond(&stream, status, &[("Content-Type", content_type), ("Cache-Control", "no-store")], &body)
+}
+
+/// Serves the dashboard and keeps track of the status of items
+#[derive(Debug)]
+pub struct Dashboard {
+    statuses: Statuses,
+    /// Serving until we are dropped
+    _server: Server,
+}
+
+impl Dashboard {
+    /// Listens for requests as `dashboard` says, showing the items and outputs of `config`
+    pub fn start(dashboard: &DashboardConfig, config: &Config) -> io::Result<Dashboard> {
+        let statuses = Statuses::default();
+        let page = Arc::new(PAGE.replace("{{since}}", &dashboard.since.to_string())
+                            .replace("{{refresh}}", &dashboard.refresh.to_string()));
+        let config = Arc::new(config.clone());
+        let served = statuses.clone();
+        let server = Server::start(&dashboard.listen, "dashboard", move |stream| {
+            // Reading a long series takes a while, which should not hold up other requests
+            let (page, config, statuses) = (page.clone(), config.clone(), served.clone());
+            thread::spawn(move || {
+                if let Err(e) = serve(stream, &page, &config, &statuses) {
+                    debug!("Could not answer a request for the dashboard: {}", e);
+                }
+            });
+        })?;
+        info!("Serving the dashboard at http://{}/", server.address());
+
+        Ok(Dashboard {
+            statuses,
+            _server: server,
+        })
+    }
+
+    /// Notes that the item with the key `item` ran at `time`, failing with `error` if given
+    pub fn ran(&self, item: &str, time: i64, error: Option<&str>) {
+        let mut statuses = self.statuses.lock().unwrap();
+        let status = statuses.entry(item.to_owned()).or_default();
+        status.last_run = Some(time);
+        if let Some(error) = error {
+            status.last_error = Some((time, error.to_owned()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::path::PathBuf;
+
+    use conf;
+    use dashboard::{decode, Dashboard, DashboardConfig};
+
+    fn get(dashboard: &Dashboard, target: &str) -> String {
+        let mut stream = TcpStream::connect(dashboard._server.address()).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", target).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn decodes() {
+        assert_eq!(decode("2016-02-01+12%3A00%3A00"), "2016-02-01 12:00:00");
+        assert_eq!(decode("100%"), "100%");
+    }
+
+    #[test]
+    fn serves() {
+        let dir = env::temp_dir().join("antikoerper-dashboard");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("os.battery"), "1454328000 98\n1454328060 97\n1454328120 Charging\n").unwrap();
+        let data = "[[items]]
+        key = \"os.battery\"
+        interval = 60
+        shell = \"acpi\"
+        ";
+        let config = conf::load(&mut data.as_bytes(), PathBuf::from(&dir)).unwrap();
+        let dashboard = Dashboard::start(&DashboardConfig {
+            listen: String::from("127.0.0.1:0"),
+            since: 3600,
+            refresh: 5,
+        }, &config).unwrap();
+
+        let page = get(&dashboard, "/");
+        assert!(page.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(page.contains("const SINCE = 3600;"));
+
+        dashboard.ran("os.battery", 1454328000, None);
+        dashboard.ran("os.battery", 1454328060, Some("acpi: not found"));
+        dashboard.ran("os.battery", 1454328120, None);
+        let items = get(&dashboard, "/api/items");
+        assert!(items.ends_with("[{\"key\":\"os.battery\",\"kind\":\"shell\",\"interval\":60,\"keys\":[\"os.battery\"],\
+                                 \"last_run\":1454328120,\"last_error\":{\"time\":1454328060,\
+                                 \"message\":\"acpi: not found\"}}]\n"));
+
+        let series = get(&dashboard, "/api/series?key=os.battery&from=1454328000&to=1454328100");
+        assert!(series.ends_with("[\n{\"time\":1454328000,\"key\":\"os.battery\",\"value\":98},\n\
+                                  {\"time\":1454328060,\"key\":\"os.battery\",\"value\":97}\n]\n"));
+        let series = get(&dashboard, "/api/series?key=os.battery&from=1454328000&aggregate=avg,count&step=2m");
+        assert!(series.ends_with("[\n{\"time\":1454328000,\"avg\":97.5,\"count\":2}\n]\n"));
+        let error = get(&dashboard, "/api/series?key=os.battery&aggregate=mean");
+        assert!(error.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(error.ends_with("{\"error\":\"Unknown aggregate function: mean\"}\n"));
+        assert!(get(&dashboard, "/nothing").starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        drop(dashboard);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,252 @@
+//! Evaluation of derived items whenever one of their inputs is updated
+
+use std::collections::{BTreeMap, VecDeque};
+
+use expression::{Expression, Lookup};
+use item::{Item, ItemKind};
+
+/// The recent numeric values of all keys derived items refer to
+#[derive(Debug, Default)]
+struct History {
+    now: i64,
+    /// For how many seconds the values of a key are kept, only keys in here are recorded
+    windows: BTreeMap<String, i64>,
+    values: BTreeMap<String, VecDeque<(i64, f64)>>,
+}
+
+impl History {
+    fn push(&mut self, key: &str, time: i64, value: f64) {
+        let window = match self.windows.get(key) {
+            Some(&w) => w,
+            None => return,
+        };
+        self.now = time;
+        let values = self.values.entry(key.to_owned()).or_default();
+        values.push_back((time, value));
+        // Two values are always kept for rate() and delta()
+        while values.len() > 2 && values.front().is_some_and(|&(t, _)| t < time - window) {
+            values.pop_front();
+        }
+    }
+
+    fn last_two(&self, key: &str) -> Option<((i64, f64), (i64, f64))> {
+        let values = self.values.get(key)?;
+        if values.len() < 2 {
+            return None;
+        }
+        Some((values[values.len() - 2], values[values.len() - 1]))
+    }
+}
+
+impl Lookup for History {
+    fn latest(&self, key: &str) -> Option<f64> {
+        self.values.get(key).and_then(|v| v.back()).map(|&(_, v)| v)
+    }
+
+    fn delta(&self, key: &str) -> Option<f64> {
+        self.last_two(key).map(|((_, a), (_, b))| b - a)
+    }
+
+    fn rate(&self, key: &str) -> Option<f64> {
+        match self.last_two(key)? {
+            ((t1, _), (t2, _)) if t1 == t2 => None,
+            ((t1, a), (t2, b)) => Some((b - a) / (t2 - t1) as f64),
+        }
+    }
+
+    fn avg_over(&self, key: &str, seconds: i64) -> Option<f64> {
+        let values = self.values.get(key)?.iter()
+            .filter(|&&(t, _)| t > self.now - seconds)
+            .map(|&(_, v)| v)
+            .collect::<Vec<_>>();
+        if values.is_empty() {
+            return None;
+        }
+        Some(values.iter().sum::<f64>() / values.len() as f64)
+    }
+}
+
+/// All derived items, together with the values needed to compute them
+#[derive(Debug)]
+pub struct Derived {
+    items: Vec<(String, Expression)>,
+    /// The derived items to evaluate when a key is updated, as indices into `items`
+    dependents: BTreeMap<String, Vec<usize>>,
+    history: History,
+}
+
+impl Derived {
+    pub fn new(items: &[Item]) -> Derived {
+        let mut derived = Derived {
+            items: Vec::new(),
+            dependents: BTreeMap::new(),
+            history: History::default(),
+        };
+
+        for item in items {
+            let expression = match item.kind {
+                ItemKind::Derived(ref e) => e,
+                _ => continue,
+            };
+            let index = derived.items.len();
+            for key in expression.keys() {
+                let dependents = derived.dependents.entry(key.to_owned()).or_default();
+                if !dependents.contains(&index) {
+                    dependents.push(index);
+                }
+                derived.history.windows.entry(key.to_owned()).or_insert(0);
+            }
+            for (key, seconds) in expression.windows() {
+                let window = derived.history.windows.entry(key.to_owned()).or_insert(0);
+                *window = (*window).max(seconds);
+            }
+            derived.items.push((item.key.clone(), expression.clone()));
+        }
+        derived
+    }
+
+    /// Records a new value of `key` and returns the derived items that could be computed
+    /// from it, including those depending on other derived items.
+    pub fn update(&mut self, key: &str, time: i64, value: &str) -> Vec<(String, String)> {
+        let mut results = Vec::new();
+        let value = match value.trim().parse::<f64>() {
+            Ok(v) => v,
+            Err(_) => {
+                if self.dependents.contains_key(key) {
+                    debug!("Value of {} is not a number: {}", key, value.trim());
+                }
+                return results;
+            }
+        };
+        self.record(key, time, value, &mut results);
+        results
+    }
+
+    fn record(&mut self, key: &str, time: i64, value: f64, results: &mut Vec<(String, String)>) {
+        self.history.push(key, time, value);
+        let dependents = match self.dependents.get(key) {
+            Some(d) => d.clone(),
+            None => return,
+        };
+        for index in dependents {
+            let (key, value) = match self.items[index].1.eval(&self.history) {
+                Some(v) if v.is_finite() => (self.items[index].0.clone(), v),
+                Some(v) => {
+                    debug!("Derived item {} evaluated to {}", self.items[index].0, v);
+                    continue;
+                }
+                None => continue,
+            };
+            results.push((key.clone(), format!("{}\n", value)));
+            self.record(&key, time, value, results);
+        }
+    }
+}
+
+/// Checks that all keys referred to by derived items are provided by some item, and that
+/// derived items do not depend on themselves. Returns a description of the first problem found.
+pub fn validate(items: &[Item]) -> Result<(), String> {
+    for item in items {
+        if let ItemKind::Derived(ref e) = item.kind {
+            for key in e.keys() {
+                if !items.iter().any(|i| i.provides(key)) {
+                    return Err(format!("{}: unknown key {}", item.key, key));
+                }
+            }
+        }
+    }
+
+    fn visit<'a>(items: &'a [Item], item: &'a Item, path: &mut Vec<&'a str>) -> Result<(), String> {
+        if let Some(start) = path.iter().position(|&k| k == item.key) {
+            let mut cycle = path[start..].to_vec();
+            cycle.push(&item.key);
+            return Err(format!("derived items form a cycle: {}", cycle.join(" -> ")));
+        }
+        if let ItemKind::Derived(ref e) = item.kind {
+            path.push(&item.key);
+            for key in e.keys() {
+                for input in items.iter().filter(|i| i.provides(key)) {
+                    visit(items, input, path)?;
+                }
+            }
+            path.pop();
+        }
+        Ok(())
+    }
+
+    for item in items {
+        visit(items, item, &mut Vec::new())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+
+    use derived::{self, Derived};
+    use expression::Expression;
+    use item::{Item, ItemKind};
+
+    fn item(key: &str, kind: ItemKind) -> Item {
+        Item {
+            next_time: 0,
+            interval: 0,
+            key: String::from(key),
+            env: BTreeMap::new(),
+            kind,
+            counter: None,
+            outputs: None,
+        }
+    }
+
+    fn derived(key: &str, e: &str) -> Item {
+        item(key, ItemKind::Derived(Expression::parse(e).unwrap()))
+    }
+
+    #[test]
+    fn evaluated_on_update() {
+        let items = vec![
+            item("mem.used", ItemKind::Shell(String::new())),
+            item("mem.total", ItemKind::Shell(String::new())),
+            derived("mem.used_pct", "mem.used / mem.total * 100"),
+            derived("mem.used_pct.avg", "avg_over(mem.used_pct, 60)"),
+            derived("mem.growth", "rate(mem.used)"),
+        ];
+        let mut derived = Derived::new(&items);
+
+        assert!(derived.update("mem.used", 0, "50\n").is_empty());
+        assert_eq!(derived.update("mem.total", 0, "200\n"),
+                   vec![(String::from("mem.used_pct"), String::from("25\n")),
+                        (String::from("mem.used_pct.avg"), String::from("25\n"))]);
+        assert_eq!(derived.update("mem.used", 10, "100\n"),
+                   vec![(String::from("mem.used_pct"), String::from("50\n")),
+                        (String::from("mem.used_pct.avg"), String::from("37.5\n")),
+                        (String::from("mem.growth"), String::from("5\n"))]);
+        assert_eq!(derived.update("mem.used", 100, "150\n"),
+                   vec![(String::from("mem.used_pct"), String::from("75\n")),
+                        (String::from("mem.used_pct.avg"), String::from("75\n")),
+                        (String::from("mem.growth"), String::from("0.5555555555555556\n"))]);
+        assert!(derived.update("mem.used", 110, "no number").is_empty());
+    }
+
+    #[test]
+    fn validation() {
+        let mut items = vec![
+            item("db", ItemKind::Sqlite { path: PathBuf::new(), query: String::new(), busy_timeout: 0 }),
+            derived("a", "db.column + 1"),
+            derived("b", "a * 2"),
+        ];
+        assert!(derived::validate(&items).is_ok());
+
+        items.push(derived("c", "missing"));
+        assert_eq!(derived::validate(&items), Err(String::from("c: unknown key missing")));
+
+        items.pop();
+        items.push(derived("c", "d"));
+        items.push(derived("d", "b + c"));
+        assert_eq!(derived::validate(&items),
+                   Err(String::from("derived items form a cycle: c -> d -> c")));
+    }
+}
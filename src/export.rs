@@ -0,0 +1,153 @@
+//! The `export` subcommand, writing the stored values of keys to a file or to another output
+//!
+//! Files are written in the formats of the file output, including the header, so `import`
+//! reads them back. CSV files get a column for the key, text files only hold a single key.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use time;
+
+use conf::Config;
+use import;
+use output::format::{Format, Timestamp};
+use query;
+use series::{self, Series};
+
+pub fn subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("export")
+        .about("Writes the stored values of keys to a file or to another output")
+        .arg(Arg::with_name("key")
+             .value_name("KEY")
+             .help("The keys to export the values of")
+             .required(true)
+             .multiple(true))
+        .args(&query::range_args())
+        .arg(Arg::with_name("source")
+             .long("source")
+             .value_name("OUTPUT")
+             .help("The output to read from, by default the first one that can be read back")
+             .takes_value(true))
+        .arg(Arg::with_name("format")
+             .long("format")
+             .value_name("FORMAT")
+             .help("The format of the file, text files only hold a single key")
+             .possible_values(&["text", "csv", "jsonl"])
+             .default_value("csv"))
+        .arg(Arg::with_name("timestamp")
+             .long("timestamp")
+             .value_name("TIMESTAMP")
+             .help("How times are written")
+             .possible_values(&["epoch", "epoch_ms", "rfc3339"])
+             .default_value("epoch"))
+        .arg(Arg::with_name("file")
+             .long("file")
+             .value_name("FILE")
+             .help("Writes to FILE instead of the standard output")
+             .takes_value(true))
+        .arg(Arg::with_name("into")
+             .long("into")
+             .value_name("OUTPUT")
+             .help("Writes the values to the configured OUTPUT instead of a file")
+             .takes_value(true)
+             .conflicts_with("file"))
+}
+
+/// Writes the samples of `series` to `out` as records of `format`, naming their key
+pub fn write(out: &mut dyn Write, format: Format, timestamp: Timestamp, series: Series) -> io::Result<()> {
+    for sample in series {
+        out.write_all(format.keyed_record(timestamp, &sample?).as_bytes())?;
+    }
+    Ok(())
+}
+
+pub fn run(config: &Config, matches: &ArgMatches) -> Result<(), String> {
+    let keys = matches.values_of("key").unwrap().collect::<Vec<_>>();
+    let (from, to) = query::range(matches, time::get_time().sec)?;
+    let format = Format::from_name(matches.value_of("format").unwrap()).unwrap();
+    let timestamp = Timestamp::from_name(matches.value_of("timestamp").unwrap()).unwrap();
+    if format == Format::Text && keys.len() > 1 {
+        return Err(String::from("Text files can only hold the values of a single key"));
+    }
+    let into = matches.value_of("into");
+
+    let mut sources = Vec::new();
+    for key in keys {
+        let output = series::source(config, key, matches.value_of("source"))?;
+        if into == Some(&output.name) {
+            return Err(format!("Cannot export {} from output {} into itself", key, output.name));
+        }
+        sources.push((key, output));
+    }
+
+    if let Some(name) = into {
+        let mut target = import::open(config, name)?;
+        for (key, output) in sources {
+            series::read(output, key, from, to)
+                .and_then(|series| import::store(&mut *target, series))
+                .map_err(|e| format!("Could not export {} from output {} into output {}: {}",
+                                     key, output.name, name, e))?;
+        }
+        return Ok(());
+    }
+
+    let stdout = io::stdout();
+    let mut out: Box<dyn Write> = match matches.value_of("file") {
+        Some(path) => Box::new(BufWriter::new(File::create(path)
+            .map_err(|e| format!("Could not create {}: {}", path, e))?)),
+        None => Box::new(BufWriter::new(stdout.lock())),
+    };
+    let mut written = out.write_all(format.keyed_header(timestamp).as_bytes());
+    for (key, output) in sources {
+        written = written
+            .and_then(|_| series::read(output, key, from, to))
+            .and_then(|series| write(&mut out, format, timestamp, series));
+        match written {
+            // Whoever reads our output, like head, has seen enough
+            Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+            Err(e) => return Err(format!("Could not export {} from output {}: {}", key, output.name, e)),
+            Ok(()) => (),
+        }
+    }
+    match out.flush() {
+        Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        Err(e) => Err(format!("Could not export: {}", e)),
+        Ok(()) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use export::write;
+    use output::Sample;
+    use output::file::Records;
+    use output::format::{Format, Timestamp};
+
+    #[test]
+    fn round_trip() {
+        let samples = vec![("os.battery", 1454328000, "99"), ("os.battery", 1454328060, "Fully\ncharged, \"100%\""),
+                           ("os.load", 1454328000, "0.5")]
+            .into_iter()
+            .map(|(key, time, value)| Sample::new(key, time, value))
+            .collect::<Vec<_>>();
+        for &format in &[Format::Csv, Format::JsonLines] {
+            for &timestamp in &[Timestamp::Epoch, Timestamp::Rfc3339] {
+                let mut out = format.keyed_header(timestamp).into_bytes();
+                let series = Box::new(samples.clone().into_iter().map(Ok));
+                write(&mut out, format, timestamp, series).unwrap();
+                let records = Records::new(&out[..], "export").unwrap();
+                assert!(records.keyed());
+                assert_eq!(records.collect::<Result<Vec<_>, _>>().unwrap(), samples, "{:?}", format);
+            }
+        }
+
+        let battery = samples[..2].to_vec();
+        let mut out = Format::Text.keyed_header(Timestamp::Epoch).into_bytes();
+        write(&mut out, Format::Text, Timestamp::Epoch, Box::new(battery.clone().into_iter().map(Ok))).unwrap();
+        let records = Records::new(&out[..], "os.battery").unwrap();
+        assert!(!records.keyed());
+        assert_eq!(records.collect::<Result<Vec<_>, _>>().unwrap(), battery);
+    }
+}
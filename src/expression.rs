@@ -0,0 +1,358 @@
+//! Arithmetic expressions over the values of other keys, as used by derived items
+
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// Access to the recorded values an expression refers to
+pub trait Lookup {
+    /// The latest value of `key`
+    fn latest(&self, key: &str) -> Option<f64>;
+    /// The difference between the two latest values of `key`
+    fn delta(&self, key: &str) -> Option<f64>;
+    /// The change of `key` per second between its two latest values
+    fn rate(&self, key: &str) -> Option<f64>;
+    /// The average of the values of `key` during the last `seconds`
+    fn avg_over(&self, key: &str, seconds: i64) -> Option<f64>;
+}
+
+/// A binary arithmetic operator
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Operator {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+/// A parsed expression
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub enum Expression {
+    /// A constant
+    Number(f64),
+    /// The latest value of a key
+    Key(String),
+    /// `delta(key)`
+    Delta(String),
+    /// `rate(key)`
+    Rate(String),
+    /// `avg_over(key, seconds)`
+    AvgOver(String, i64),
+    /// `abs(expression)`
+    Abs(Box<Expression>),
+    /// `min(expression, expression)`
+    Min(Box<Expression>, Box<Expression>),
+    /// `max(expression, expression)`
+    Max(Box<Expression>, Box<Expression>),
+    /// `-expression`
+    Negate(Box<Expression>),
+    /// `expression <operator> expression`
+    Binary(Operator, Box<Expression>, Box<Expression>),
+}
+
+// Number literals are never NaN, so equality is reflexive
+impl Eq for Expression {}
+
+impl Expression {
+    /// Parses an expression like `mem.used / mem.total * 100`
+    pub fn parse(s: &str) -> Result<Expression, String> {
+        let mut parser = Parser { chars: s.chars().peekable(), position: 0 };
+        let expression = parser.sum()?;
+        parser.skip_whitespace();
+        match parser.chars.peek() {
+            None => Ok(expression),
+            Some(&c) => Err(parser.error(&format!("unexpected '{}'", c))),
+        }
+    }
+
+    /// All keys this expression refers to
+    pub fn keys(&self) -> Vec<&str> {
+        let mut keys = Vec::new();
+        self.visit(&mut |e| match *e {
+            Expression::Key(ref k)
+                | Expression::Delta(ref k)
+                | Expression::Rate(ref k)
+                | Expression::AvgOver(ref k, _) => keys.push(&k[..]),
+            _ => (),
+        });
+        keys
+    }
+
+    /// For how many seconds the values of each key have to be kept to evaluate this expression
+    pub fn windows(&self) -> Vec<(&str, i64)> {
+        let mut windows = Vec::new();
+        self.visit(&mut |e| if let Expression::AvgOver(ref k, s) = *e {
+            windows.push((&k[..], s));
+        });
+        windows
+    }
+
+    fn visit<'a, F: FnMut(&'a Expression)>(&'a self, f: &mut F) {
+        f(self);
+        match *self {
+            Expression::Abs(ref e) | Expression::Negate(ref e) => e.visit(f),
+            Expression::Min(ref a, ref b)
+                | Expression::Max(ref a, ref b)
+                | Expression::Binary(_, ref a, ref b) => {
+                a.visit(f);
+                b.visit(f);
+            }
+            _ => (),
+        }
+    }
+
+    /// Evaluates the expression, `None` if any value it needs is not known (yet)
+    pub fn eval(&self, lookup: &dyn Lookup) -> Option<f64> {
+        Some(match *self {
+            Expression::Number(n) => n,
+            Expression::Key(ref k) => lookup.latest(k)?,
+            Expression::Delta(ref k) => lookup.delta(k)?,
+            Expression::Rate(ref k) => lookup.rate(k)?,
+            Expression::AvgOver(ref k, s) => lookup.avg_over(k, s)?,
+            Expression::Abs(ref e) => e.eval(lookup)?.abs(),
+            Expression::Min(ref a, ref b) => a.eval(lookup)?.min(b.eval(lookup)?),
+            Expression::Max(ref a, ref b) => a.eval(lookup)?.max(b.eval(lookup)?),
+            Expression::Negate(ref e) => -e.eval(lookup)?,
+            Expression::Binary(op, ref a, ref b) => {
+                let (a, b) = (a.eval(lookup)?, b.eval(lookup)?);
+                match op {
+                    Operator::Add => a + b,
+                    Operator::Sub => a - b,
+                    Operator::Mul => a * b,
+                    Operator::Div => a / b,
+                    Operator::Rem => a % b,
+                }
+            }
+        })
+    }
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &str) -> String {
+        format!("{} at position {}", message, self.position)
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        self.position += 1;
+        self.chars.next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.peek().is_some_and(|c| c.is_whitespace()) {
+            self.bump();
+        }
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        if self.chars.peek() == Some(&c) {
+            self.bump();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", c)))
+        }
+    }
+
+    fn sum(&mut self) -> Result<Expression, String> {
+        let mut left = self.product()?;
+        loop {
+            let op = if self.eat('+') {
+                Operator::Add
+            } else if self.eat('-') {
+                Operator::Sub
+            } else {
+                return Ok(left);
+            };
+            left = Expression::Binary(op, Box::new(left), Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> Result<Expression, String> {
+        let mut left = self.unary()?;
+        loop {
+            let op = if self.eat('*') {
+                Operator::Mul
+            } else if self.eat('/') {
+                Operator::Div
+            } else if self.eat('%') {
+                Operator::Rem
+            } else {
+                return Ok(left);
+            };
+            left = Expression::Binary(op, Box::new(left), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expression, String> {
+        if self.eat('-') {
+            Ok(Expression::Negate(Box::new(self.unary()?)))
+        } else {
+            self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expression, String> {
+        self.skip_whitespace();
+        match self.chars.peek().cloned() {
+            Some('(') => {
+                self.bump();
+                let e = self.sum()?;
+                self.expect(')')?;
+                Ok(e)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => self.number().map(Expression::Number),
+            Some('\'') => self.quoted().map(Expression::Key),
+            Some(c) if c.is_alphabetic() || c == '_' => {
+                let name = self.identifier();
+                if self.eat('(') {
+                    self.call(&name)
+                } else {
+                    Ok(Expression::Key(name))
+                }
+            }
+            Some(c) => Err(self.error(&format!("unexpected '{}'", c))),
+            None => Err(self.error("unexpected end of expression")),
+        }
+    }
+
+    fn number(&mut self) -> Result<f64, String> {
+        let mut s = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if c.is_ascii_digit() || c == '.' {
+                s.push(c);
+                self.bump();
+            } else {
+                break;
+            }
+        }
+        s.parse().map_err(|_| self.error(&format!("invalid number '{}'", s)))
+    }
+
+    fn identifier(&mut self) -> String {
+        let mut s = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if c.is_alphanumeric() || c == '_' || c == '.' {
+                s.push(c);
+                self.bump();
+            } else {
+                break;
+            }
+        }
+        s
+    }
+
+    fn quoted(&mut self) -> Result<String, String> {
+        self.bump();
+        let mut s = String::new();
+        loop {
+            match self.bump() {
+                Some('\'') => return Ok(s),
+                Some(c) => s.push(c),
+                None => return Err(self.error("unterminated quoted key")),
+            }
+        }
+    }
+
+    fn key(&mut self) -> Result<String, String> {
+        self.skip_whitespace();
+        match self.chars.peek().cloned() {
+            Some('\'') => self.quoted(),
+            Some(c) if c.is_alphabetic() || c == '_' => Ok(self.identifier()),
+            _ => Err(self.error("expected a key")),
+        }
+    }
+
+    fn call(&mut self, name: &str) -> Result<Expression, String> {
+        let e = match name {
+            "rate" => Expression::Rate(self.key()?),
+            "delta" => Expression::Delta(self.key()?),
+            "avg_over" => {
+                let key = self.key()?;
+                self.expect(',')?;
+                self.skip_whitespace();
+                let seconds = self.number()?;
+                if seconds < 1.0 || seconds.fract() != 0.0 {
+                    return Err(self.error("avg_over needs a positive whole number of seconds"));
+                }
+                Expression::AvgOver(key, seconds as i64)
+            }
+            "abs" => Expression::Abs(Box::new(self.sum()?)),
+            "min" | "max" => {
+                let a = Box::new(self.sum()?);
+                self.expect(',')?;
+                let b = Box::new(self.sum()?);
+                if name == "min" { Expression::Min(a, b) } else { Expression::Max(a, b) }
+            }
+            _ => return Err(self.error(&format!("unknown function '{}'", name))),
+        };
+        self.expect(')')?;
+        Ok(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use expression::{Expression, Lookup};
+
+    struct Values(BTreeMap<&'static str, f64>);
+
+    impl Lookup for Values {
+        fn latest(&self, key: &str) -> Option<f64> {
+            self.0.get(key).cloned()
+        }
+        fn delta(&self, key: &str) -> Option<f64> {
+            self.latest(key).map(|v| v / 10.0)
+        }
+        fn rate(&self, key: &str) -> Option<f64> {
+            self.latest(key).map(|v| v / 100.0)
+        }
+        fn avg_over(&self, key: &str, seconds: i64) -> Option<f64> {
+            self.latest(key).map(|v| v + seconds as f64)
+        }
+    }
+
+    #[test]
+    fn parse_and_eval() {
+        let mut values = BTreeMap::new();
+        values.insert("mem.used", 512.0);
+        values.insert("mem.total", 2048.0);
+        values.insert("odd-key", 3.0);
+        let values = Values(values);
+
+        let eval = |s: &str| Expression::parse(s).unwrap().eval(&values);
+        assert_eq!(eval("mem.used / mem.total * 100"), Some(25.0));
+        assert_eq!(eval("1 + 2 * 3 - -4"), Some(11.0));
+        assert_eq!(eval("(1 + 2) * 3 % 5"), Some(4.0));
+        assert_eq!(eval("'odd-key' * 2"), Some(6.0));
+        assert_eq!(eval("rate(mem.used) + delta( mem.total )"), Some(5.12 + 204.8));
+        assert_eq!(eval("avg_over(mem.used, 60)"), Some(572.0));
+        assert_eq!(eval("max(abs(-3), min(mem.used, 1))"), Some(3.0));
+        assert_eq!(eval("mem.free + 1"), None);
+
+        let e = Expression::parse("avg_over(a, 30) + rate(b) * c").unwrap();
+        assert_eq!(e.keys(), vec!["a", "b", "c"]);
+        assert_eq!(e.windows(), vec![("a", 30)]);
+
+        assert!(Expression::parse("1 +").is_err());
+        assert!(Expression::parse("(1 + 2").is_err());
+        assert!(Expression::parse("rate(1)").is_err());
+        assert!(Expression::parse("avg_over(a, 0.5)").is_err());
+        assert!(Expression::parse("foo(a)").is_err());
+        assert!(Expression::parse("a b").is_err());
+    }
+}
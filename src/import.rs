@@ -0,0 +1,182 @@
+//! The `import` subcommand, writing the values of files into a configured output
+//!
+//! Files of the file output are read including their rotated and compressed segments, as are
+//! files written by `export`. The key of a file holding the values of a single key is its
+//! name, without the time a segment was rotated at.
+
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use conf::Config;
+use item::valid_key;
+use output::{Output, Sample};
+use output::file::Records;
+use output::rotate::{Compression, Segment};
+
+pub fn subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("import")
+        .about("Writes the values of files into an output")
+        .arg(Arg::with_name("file")
+             .value_name("FILE")
+             .help("The files to read, - for the standard input")
+             .required(true)
+             .multiple(true))
+        .arg(Arg::with_name("into")
+             .long("into")
+             .value_name("OUTPUT")
+             .help("The output to write the values to")
+             .takes_value(true)
+             .required(true))
+        .arg(Arg::with_name("key")
+             .long("key")
+             .value_name("KEY")
+             .help("The key of files holding the values of a single key, by default their name up to an @")
+             .takes_value(true))
+}
+
+/// The output named `name`, opened for writing many samples at once
+pub fn open(config: &Config, name: &str) -> Result<Box<dyn Output>, String> {
+    let output = config.outputs.iter().find(|o| o.name == name)
+        .ok_or_else(|| format!("There is no output named {}", name))?;
+    let items = config.items.iter().chain(config.derived.iter()).cloned().collect::<Vec<_>>();
+    output.open_direct(&items).map_err(|e| format!("Could not open output {}: {}", name, e))
+}
+
+/// Writes all of `samples` to `output` and everything it buffered, returns how many there were
+pub fn store<I>(output: &mut dyn Output, samples: I) -> io::Result<u64>
+    where I: IntoIterator<Item = io::Result<Sample>>
+{
+    let mut count = 0;
+    for sample in samples {
+        output.write(&sample?)?;
+        output.flush()?;
+        count += 1;
+    }
+    output.finish()?;
+    Ok(count)
+}
+
+/// The key of the values in the file at `path` of the file output
+fn key_of(path: &Path) -> Option<String> {
+    let name = path.file_name()?.to_str()?;
+    let key = name.split('@').next()?;
+    if valid_key(key) {
+        Some(key.to_owned())
+    } else {
+        None
+    }
+}
+
+/// Orders files by their key, with the segments of a key before its current file, as that is
+/// the order their values were written in
+fn order(path: &Path) -> (Option<&Path>, String, bool, PathBuf) {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    let (key, current) = match name.split_once('@') {
+        Some((key, _)) => (key, false),
+        None => (name, true),
+    };
+    (path.parent(), key.to_owned(), current, path.to_owned())
+}
+
+/// Writes the values of the file at `path`, `-` being the standard input, to `output`
+fn import(output: &mut dyn Output, path: &Path, key: Option<&str>) -> io::Result<u64> {
+    let (reader, key): (Box<dyn Read>, _) = if path == Path::new("-") {
+        (Box::new(io::stdin()), key.map(String::from))
+    } else {
+        let compression = match path.extension().and_then(|e| e.to_str()) {
+            Some("gz") => Some(Compression::Gzip),
+            Some("zst") => Some(Compression::Zstd),
+            _ => None,
+        };
+        let segment = Segment {
+            path: path.to_owned(),
+            end: i64::MAX,
+            compression,
+        };
+        (segment.open()?, key.map(String::from).or_else(|| key_of(path)))
+    };
+    let records = Records::new(BufReader::new(reader), key.as_ref().map_or("", |k| k))?;
+    if key.is_none() && !records.keyed() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "the key of its values is not known, give it with --key"));
+    }
+    store(output, records)
+}
+
+pub fn run(config: &Config, matches: &ArgMatches) -> Result<(), String> {
+    let name = matches.value_of("into").unwrap();
+    let key = matches.value_of("key");
+    if let Some(key) = key {
+        if !valid_key(key) {
+            return Err(format!("{} is not a valid key", key));
+        }
+    }
+    let mut paths = matches.values_of("file").unwrap().map(PathBuf::from).collect::<Vec<_>>();
+    paths.sort_by(|a, b| order(a).cmp(&order(b)));
+
+    let mut output = open(config, name)?;
+    for path in &paths {
+        let count = import(&mut *output, path, key)
+            .map_err(|e| format!("Could not import {} into output {}: {}", path.display(), name, e))?;
+        println!("{}: {} values", path.display(), count);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::time::Duration;
+
+    use flate2::Compression as GzLevel;
+    use flate2::write::GzEncoder;
+
+    use import::{import, order};
+    use output::sqlite::{Readings, SqliteOutput};
+
+    #[test]
+    fn imports_files() {
+        let dir = env::temp_dir().join("antikoerper-import");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut gz = GzEncoder::new(File::create(dir.join("os.battery@20160201T120000Z.gz")).unwrap(),
+                                    GzLevel::default());
+        gz.write_all(b"1454327940 100\n1454327970 99\n").unwrap();
+        gz.finish().unwrap();
+        fs::write(dir.join("os.battery"), "# antikoerper text v1 rfc3339\n2016-02-01T12:01:00.000Z 98\n")
+            .unwrap();
+        fs::write(dir.join("export.csv"),
+                  "# antikoerper csv v1 epoch\ntime,key,value\n1454328000,os.load,0.5\n1454328000,os.battery,97\n")
+            .unwrap();
+        fs::write(dir.join("battery.txt"), "1454328060 96\n").unwrap();
+        fs::write(dir.join(".battery"), "1454328060 96\n").unwrap();
+
+        let mut paths = ["os.battery", "export.csv", "os.battery@20160201T120000Z.gz"]
+            .iter().map(|n| dir.join(n)).collect::<Vec<_>>();
+        paths.sort_by(|a, b| order(a).cmp(&order(b)));
+        assert_eq!(paths.iter().map(|p| p.file_name().unwrap().to_str().unwrap()).collect::<Vec<_>>(),
+                   ["export.csv", "os.battery@20160201T120000Z.gz", "os.battery"]);
+
+        let db = dir.join("samples.db");
+        let mut output = SqliteOutput::open(&db, 1000, Duration::from_secs(3600), 100_000).unwrap();
+        let counts = paths.iter().map(|p| import(&mut output, p, None).unwrap()).collect::<Vec<_>>();
+        assert_eq!(counts, [2, 2, 1]);
+        assert_eq!(import(&mut output, &dir.join("battery.txt"), Some("os.battery")).unwrap(), 1);
+        assert!(import(&mut output, &dir.join(".battery"), None).is_err());
+
+        let values = |key: &str| Readings::open(&db, key, i64::MIN, i64::MAX).unwrap()
+            .map(|s| s.map(|s| (s.time.sec, s.value)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(values("os.battery").iter().map(|(_, v)| v.as_str()).collect::<Vec<_>>(),
+                   ["100", "99", "97", "98", "96"]);
+        assert_eq!(values("os.load"), [(1454328000, String::from("0.5"))]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -3,9 +3,13 @@ use std::path::PathBuf;
 use std::error::Error;
 use std::collections::BTreeMap;
 
+use glob::Pattern;
 use toml;
+use counter::Counter;
+use expression::Expression;
+use source::{activity, path_stat};
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 enum ItemErrorKind {
     MissingValueSection,
     MissingIntervalSection,
@@ -14,8 +18,22 @@ enum ItemErrorKind {
     InvalidValueType,
     InvalidShellType,
     InvalidPathType,
+    InvalidSqliteType,
+    InvalidBusyTimeout,
+    InvalidPathStatType,
+    InvalidPatterns,
+    InvalidMaxDepth,
+    InvalidActivityType,
+    InvalidIdleAfter,
+    InvalidDerivedType,
+    InvalidExpression(String),
+    InvalidCounter,
+    InvalidCounterBits,
+    DerivedCounter,
+    InvalidOutputs,
     MultipleSources,
     MissingKey,
+    InvalidKey,
     InvalidInterval,
 }
 
@@ -28,15 +46,29 @@ pub struct ItemError {
 impl ItemError {
     fn as_str(&self) -> &str {
         match self.kind {
-            ItemErrorKind::MissingValueSection  => "missing 'command', 'shell' or 'file' key",
+            ItemErrorKind::MissingValueSection  => "missing 'command', 'shell', 'file', 'sqlite', 'path_stat', 'activity' or 'derived' key",
             ItemErrorKind::MissingIntervalSection   => "missing 'interval' key",
             ItemErrorKind::ValueArrayInvalid    => "specified an empty array as command",
             ItemErrorKind::ValueTableMissingKey => "specified a table with missing path and/or args",
             ItemErrorKind::InvalidValueType     => "invalid value type, you may only use tables, strings and arrays",
             ItemErrorKind::InvalidShellType
                 | ItemErrorKind::InvalidPathType      => "invalid value type, you may only use a string",
-            ItemErrorKind::MultipleSources      => "multiple sources given, you may only use command or file or shell",
+            ItemErrorKind::InvalidSqliteType    => "sqlite has to be a table with a 'path' and a 'query' string",
+            ItemErrorKind::InvalidBusyTimeout   => "busy_timeout has to be a positive number of milliseconds",
+            ItemErrorKind::InvalidPathStatType  => "path_stat has to be a table with a 'path' string",
+            ItemErrorKind::InvalidPatterns      => "include and exclude have to be arrays of glob patterns",
+            ItemErrorKind::InvalidMaxDepth      => "max_depth has to be a positive number",
+            ItemErrorKind::InvalidActivityType  => "activity has to be a table with optional string paths, an array of interrupt names and a device pattern",
+            ItemErrorKind::InvalidIdleAfter     => "idle_after has to be a positive number of seconds",
+            ItemErrorKind::InvalidDerivedType   => "derived has to be a string",
+            ItemErrorKind::InvalidExpression(ref s) => s,
+            ItemErrorKind::InvalidCounter       => "counter has to be a boolean",
+            ItemErrorKind::InvalidCounterBits   => "counter_bits has to be 32 or 64 and needs counter = true",
+            ItemErrorKind::DerivedCounter       => "derived items cannot be counters, use rate() instead",
+            ItemErrorKind::InvalidOutputs       => "outputs has to be an array of output names",
+            ItemErrorKind::MultipleSources      => "multiple sources given, you may only use command or file or shell or sqlite or path_stat or activity or derived",
             ItemErrorKind::MissingKey           => "missing key field",
+            ItemErrorKind::InvalidKey           => "key may not be empty, contain '/', '@' or '..', or start or end with '.'",
             ItemErrorKind::InvalidInterval      => "interval has to be bigger than 0 and smaller than MAX_INT64",
         }
     }
@@ -45,7 +77,7 @@ impl ItemError {
 impl ItemError {
     fn new(key: String ,k: ItemErrorKind) -> ItemError {
         ItemError {
-            key: key,
+            key,
             kind: k,
         }
     }
@@ -63,6 +95,17 @@ impl ::std::fmt::Display for ItemError {
     }
 }
 
+/// Whether `key` can safely be used as a file name, and as a path of directories when
+/// splitting it at its dots. Such keys never point outside of the directory they are in, and
+/// never contain the `@` that separates the name of a rotated file from its time.
+pub fn valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && !key.contains(['/', '@', '\0'])
+        && !key.starts_with('.')
+        && !key.ends_with('.')
+        && !key.contains("..")
+}
+
 /// The different kinds of items one can supervise
 #[derive(Debug, Clone, Eq, PartialEq, PartialOrd)]
 pub enum ItemKind {
@@ -72,6 +115,58 @@ pub enum ItemKind {
     Command(PathBuf, Vec<String>),
     /// A string to be executed in a shell context
     Shell(String),
+    /// A query run against a read-only opened SQLite database, the first row is recorded
+    Sqlite {
+        /// Path to the database file
+        path: PathBuf,
+        /// The query to run
+        query: String,
+        /// Milliseconds to wait for a locked database
+        busy_timeout: u64,
+    },
+    /// Statistics about a file or directory tree
+    PathStat {
+        /// The file or directory to look at
+        path: PathBuf,
+        /// Only count files matching one of these, all if empty
+        include: Vec<Pattern>,
+        /// Neither count files nor descend into directories matching one of these
+        exclude: Vec<Pattern>,
+        /// How deep to descend into the directory tree, unlimited if not given
+        max_depth: Option<u64>,
+    },
+    /// Idle time of the user, derived from input devices and logind sessions
+    Activity {
+        /// The file listing interrupt counts
+        interrupts_file: PathBuf,
+        /// Interrupt names of input devices
+        interrupts: Vec<String>,
+        /// Input device nodes
+        devices: Pattern,
+        /// The directory containing logind session files
+        sessions: PathBuf,
+        /// The directory session TTYs are relative to
+        tty_dir: PathBuf,
+        /// Seconds without activity after which the user is considered idle
+        idle_after: i64,
+    },
+    /// An expression over the values of other keys, computed whenever one of them is updated
+    Derived(Expression),
+}
+
+impl ItemKind {
+    /// The name of the kind, as it is used in the configuration
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ItemKind::File(_) => "file",
+            ItemKind::Command(..) => "command",
+            ItemKind::Shell(_) => "shell",
+            ItemKind::Sqlite { .. } => "sqlite",
+            ItemKind::PathStat { .. } => "path_stat",
+            ItemKind::Activity { .. } => "activity",
+            ItemKind::Derived(_) => "derived",
+        }
+    }
 }
 
 /// A single item, knowing when it is supposed to run next, what should be done and its key.
@@ -82,23 +177,30 @@ pub struct Item {
     pub key: String,
     pub env: BTreeMap<String, String>,
     pub kind: ItemKind,
+    pub counter: Option<Counter>,
+    /// The names of the outputs values are written to, all outputs if not given
+    pub outputs: Option<Vec<String>>,
 }
 
 impl Item {
     pub fn from_toml(table: &toml::Table) -> Result<Item, ItemError> {
 
         let key = match table.get("key") {
-            Some(&toml::Value::String(ref s)) => s.clone(),
+            Some(toml::Value::String(s)) => s.clone(),
             _ => return Err(ItemError::new(String::from(""), ItemErrorKind::MissingKey))
         };
 
+        if !valid_key(&key) {
+            return Err(ItemError::new(key, ItemErrorKind::InvalidKey));
+        }
+
         let command = table.get("command")
             .ok_or_else(|| ItemError::new(key.clone(), ItemErrorKind::MissingValueSection))
             .and_then(|v| {
                 let path : PathBuf;
                 let args : Vec<String>;
                 if let toml::Value::Table(ref v) = *v {
-                    if let (Some(&toml::Value::String(ref s)), Some(&toml::Value::Array(ref a)))
+                    if let (Some(toml::Value::String(s)), Some(toml::Value::Array(a)))
                                                                  = (v.get("path"), v.get("args")) {
                         path = PathBuf::from(&s);
                         args = {
@@ -111,10 +213,10 @@ impl Item {
 
                         Ok(ItemKind::Command(path, args))
                     } else {
-                        return Err(ItemError::new(key.clone(), ItemErrorKind::ValueTableMissingKey));
+                        Err(ItemError::new(key.clone(), ItemErrorKind::ValueTableMissingKey))
                     }
                 } else if let toml::Value::Array(ref a) = *v {
-                    if a.len() < 1 {
+                    if a.is_empty() {
                         return Err(ItemError::new(key.clone(), ItemErrorKind::ValueArrayInvalid));
                     }
                     let mut iter = a.iter().map(|x| x.as_str());
@@ -130,7 +232,7 @@ impl Item {
                     args = Vec::new();
                     Ok(ItemKind::Command(path, args))
                 } else {
-                    return Err(ItemError::new(key.clone(), ItemErrorKind::InvalidValueType));
+                    Err(ItemError::new(key.clone(), ItemErrorKind::InvalidValueType))
                 }
             });
 
@@ -154,8 +256,119 @@ impl Item {
                 }
             });
 
+        let sqlite = table.get("sqlite")
+            .ok_or_else(|| ItemError::new(key.clone(), ItemErrorKind::MissingValueSection))
+            .and_then(|v| {
+                if let toml::Value::Table(ref v) = *v {
+                    let busy_timeout = match v.get("busy_timeout") {
+                        Some(&toml::Value::Integer(x)) if x > 0 => x as u64,
+                        Some(_) => return Err(ItemError::new(key.clone(), ItemErrorKind::InvalidBusyTimeout)),
+                        None => 1000,
+                    };
+                    if let (Some(toml::Value::String(path)), Some(toml::Value::String(query)))
+                                                                 = (v.get("path"), v.get("query")) {
+                        Ok(ItemKind::Sqlite {
+                            path: PathBuf::from(path),
+                            query: query.clone(),
+                            busy_timeout,
+                        })
+                    } else {
+                        Err(ItemError::new(key.clone(), ItemErrorKind::InvalidSqliteType))
+                    }
+                } else {
+                    Err(ItemError::new(key.clone(), ItemErrorKind::InvalidSqliteType))
+                }
+            });
+
+        let path_stat = table.get("path_stat")
+            .ok_or_else(|| ItemError::new(key.clone(), ItemErrorKind::MissingValueSection))
+            .and_then(|v| {
+                let v = match *v {
+                    toml::Value::Table(ref v) => v,
+                    _ => return Err(ItemError::new(key.clone(), ItemErrorKind::InvalidPathStatType)),
+                };
+                let path = match v.get("path") {
+                    Some(toml::Value::String(s)) => PathBuf::from(s),
+                    _ => return Err(ItemError::new(key.clone(), ItemErrorKind::InvalidPathStatType)),
+                };
+                let patterns = |name: &str| match v.get(name) {
+                    Some(toml::Value::Array(a)) => a.iter()
+                        .map(|x| x.as_str().and_then(|x| Pattern::new(x).ok()))
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or_else(|| ItemError::new(key.clone(), ItemErrorKind::InvalidPatterns)),
+                    Some(_) => Err(ItemError::new(key.clone(), ItemErrorKind::InvalidPatterns)),
+                    None => Ok(Vec::new()),
+                };
+                let include = patterns("include")?;
+                let exclude = patterns("exclude")?;
+                let max_depth = match v.get("max_depth") {
+                    Some(&toml::Value::Integer(x)) if x > 0 => Some(x as u64),
+                    Some(_) => return Err(ItemError::new(key.clone(), ItemErrorKind::InvalidMaxDepth)),
+                    None => None,
+                };
+                Ok(ItemKind::PathStat {
+                    path,
+                    include,
+                    exclude,
+                    max_depth,
+                })
+            });
+
+        let activity = table.get("activity")
+            .ok_or_else(|| ItemError::new(key.clone(), ItemErrorKind::MissingValueSection))
+            .and_then(|v| {
+                let v = match *v {
+                    toml::Value::Table(ref v) => v,
+                    _ => return Err(ItemError::new(key.clone(), ItemErrorKind::InvalidActivityType)),
+                };
+                let path = |name: &str, default: &str| match v.get(name) {
+                    Some(toml::Value::String(s)) => Ok(PathBuf::from(s)),
+                    Some(_) => Err(ItemError::new(key.clone(), ItemErrorKind::InvalidActivityType)),
+                    None => Ok(PathBuf::from(default)),
+                };
+                let interrupts = match v.get("interrupts") {
+                    Some(toml::Value::Array(a)) => a.iter()
+                        .map(|x| x.as_str().map(String::from))
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or_else(|| ItemError::new(key.clone(), ItemErrorKind::InvalidActivityType))?,
+                    Some(_) => return Err(ItemError::new(key.clone(), ItemErrorKind::InvalidActivityType)),
+                    None => vec![String::from("i8042")],
+                };
+                let devices = match v.get("devices") {
+                    Some(toml::Value::String(s)) => Pattern::new(s).ok(),
+                    Some(_) => None,
+                    None => Pattern::new("/dev/input/event*").ok(),
+                }.ok_or_else(|| ItemError::new(key.clone(), ItemErrorKind::InvalidActivityType))?;
+                let idle_after = match v.get("idle_after") {
+                    Some(&toml::Value::Integer(x)) if x > 0 => x,
+                    Some(_) => return Err(ItemError::new(key.clone(), ItemErrorKind::InvalidIdleAfter)),
+                    None => 300,
+                };
+                Ok(ItemKind::Activity {
+                    interrupts_file: path("interrupts_file", "/proc/interrupts")?,
+                    interrupts,
+                    devices,
+                    sessions: path("sessions", "/run/systemd/sessions")?,
+                    tty_dir: path("tty_dir", "/dev")?,
+                    idle_after,
+                })
+            });
+
+        let derived = table.get("derived")
+            .ok_or_else(|| ItemError::new(key.clone(), ItemErrorKind::MissingValueSection))
+            .and_then(|v| {
+                if let toml::Value::String(ref s) = *v {
+                    Expression::parse(s).map(ItemKind::Derived).map_err(|e| {
+                        ItemError::new(key.clone(), ItemErrorKind::InvalidExpression(
+                                format!("invalid derived expression, {}", e)))
+                    })
+                } else {
+                    Err(ItemError::new(key.clone(), ItemErrorKind::InvalidDerivedType))
+                }
+            });
+
         let env = match table.get("env") {
-            Some(&toml::Value::Table(ref x)) => {
+            Some(toml::Value::Table(x)) => {
                 x.iter().map(|(k, v)| (k.clone(), v.as_str()))
                     .filter(|&(_, v)| v.is_some())
                     .map(|(k,v)| (k, v.unwrap().into()))
@@ -168,7 +381,7 @@ impl Item {
 
         debug!("Got this env: {:#?}", env);
 
-        let sources = vec![command, shell, path];
+        let sources = vec![command, shell, path, sqlite, path_stat, activity, derived];
 
         {
             if sources.iter().all(|x| x.is_err()) {
@@ -180,9 +393,11 @@ impl Item {
             }
         }
 
-        let kind = try!(sources.into_iter().filter(|x| x.is_ok()).next().unwrap());
+        let kind = sources.into_iter().find(|x| x.is_ok()).unwrap()?;
 
         let time = match table.get("interval") {
+            // Derived items are not scheduled, they are computed when their inputs change
+            _ if matches!(kind, ItemKind::Derived(_)) => 0,
             Some(&toml::Value::Integer(x)) if x <= 0 => {
                 return Err(ItemError {
                     key: key.clone(),
@@ -198,16 +413,73 @@ impl Item {
             }
         };
 
+        let counter = match (table.get("counter"), table.get("counter_bits")) {
+            (None, None) | (Some(&toml::Value::Boolean(false)), None) => None,
+            (Some(&toml::Value::Boolean(true)), None) => Some(Counter { bits: None }),
+            (Some(&toml::Value::Boolean(true)), Some(&toml::Value::Integer(x))) if x == 32 || x == 64 => {
+                Some(Counter { bits: Some(x as u32) })
+            }
+            (Some(&toml::Value::Boolean(_)), _) | (None, Some(_)) => {
+                return Err(ItemError::new(key.clone(), ItemErrorKind::InvalidCounterBits));
+            }
+            (Some(_), _) => return Err(ItemError::new(key.clone(), ItemErrorKind::InvalidCounter)),
+        };
+
+        if counter.is_some() && matches!(kind, ItemKind::Derived(_)) {
+            return Err(ItemError::new(key.clone(), ItemErrorKind::DerivedCounter));
+        }
+
+        let outputs = match table.get("outputs") {
+            Some(toml::Value::Array(a)) => Some(a.iter()
+                .map(|x| x.as_str().map(String::from))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| ItemError::new(key.clone(), ItemErrorKind::InvalidOutputs))?),
+            Some(_) => return Err(ItemError::new(key.clone(), ItemErrorKind::InvalidOutputs)),
+            None => None,
+        };
+
         Ok(Item {
             next_time: 0,
             interval: time,
-            key: key,
-            kind: kind,
-            env: env,
+            key,
+            kind,
+            env,
+            counter,
+            outputs,
         })
     }
 }
 
+impl Item {
+    /// The keys running this item records values for, as far as they are known in advance
+    pub fn known_keys(&self) -> Vec<String> {
+        let sub_keys = match self.kind {
+            ItemKind::PathStat { .. } => path_stat::SUB_KEYS,
+            ItemKind::Activity { .. } => activity::SUB_KEYS,
+            _ => return vec![self.key.clone()],
+        };
+        sub_keys.iter().map(|k| format!("{}.{}", self.key, k)).collect()
+    }
+
+    /// Whether running this item records a value for `key`
+    pub fn provides(&self, key: &str) -> bool {
+        let sub_key = if key.len() > self.key.len() + 1
+            && key.starts_with(&self.key[..])
+            && key[self.key.len()..].starts_with('.') {
+            Some(&key[self.key.len() + 1..])
+        } else {
+            None
+        };
+
+        match self.kind {
+            ItemKind::Sqlite { .. } => key == self.key || sub_key.is_some(),
+            ItemKind::PathStat { .. } => sub_key.is_some_and(|k| path_stat::SUB_KEYS.contains(&k)),
+            ItemKind::Activity { .. } => sub_key.is_some_and(|k| activity::SUB_KEYS.contains(&k)),
+            _ => key == self.key,
+        }
+    }
+}
+
 impl PartialOrd for Item {
     fn partial_cmp(&self, other: &Self) -> Option<::std::cmp::Ordering> {
         Some(self.cmp(other))
@@ -232,7 +504,24 @@ mod tests {
     use std::collections::BinaryHeap;
     use std::collections::BTreeMap;
 
-    use item::{Item,ItemKind};
+    use item::{self, Item, ItemKind};
+
+    #[test]
+    fn keys_stay_inside_output() {
+        assert!(item::valid_key("os.battery"));
+        assert!(item::valid_key("os.battery-0_now"));
+        assert!(!item::valid_key(""));
+        assert!(!item::valid_key("."));
+        assert!(!item::valid_key(".."));
+        assert!(!item::valid_key("os..battery"));
+        assert!(!item::valid_key(".hidden"));
+        assert!(!item::valid_key("os."));
+        assert!(!item::valid_key("../etc/passwd"));
+        assert!(!item::valid_key("os/battery"));
+        // Rotated files are named like os.battery@20160201T120000Z
+        assert!(!item::valid_key("user@host.load"));
+        assert!(!item::valid_key("os.battery@20160201T120000Z"));
+    }
 
     #[test]
     fn items_ordered_by_smallest_time_first() {
@@ -243,6 +532,8 @@ mod tests {
             env: BTreeMap::new(),
             key: String::from("tests.one"),
             kind: ItemKind::File(PathBuf::from("/dev/null")),
+            counter: None,
+            outputs: None,
         });
         heap.push(Item {
             next_time: 3,
@@ -250,6 +541,8 @@ mod tests {
             env: BTreeMap::new(),
             key: String::from("tests.two"),
             kind: ItemKind::File(PathBuf::from("/dev/null")),
+            counter: None,
+            outputs: None,
         });
 
         if let Some(item) = heap.pop() {
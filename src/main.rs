@@ -14,6 +14,11 @@ extern crate clap;
 extern crate env_logger;
 extern crate xdg;
 extern crate time;
+extern crate rusqlite;
+extern crate glob;
+extern crate flate2;
+extern crate zstd;
+extern crate signal_hook;
 
 use std::fs::File;
 use std::path::PathBuf;
@@ -24,7 +29,22 @@ use clap::{Arg, App};
 
 mod conf;
 mod item;
+mod output;
+mod aggregate;
 mod app;
+mod compact;
+mod export;
+mod import;
+mod plot;
+mod query;
+mod report;
+mod series;
+mod counter;
+mod dashboard;
+mod derived;
+mod expression;
+mod source;
+mod units;
 
 fn main() {
     let matches = App::new("Antikörper")
@@ -54,6 +74,12 @@ fn main() {
                          .short("v")
                          .multiple(true)
                          .help("Sets the level of verbosity"))
+                    .subcommand(query::subcommand())
+                    .subcommand(plot::subcommand())
+                    .subcommand(report::subcommand())
+                    .subcommand(export::subcommand())
+                    .subcommand(import::subcommand())
+                    .subcommand(compact::subcommand())
                     .get_matches();
 
     trace!("Getting XDG Base directories");
@@ -63,15 +89,13 @@ fn main() {
         0 => log::LogLevelFilter::Off,
         1 => log::LogLevelFilter::Warn,
         2 => log::LogLevelFilter::Debug,
-        3 | _ => log::LogLevelFilter::Trace,
+        _ => log::LogLevelFilter::Trace,
     };
 
     env_logger::LogBuilder::new().filter(None, level).init().unwrap();
 
     trace!("Matching for config value");
-    let config_path = matches.value_of("config").and_then(|s| {
-        Some(PathBuf::from(s))
-    }).or_else(|| {
+    let config_path = matches.value_of("config").map(PathBuf::from).or_else(|| {
         xdg_dirs.find_config_file("config.toml")
     });
     trace!("Value is: {:#?}", config_path);
@@ -81,7 +105,7 @@ fn main() {
         None => {
             println!("Could not find config file, make sure to give one with the --config option.");
             println!("The default is XDG_CONFIG_HOME/antikoerper/config.toml");
-            println!("");
+            println!();
             println!("Check out https://github.com/anti-koerper/antikoerper for details
 on what should be in that file.");
             return;
@@ -94,9 +118,9 @@ on what should be in that file.");
         None => PathBuf::new(),
     };
 
-    if matches.is_present("daemonize") {
+    if matches.is_present("daemonize") && matches.subcommand_name().is_none() {
 
-        let mut child = process::Command::new(std::env::args().next().unwrap());
+        let mut child = process::Command::new(env::args().next().unwrap());
         let args = env::args().skip(1).filter(|a| a != "--daemonize" && a != "-d")
             .collect::<Vec<_>>();
         child.args(&args).stdin(process::Stdio::null()).stdout(process::Stdio::null()).stderr(process::Stdio::null());
@@ -127,6 +151,20 @@ on what should be in that file.");
                                   config_path.display() , e),
     };
 
-    app::start(config);
-
+    let result = match matches.subcommand() {
+        ("query", Some(matches)) => query::run(&config, matches),
+        ("plot", Some(matches)) => plot::run(&config, matches),
+        ("report", Some(matches)) => report::run(&config, matches),
+        ("export", Some(matches)) => export::run(&config, matches),
+        ("import", Some(matches)) => import::run(&config, matches),
+        ("compact", Some(matches)) => compact::run(&config, matches),
+        _ => {
+            app::start(config);
+            Ok(())
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
 }
@@ -0,0 +1,678 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use item::valid_key;
+use output::{Output, Sample};
+use output::format::{self, Format, Timestamp};
+use output::rotate::{self, Rotation};
+
+/// How keys are mapped to files
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Layout {
+    /// `os.battery` is written to the file `os.battery`
+    Flat,
+    /// `os.battery` is written to the file `battery` in the directory `os`
+    Nested,
+}
+
+impl Layout {
+    pub fn from_name(s: &str) -> Option<Layout> {
+        match s {
+            "flat" => Some(Layout::Flat),
+            "nested" => Some(Layout::Nested),
+            _ => None,
+        }
+    }
+
+    /// The path of the file of `key` inside `directory`, `None` if the key could point
+    /// outside of it
+    pub fn path(&self, directory: &Path, key: &str) -> Option<PathBuf> {
+        if !valid_key(key) {
+            return None;
+        }
+        Some(match *self {
+            Layout::Flat => directory.join(key),
+            Layout::Nested => directory.join(key.replace('.', "/")),
+        })
+    }
+}
+
+/// Reads back the samples of a file written by the file output, in the order they were written.
+/// Files holding the values of several keys, as written by `export`, are read as well.
+#[derive(Debug)]
+pub struct Records<R> {
+    reader: R,
+    key: String,
+    format: Format,
+    timestamp: Timestamp,
+    /// Whether records name their key, instead of all being of `key`
+    keyed: bool,
+    /// The first line, if it turned out not to be a header
+    first: Option<String>,
+}
+
+impl<R: BufRead> Records<R> {
+    /// Reads the header of the file of `key` from `reader`
+    pub fn new(mut reader: R, key: &str) -> io::Result<Records<R>> {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let (format, timestamp, keyed, first) = match Format::parse_header(&line) {
+            Some((_, version, _)) if version > format::VERSION => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "file is in version {} of its format, only up to {} is known", version, format::VERSION)));
+            }
+            Some((Format::Csv, _, timestamp)) => {
+                let mut columns = String::new();
+                reader.read_line(&mut columns)?;
+                (Format::Csv, timestamp, columns.trim_end() == format::KEYED_COLUMNS, None)
+            }
+            Some((format, _, timestamp)) => (format, timestamp, format == Format::JsonLines, None),
+            None => (Format::Text, Timestamp::Epoch, false, Some(line)),
+        };
+        Ok(Records {
+            reader,
+            key: key.to_owned(),
+            format,
+            timestamp,
+            keyed,
+            first,
+        })
+    }
+
+    /// The format of the records and their times
+    pub fn format(&self) -> (Format, Timestamp) {
+        (self.format, self.timestamp)
+    }
+
+    /// Whether the records name their key, so the key given to `new` is not needed
+    pub fn keyed(&self) -> bool {
+        self.keyed
+    }
+
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = match self.first.take() {
+            Some(line) => line,
+            None => {
+                let mut line = String::new();
+                if self.reader.read_line(&mut line)? == 0 {
+                    return Ok(None);
+                }
+                line
+            }
+        };
+        if line.ends_with('\n') {
+            line.pop();
+        }
+        Ok(Some(line))
+    }
+}
+
+impl<R: BufRead> Iterator for Records<R> {
+    type Item = io::Result<Sample>;
+
+    fn next(&mut self) -> Option<io::Result<Sample>> {
+        loop {
+            let mut record = match self.read_line() {
+                Ok(Some(line)) => line,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            };
+            if record.is_empty() {
+                continue;
+            }
+            while self.format.continues(&record) {
+                match self.read_line() {
+                    Ok(Some(line)) => {
+                        record.push('\n');
+                        record.push_str(&line);
+                    }
+                    Ok(None) => break,
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+            let parsed = if self.keyed {
+                self.format.parse_keyed_record(self.timestamp, &record)
+            } else {
+                self.format.parse_record(self.timestamp, &record).map(|(time, value)| (self.key.clone(), time, value))
+            };
+            match parsed {
+                Some((key, time, value)) => return Some(Ok(Sample { time, key, value })),
+                None => warn!("Skipping invalid record of {}: {}", self.key, record),
+            }
+        }
+    }
+}
+
+/// When written records are synced to disk
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Fsync {
+    /// Whenever the system decides to
+    Never,
+    /// Every flush interval
+    Interval,
+    /// After every record, which also means records are not buffered
+    Always,
+}
+
+impl Fsync {
+    pub fn from_name(s: &str) -> Option<Fsync> {
+        match s {
+            "never" => Some(Fsync::Never),
+            "interval" => Some(Fsync::Interval),
+            "always" => Some(Fsync::Always),
+            _ => None,
+        }
+    }
+}
+
+/// How files are kept open and written
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Writing {
+    /// How long records are buffered before they are written, zero writes them right away
+    pub flush_interval: Duration,
+    pub fsync: Fsync,
+    /// How many files are kept open at most, the least recently used one is closed first
+    pub max_open: usize,
+}
+
+impl Default for Writing {
+    fn default() -> Writing {
+        Writing {
+            flush_interval: Duration::from_secs(1),
+            fsync: Fsync::Never,
+            max_open: 64,
+        }
+    }
+}
+
+/// The file in the directory of a file output that everything writing to it holds a shared
+/// lock on, so `compact` knows not to rewrite files meanwhile
+const LOCK: &str = ".lock";
+
+/// Opens the lock file of the file output in `directory`
+pub fn lock_file(directory: &Path) -> io::Result<File> {
+    OpenOptions::new().append(true).create(true).open(directory.join(LOCK))
+}
+
+/// How often open files are checked for having been replaced, at least
+const REPLACED_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Counts the files replaced in this process by something else than the file output
+static REPLACEMENTS: AtomicU64 = AtomicU64::new(0);
+
+/// Tells all file outputs that a file they may have open was replaced, like by compacting it,
+/// so they check their files before writing to them next time
+pub fn replaced() {
+    REPLACEMENTS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// A file kept open, and the records not written to it yet
+#[derive(Debug)]
+struct Handle {
+    path: PathBuf,
+    file: File,
+    /// How many bytes were written to the file
+    written: u64,
+    buffer: String,
+    /// When the last record was appended, in seconds since the epoch
+    modified: Option<i64>,
+    /// Whether something was written since the last sync
+    unsynced: bool,
+    /// When the handle was used last, for closing the least recently used one
+    used: u64,
+    /// When the file was last checked for having been replaced, and `REPLACEMENTS` back then
+    checked: (Instant, u64),
+}
+
+impl Handle {
+    fn open(path: &Path) -> io::Result<Handle> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        let metadata = file.metadata()?;
+        Ok(Handle {
+            path: path.to_owned(),
+            file,
+            written: metadata.len(),
+            buffer: String::new(),
+            modified: rotate::modified(&metadata),
+            unsynced: false,
+            used: 0,
+            checked: (Instant::now(), REPLACEMENTS.load(Ordering::SeqCst)),
+        })
+    }
+
+    /// The size of the file once the buffer is written
+    fn len(&self) -> u64 {
+        self.written + self.buffer.len() as u64
+    }
+
+    /// Whether the file was removed or replaced since it was opened, by something else than us
+    fn replaced(&self) -> bool {
+        match (fs::metadata(&self.path), self.file.metadata()) {
+            (Ok(ref a), Ok(ref b)) => (a.dev(), a.ino()) != (b.dev(), b.ino()),
+            _ => true,
+        }
+    }
+
+    /// Writes the buffer, starting with `header` if the file is empty, and syncs if `sync` is set.
+    /// Whether the file was replaced is checked every `interval`, or if we were told about it.
+    fn write_out(&mut self, header: &str, sync: bool, interval: Duration) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            let replacements = REPLACEMENTS.load(Ordering::SeqCst);
+            let check = self.checked.1 != replacements || self.checked.0.elapsed() >= interval;
+            if check {
+                self.checked = (Instant::now(), replacements);
+            }
+            // Our records go to the file now at the path, not to the one we opened
+            if check && self.replaced() {
+                let mut new = Handle::open(&self.path)?;
+                new.buffer = self.buffer.split_off(0);
+                new.modified = self.modified;
+                new.used = self.used;
+                *self = new;
+            }
+            let header = if self.written == 0 { header } else { "" };
+            self.buffer.insert_str(0, header);
+            if let Err(e) = self.file.write_all(self.buffer.as_bytes()) {
+                // The records are kept for the next try, part of them may have been written, so
+                // we ask the file how much it has
+                self.buffer.drain(..header.len());
+                self.written = self.file.metadata()?.len();
+                return Err(e);
+            }
+            self.written += self.buffer.len() as u64;
+            self.buffer.clear();
+            self.unsynced = true;
+        }
+        if sync && self.unsynced {
+            self.file.sync_data()?;
+            self.unsynced = false;
+        }
+        Ok(())
+    }
+}
+
+/// Appends a record per sample to a file per key in a directory
+#[derive(Debug)]
+pub struct FileOutput {
+    directory: PathBuf,
+    layout: Layout,
+    format: Format,
+    timestamp: Timestamp,
+    rotation: Rotation,
+    writing: Writing,
+    /// Keys whose files are known to be in our format
+    checked: BTreeSet<String>,
+    /// Open files per key
+    handles: BTreeMap<String, Handle>,
+    /// Counts up with every record, for knowing which handle was used least recently
+    uses: u64,
+    last_flush: Instant,
+    /// The lock file of the directory, locked shared as long as we are around
+    _lock: File,
+}
+
+impl FileOutput {
+    pub fn new(directory: &Path, layout: Layout, format: Format, timestamp: Timestamp,
+               rotation: Rotation, writing: Writing) -> io::Result<FileOutput>
+    {
+        fs::create_dir_all(directory)?;
+        let lock = lock_file(directory)?;
+        match lock.try_lock_shared() {
+            Ok(()) => (),
+            Err(TryLockError::WouldBlock) => {
+                info!("Waiting for the files in {} to be compacted", directory.display());
+                lock.lock_shared()?;
+            }
+            Err(TryLockError::Error(e)) => return Err(e),
+        }
+        Ok(FileOutput {
+            directory: directory.to_owned(),
+            layout,
+            format,
+            timestamp,
+            rotation,
+            writing,
+            checked: BTreeSet::new(),
+            handles: BTreeMap::new(),
+            uses: 0,
+            last_flush: Instant::now(),
+            _lock: lock,
+        })
+    }
+
+    /// How often open files are checked for having been replaced by something else than us
+    fn replaced_interval(&self) -> Duration {
+        self.writing.flush_interval.max(REPLACED_INTERVAL)
+    }
+
+    /// Writes out and closes the handle of `key`, checking whether its file was replaced
+    fn close(&mut self, key: &str) -> io::Result<()> {
+        let sync = self.writing.fsync != Fsync::Never;
+        let header = self.format.header(self.timestamp);
+        match self.handles.remove(key) {
+            Some(mut handle) => handle.write_out(&header, sync, Duration::from_secs(0)),
+            None => Ok(()),
+        }
+    }
+
+    /// The handle of `key`, opened if needed
+    fn handle(&mut self, key: &str, path: &Path) -> io::Result<&mut Handle> {
+        if !self.handles.contains_key(key) {
+            if self.handles.len() >= self.writing.max_open {
+                let oldest = self.handles.iter().min_by_key(|&(_, h)| h.used).map(|(k, _)| k.clone());
+                if let Some(oldest) = oldest {
+                    if let Err(e) = self.close(&oldest) {
+                        error!("Could not write records of {}: {}", oldest, e);
+                    }
+                }
+            }
+            self.handles.insert(key.to_owned(), Handle::open(path)?);
+        }
+        self.uses += 1;
+        let handle = self.handles.get_mut(key).unwrap();
+        handle.used = self.uses;
+        Ok(handle)
+    }
+
+    fn append(&mut self, sample: &Sample, path: &Path) -> io::Result<()> {
+        let record = self.format.record(self.timestamp, sample);
+        let time = sample.time.sec;
+        if self.rotation.is_enabled() {
+            let due = match self.handles.get(&sample.key) {
+                Some(h) => self.rotation.due(h.len(), h.modified, time, record.len() as u64),
+                None => match fs::metadata(path) {
+                    Ok(ref m) => self.rotation.due(m.len(), rotate::modified(m), time, record.len() as u64),
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => false,
+                    Err(e) => return Err(e),
+                },
+            };
+            if due {
+                self.close(&sample.key)?;
+                // Compressing and removing segments goes on in the background
+                self.rotation.rotate(path, time)?;
+            }
+        }
+
+        let writing = self.writing;
+        let header = self.format.header(self.timestamp);
+        let interval = self.replaced_interval();
+        let handle = self.handle(&sample.key, path)?;
+        handle.buffer.push_str(&record);
+        handle.modified = Some(time);
+        if writing.flush_interval == Duration::from_secs(0) || writing.fsync == Fsync::Always {
+            handle.write_out(&header, writing.fsync == Fsync::Always, interval)?;
+        }
+        Ok(())
+    }
+
+    /// Makes sure we do not append records to a file written in another format
+    fn check(&self, path: &Path) -> io::Result<()> {
+        let mut line = String::new();
+        match File::open(path) {
+            Ok(f) => BufReader::new(f).read_line(&mut line)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if line.is_empty() {
+            return Ok(());
+        }
+
+        let (format, timestamp) = match Format::parse_header(&line) {
+            Some((format, _, timestamp)) => (format, timestamp),
+            None => (Format::Text, Timestamp::Epoch),
+        };
+        if (format, timestamp) != (self.format, self.timestamp) {
+            return Err(io::Error::other(format!(
+                "file is in format {} with {} timestamps, but the output is configured for {} with {}",
+                format.as_str(), timestamp.as_str(), self.format.as_str(), self.timestamp.as_str())));
+        }
+        Ok(())
+    }
+}
+
+impl Output for FileOutput {
+    fn write(&mut self, sample: &Sample) -> io::Result<()> {
+        let path = match self.layout.path(&self.directory, &sample.key) {
+            Some(p) => p,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              format!("invalid key {}", sample.key))),
+        };
+        let result = if self.checked.contains(&sample.key) {
+            Ok(())
+        } else {
+            self.check(&path).and_then(|_| match path.parent() {
+                Some(parent) if self.layout == Layout::Nested => fs::create_dir_all(parent),
+                _ => Ok(()),
+            })
+        }.and_then(|_| self.append(sample, &path));
+
+        match result {
+            Ok(()) => {
+                self.checked.insert(sample.key.clone());
+                Ok(())
+            }
+            Err(e) => Err(io::Error::new(e.kind(), format!("{}: {}", path.display(), e))),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.last_flush.elapsed() < self.writing.flush_interval {
+            return Ok(());
+        }
+        self.last_flush = Instant::now();
+        let header = self.format.header(self.timestamp);
+        let sync = self.writing.fsync == Fsync::Interval;
+        let interval = self.replaced_interval();
+        let mut result = Ok(());
+        for handle in self.handles.values_mut() {
+            if let Err(e) = handle.write_out(&header, sync, interval) {
+                result = Err(io::Error::new(e.kind(), format!("{}: {}", handle.path.display(), e)));
+            }
+        }
+        result
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let keys = self.handles.keys().cloned().collect::<Vec<_>>();
+        let mut result = Ok(());
+        for key in keys {
+            if let Err(e) = self.close(&key) {
+                result = Err(io::Error::new(e.kind(), format!("records of {}: {}", key, e)));
+            }
+        }
+        result
+    }
+}
+
+impl Drop for FileOutput {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            error!("Could not write {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{Read, Write};
+    use std::path::Path;
+    use std::time::Duration;
+
+    use output::{Output, Sample};
+    use output::file::{replaced, FileOutput, Fsync, Layout, Records, Writing};
+    use output::format::{Format, Timestamp};
+    use output::rotate::{segments, Rotation};
+
+    /// Writing every record right away
+    fn immediate() -> Writing {
+        Writing {
+            flush_interval: Duration::from_secs(0),
+            ..Writing::default()
+        }
+    }
+
+    fn read(path: &Path) -> String {
+        let mut content = String::new();
+        File::open(path).unwrap().read_to_string(&mut content).unwrap();
+        content
+    }
+
+    #[test]
+    fn headers_and_formats() {
+        let dir = env::temp_dir().join("antikoerper-file-output");
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut csv = FileOutput::new(&dir, Layout::Flat, Format::Csv, Timestamp::Epoch,
+                                      Rotation::default(), immediate()).unwrap();
+        csv.write(&Sample::new("a", 1454328000, "1\n")).unwrap();
+        csv.write(&Sample::new("a", 1454328000, "2")).unwrap();
+        let mut content = String::new();
+        File::open(dir.join("a")).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "# antikoerper csv v1 epoch\ntime,value\n1454328000,1\n1454328000,2\n");
+
+        // Files without a header are in the format of earlier versions
+        writeln!(File::create(dir.join("b")).unwrap(), "1454327000 1").unwrap();
+        let mut text = FileOutput::new(&dir, Layout::Flat, Format::Text, Timestamp::Epoch,
+                                       Rotation::default(), immediate()).unwrap();
+        text.write(&Sample::new("b", 1454328000, "2\n")).unwrap();
+        assert!(text.write(&Sample::new("a", 1454328000, "3\n")).is_err());
+        let mut content = String::new();
+        File::open(dir.join("b")).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "1454327000 1\n1454328000 2\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn nested_layout() {
+        let dir = env::temp_dir().join("antikoerper-file-output-nested");
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut nested = FileOutput::new(&dir, Layout::Nested, Format::Text, Timestamp::Epoch,
+                                         Rotation::default(), immediate()).unwrap();
+        nested.write(&Sample::new("os.battery.now", 1454328000, "1\n")).unwrap();
+        assert!(dir.join("os/battery/now").is_file());
+        assert!(nested.write(&Sample::new("os..battery", 1454328000, "1\n")).is_err());
+        assert!(nested.write(&Sample::new("../battery", 1454328000, "1\n")).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotation() {
+        let dir = env::temp_dir().join("antikoerper-file-output-rotation");
+        let _ = fs::remove_dir_all(&dir);
+
+        let rotation = Rotation {
+            max_size: Some(60),
+            ..Rotation::default()
+        };
+        let mut text = FileOutput::new(&dir, Layout::Flat, Format::Text, Timestamp::Epoch,
+                                       rotation, immediate()).unwrap();
+        for &time in &[1454328000, 1454328060, 1454328120] {
+            text.write(&Sample::new("os.battery", time, "100\n")).unwrap();
+        }
+        let segments = segments(&dir.join("os.battery")).unwrap();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].end, 1454328120);
+
+        // Every segment starts with a header
+        let mut content = String::new();
+        File::open(&segments[0].path).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "# antikoerper text v1 epoch\n1454328000 100\n1454328060 100\n");
+        let mut content = String::new();
+        File::open(dir.join("os.battery")).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "# antikoerper text v1 epoch\n1454328120 100\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn buffering() {
+        let dir = env::temp_dir().join("antikoerper-file-output-buffering");
+        let _ = fs::remove_dir_all(&dir);
+
+        let writing = Writing {
+            flush_interval: Duration::from_secs(3600),
+            fsync: Fsync::Interval,
+            max_open: 1,
+        };
+        let mut text = FileOutput::new(&dir, Layout::Flat, Format::Text, Timestamp::Epoch,
+                                       Rotation::default(), writing).unwrap();
+        text.write(&Sample::new("a", 1454328000, "1\n")).unwrap();
+        text.flush().unwrap();
+        assert_eq!(read(&dir.join("a")), "");
+        // Only one file is kept open, the other one is written and closed
+        text.write(&Sample::new("b", 1454328000, "1\n")).unwrap();
+        assert_eq!(read(&dir.join("a")), "# antikoerper text v1 epoch\n1454328000 1\n");
+        text.write(&Sample::new("b", 1454328000, "2\n")).unwrap();
+
+        // Records still buffered go to the file at the path, even if it was replaced
+        fs::remove_file(dir.join("b")).unwrap();
+        drop(text);
+        assert_eq!(read(&dir.join("b")), "# antikoerper text v1 epoch\n1454328000 1\n1454328000 2\n");
+
+        // Files are not checked before every write, but right after we were told
+        let mut text = FileOutput::new(&dir, Layout::Flat, Format::Text, Timestamp::Epoch,
+                                       Rotation::default(), immediate()).unwrap();
+        text.write(&Sample::new("c", 1454328000, "1\n")).unwrap();
+        fs::remove_file(dir.join("c")).unwrap();
+        replaced();
+        text.write(&Sample::new("c", 1454328000, "2\n")).unwrap();
+        assert_eq!(read(&dir.join("c")), "# antikoerper text v1 epoch\n1454328000 2\n");
+        drop(text);
+
+        // Records that could not be written are kept for the next try, with the header
+        let mut text = FileOutput::new(&dir, Layout::Flat, Format::Text, Timestamp::Epoch,
+                                       Rotation::default(), immediate()).unwrap();
+        text.write(&Sample::new("d", 1454328000, "1\n")).unwrap();
+        fs::remove_file(dir.join("d")).unwrap();
+        File::create(dir.join("d")).unwrap();
+        text.handles.get_mut("d").unwrap().file = File::open(dir.join("d")).unwrap();
+        assert!(text.write(&Sample::new("d", 1454328000, "2\n")).is_err());
+        text.handles.get_mut("d").unwrap().file = OpenOptions::new().append(true).open(dir.join("d")).unwrap();
+        text.write(&Sample::new("d", 1454328000, "3\n")).unwrap();
+        assert_eq!(read(&dir.join("d")), "# antikoerper text v1 epoch\n1454328000 2\n1454328000 3\n");
+        drop(text);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn records() {
+        let samples = [("1454328000", "99"), ("1454328060", "Fully\ncharged, \"100%\"")];
+        for &format in &[Format::Text, Format::Csv, Format::JsonLines] {
+            let mut file = format.header(Timestamp::Rfc3339);
+            for &(time, value) in &samples {
+                file.push_str(&format.record(Timestamp::Rfc3339,
+                                             &Sample::new("os.battery", time.parse().unwrap(), value)));
+            }
+            let records = Records::new(file.as_bytes(), "os.battery").unwrap()
+                .map(|r| r.map(|s| (s.time.sec.to_string(), s.value)))
+                .collect::<Result<Vec<_>, _>>().unwrap();
+            let expected = samples.iter().map(|&(t, v)| (t.to_owned(), v.to_owned())).collect::<Vec<_>>();
+            assert_eq!(records, expected, "{:?}", format);
+        }
+
+        // Files of earlier versions, without a header
+        let records = Records::new(&b"1454328000 99\n\nnow 98\n1454328060 98"[..], "os.battery").unwrap()
+            .map(|r| r.unwrap().value)
+            .collect::<Vec<_>>();
+        assert_eq!(records, ["99", "98"]);
+
+        // Exports of several keys
+        let file = "# antikoerper csv v1 epoch\ntime,key,value\n1454328000,os.battery,99\n1454328000,os.load,\"0.5\"\n";
+        let records = Records::new(file.as_bytes(), "export").unwrap()
+            .map(|r| r.map(|s| (s.key, s.value)))
+            .collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(records, [(String::from("os.battery"), String::from("99")),
+                             (String::from("os.load"), String::from("0.5"))]);
+    }
+}
@@ -0,0 +1,432 @@
+//! The record formats of the file output
+//!
+//! Files start with a header naming the format, its version and the timestamp format, so
+//! they can be read back unambiguously. Files without a header are in the `text` format
+//! with `epoch` timestamps, as written by earlier versions.
+
+use rustc_serialize::json::Json;
+use time::{self, Timespec};
+
+use output::Sample;
+
+/// The version of the record formats written
+pub const VERSION: u32 = 1;
+
+/// The names of the columns of CSV files holding the values of several keys
+pub const KEYED_COLUMNS: &str = "time,key,value";
+
+/// How a sample is written as a line
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Format {
+    /// `<time> <value>`, with backslashes, newlines and carriage returns in the value escaped
+    Text,
+    /// `<time>,<value>`, quoted as in RFC 4180 when needed
+    Csv,
+    /// `{"time":<time>,"key":"<key>","value":<value>}`, with numeric values as JSON numbers
+    JsonLines,
+}
+
+/// How the time of a sample is written
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Timestamp {
+    /// Seconds since the epoch
+    Epoch,
+    /// Milliseconds since the epoch
+    EpochMillis,
+    /// Like `2016-02-01T12:00:00.000Z`
+    Rfc3339,
+}
+
+impl Format {
+    pub fn from_name(s: &str) -> Option<Format> {
+        match s {
+            "text" => Some(Format::Text),
+            "csv" => Some(Format::Csv),
+            "jsonl" => Some(Format::JsonLines),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Format::Text => "text",
+            Format::Csv => "csv",
+            Format::JsonLines => "jsonl",
+        }
+    }
+
+    /// What is written at the start of a new file
+    pub fn header(&self, timestamp: Timestamp) -> String {
+        match *self {
+            Format::Text => format!("# antikoerper text v{} {}\n", VERSION, timestamp.as_str()),
+            Format::Csv => format!("# antikoerper csv v{} {}\ntime,value\n", VERSION, timestamp.as_str()),
+            Format::JsonLines => format!("{{\"antikoerper\":\"jsonl\",\"version\":{},\"timestamp\":\"{}\"}}\n",
+                                         VERSION, timestamp.as_str()),
+        }
+    }
+
+    /// Like `header`, for files holding the values of several keys. CSV files get a column
+    /// for the key, JSON Lines records name their key anyway.
+    pub fn keyed_header(&self, timestamp: Timestamp) -> String {
+        match *self {
+            Format::Csv => format!("# antikoerper csv v{} {}\n{}\n", VERSION, timestamp.as_str(), KEYED_COLUMNS),
+            _ => self.header(timestamp),
+        }
+    }
+
+    /// Reads the format, version and timestamp format from the first line of a file.
+    /// `None` if it is not a header.
+    pub fn parse_header(line: &str) -> Option<(Format, u32, Timestamp)> {
+        let line = line.trim_end();
+        if let Some(rest) = line.strip_prefix("# antikoerper ") {
+            let fields = rest.split(' ').collect::<Vec<_>>();
+            if fields.len() != 3 {
+                return None;
+            }
+            let version = fields[1].strip_prefix('v')?.parse().ok()?;
+            return Some((Format::from_name(fields[0])?, version, Timestamp::from_name(fields[2])?));
+        }
+
+        let json = Json::from_str(line).ok()?;
+        let header = json.as_object()?;
+        let format = Format::from_name(header.get("antikoerper")?.as_string()?)?;
+        let version = header.get("version")?.as_u64()? as u32;
+        let timestamp = Timestamp::from_name(header.get("timestamp")?.as_string()?)?;
+        Some((format, version, timestamp))
+    }
+
+    /// Formats a sample as a single line, including the newline
+    pub fn record(&self, timestamp: Timestamp, sample: &Sample) -> String {
+        let value = sample.value.trim_end_matches(['\n', '\r']);
+        let time = timestamp.format(sample.time);
+        match *self {
+            Format::Text => {
+                let mut escaped = String::with_capacity(value.len());
+                for c in value.chars() {
+                    match c {
+                        '\\' => escaped.push_str("\\\\"),
+                        '\n' => escaped.push_str("\\n"),
+                        '\r' => escaped.push_str("\\r"),
+                        c => escaped.push(c),
+                    }
+                }
+                format!("{} {}\n", time, escaped)
+            }
+            Format::Csv => format!("{},{}\n", time, csv_field(value)),
+            Format::JsonLines => {
+                let time = match timestamp {
+                    Timestamp::Rfc3339 => Json::String(time).to_string(),
+                    _ => time,
+                };
+                let value = if is_json_number(value.trim()) {
+                    value.trim().to_owned()
+                } else {
+                    Json::String(value.to_owned()).to_string()
+                };
+                format!("{{\"time\":{},\"key\":{},\"value\":{}}}\n",
+                        time, Json::String(sample.key.clone()), value)
+            }
+        }
+    }
+
+    /// Like `record`, for files holding the values of several keys. Text records have no room
+    /// for the key, so they are the same as those of `record`.
+    pub fn keyed_record(&self, timestamp: Timestamp, sample: &Sample) -> String {
+        match *self {
+            Format::Csv => {
+                let record = self.record(timestamp, sample);
+                let (time, value) = record.split_once(',').unwrap();
+                format!("{},{},{}", time, csv_field(&sample.key), value)
+            }
+            _ => self.record(timestamp, sample),
+        }
+    }
+
+    /// Reads the key, time and value back from a record written by `keyed_record`, without
+    /// the newline ending it. `None` if it is not a valid record or one without a key.
+    pub fn parse_keyed_record(&self, timestamp: Timestamp, record: &str) -> Option<(String, Timespec, String)> {
+        match *self {
+            Format::Text => None,
+            Format::Csv => {
+                let (time, rest) = record.split_once(',')?;
+                let (key, value) = match rest.strip_prefix('"') {
+                    Some(quoted) => {
+                        // The key ends at the first quote that is not doubled
+                        let mut key = String::new();
+                        let mut chars = quoted.char_indices();
+                        loop {
+                            match chars.next()? {
+                                (i, '"') if quoted[i + 1..].starts_with('"') => {
+                                    key.push('"');
+                                    chars.next();
+                                }
+                                (i, '"') => break (key, quoted[i + 1..].strip_prefix(',')?),
+                                (_, c) => key.push(c),
+                            }
+                        }
+                    }
+                    None => {
+                        let (key, value) = rest.split_once(',')?;
+                        (key.to_owned(), value)
+                    }
+                };
+                let (time, value) = self.parse_record(timestamp, &format!("{},{}", time, value))?;
+                Some((key, time, value))
+            }
+            Format::JsonLines => {
+                let (time, value) = self.parse_record(timestamp, record)?;
+                let json = Json::from_str(record).ok()?;
+                let key = json.find("key")?.as_string()?.to_owned();
+                Some((key, time, value))
+            }
+        }
+    }
+
+    /// Reads the time and value back from a record written by `record`, without the newline
+    /// ending it. CSV records with quoted newlines span several lines. `None` if it is not a
+    /// valid record.
+    pub fn parse_record(&self, timestamp: Timestamp, record: &str) -> Option<(Timespec, String)> {
+        match *self {
+            Format::Text => {
+                let (time, escaped) = record.split_once(' ').unwrap_or((record, ""));
+                let mut value = String::with_capacity(escaped.len());
+                let mut chars = escaped.chars();
+                while let Some(c) = chars.next() {
+                    if c != '\\' {
+                        value.push(c);
+                        continue;
+                    }
+                    value.push(match chars.next()? {
+                        'n' => '\n',
+                        'r' => '\r',
+                        c => c,
+                    });
+                }
+                Some((timestamp.parse(time)?, value))
+            }
+            Format::Csv => {
+                let (time, value) = record.split_once(',')?;
+                let value = match value.strip_prefix('"') {
+                    Some(quoted) => quoted.strip_suffix('"')?.replace("\"\"", "\""),
+                    None => value.to_owned(),
+                };
+                Some((timestamp.parse(time)?, value))
+            }
+            Format::JsonLines => {
+                let json = Json::from_str(record).ok()?;
+                let object = json.as_object()?;
+                let time = match *object.get("time")? {
+                    Json::String(ref s) => timestamp.parse(s)?,
+                    ref number => timestamp.parse(&number.to_string())?,
+                };
+                let value = match *object.get("value")? {
+                    Json::String(ref s) => s.clone(),
+                    // Numbers are taken as written, as they come last
+                    ref number if number.is_number() => {
+                        let (_, raw) = record.rsplit_once("\"value\":")?;
+                        raw.trim_end().strip_suffix('}')?.trim().to_owned()
+                    }
+                    _ => return None,
+                };
+                Some((time, value))
+            }
+        }
+    }
+
+    /// Whether `record` is not complete yet, as it is a CSV record with a quoted newline
+    pub fn continues(&self, record: &str) -> bool {
+        *self == Format::Csv && record.matches('"').count() % 2 == 1
+    }
+}
+
+impl Timestamp {
+    pub fn from_name(s: &str) -> Option<Timestamp> {
+        match s {
+            "epoch" => Some(Timestamp::Epoch),
+            "epoch_ms" => Some(Timestamp::EpochMillis),
+            "rfc3339" => Some(Timestamp::Rfc3339),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Timestamp::Epoch => "epoch",
+            Timestamp::EpochMillis => "epoch_ms",
+            Timestamp::Rfc3339 => "rfc3339",
+        }
+    }
+
+    pub fn format(&self, time: Timespec) -> String {
+        match *self {
+            Timestamp::Epoch => time.sec.to_string(),
+            Timestamp::EpochMillis => (time.sec * 1000 + time.nsec as i64 / 1_000_000).to_string(),
+            Timestamp::Rfc3339 => {
+                let tm = time::at_utc(time);
+                format!("{}.{:03}Z", tm.strftime("%Y-%m-%dT%H:%M:%S").unwrap(), time.nsec / 1_000_000)
+            }
+        }
+    }
+
+    /// Reads a time written by `format`
+    pub fn parse(&self, s: &str) -> Option<Timespec> {
+        match *self {
+            Timestamp::Epoch => s.parse().ok().map(|sec| Timespec::new(sec, 0)),
+            Timestamp::EpochMillis => {
+                let millis = s.parse::<i64>().ok()?;
+                Some(Timespec::new(millis.div_euclid(1000), millis.rem_euclid(1000) as i32 * 1_000_000))
+            }
+            Timestamp::Rfc3339 => {
+                let (seconds, millis) = s.strip_suffix('Z')?.split_once('.')?;
+                let tm = time::strptime(seconds, "%Y-%m-%dT%H:%M:%S").ok()?;
+                if millis.len() != 3 {
+                    return None;
+                }
+                Some(Timespec::new(tm.to_timespec().sec, millis.parse::<i32>().ok()? * 1_000_000))
+            }
+        }
+    }
+}
+
+/// `s` as a CSV field, quoted as in RFC 4180 when needed
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+/// Whether `s` is a number as JSON defines it
+pub fn is_json_number(s: &str) -> bool {
+    fn digits(s: &[u8], mut i: usize) -> usize {
+        while i < s.len() && s[i].is_ascii_digit() {
+            i += 1;
+        }
+        i
+    }
+
+    let s = s.as_bytes();
+    let mut i = 0;
+    if s.first() == Some(&b'-') {
+        i += 1;
+    }
+    match s.get(i) {
+        Some(&b'0') => i += 1,
+        Some(c) if c.is_ascii_digit() => i = digits(s, i),
+        _ => return false,
+    }
+    if s.get(i) == Some(&b'.') {
+        let end = digits(s, i + 1);
+        if end == i + 1 {
+            return false;
+        }
+        i = end;
+    }
+    if let Some(&b'e') | Some(&b'E') = s.get(i) {
+        i += 1;
+        if let Some(&b'+') | Some(&b'-') = s.get(i) {
+            i += 1;
+        }
+        let end = digits(s, i);
+        if end == i {
+            return false;
+        }
+        i = end;
+    }
+    i == s.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use time::Timespec;
+
+    use output::Sample;
+    use output::format::{Format, Timestamp};
+
+    fn sample(value: &str) -> Sample {
+        let mut sample = Sample::new("os.battery", 1454328000, value);
+        sample.time.nsec = 123_456_789;
+        sample
+    }
+
+    #[test]
+    fn records() {
+        let text = Format::Text;
+        assert_eq!(text.record(Timestamp::Epoch, &sample("42\n")), "1454328000 42\n");
+        assert_eq!(text.record(Timestamp::EpochMillis, &sample("a\\b\nc\r\n")),
+                   "1454328000123 a\\\\b\\nc\n");
+        assert_eq!(text.record(Timestamp::Rfc3339, &sample("42")),
+                   "2016-02-01T12:00:00.123Z 42\n");
+
+        let csv = Format::Csv;
+        assert_eq!(csv.record(Timestamp::Epoch, &sample("42\n")), "1454328000,42\n");
+        assert_eq!(csv.record(Timestamp::Epoch, &sample("Battery 0: \"Full\", 100%\n")),
+                   "1454328000,\"Battery 0: \"\"Full\"\", 100%\"\n");
+
+        let json = Format::JsonLines;
+        assert_eq!(json.record(Timestamp::Epoch, &sample("-4.50e3\n")),
+                   "{\"time\":1454328000,\"key\":\"os.battery\",\"value\":-4.50e3}\n");
+        assert_eq!(json.record(Timestamp::Rfc3339, &sample("01\n")),
+                   "{\"time\":\"2016-02-01T12:00:00.123Z\",\"key\":\"os.battery\",\"value\":\"01\"}\n");
+    }
+
+    #[test]
+    fn headers() {
+        for &format in &[Format::Text, Format::Csv, Format::JsonLines] {
+            for &timestamp in &[Timestamp::Epoch, Timestamp::EpochMillis, Timestamp::Rfc3339] {
+                let header = format.header(timestamp);
+                assert_eq!(Format::parse_header(header.lines().next().unwrap()),
+                           Some((format, 1, timestamp)));
+            }
+        }
+        assert_eq!(Format::parse_header("1454328000 42"), None);
+        assert_eq!(Format::parse_header("{\"time\":1,\"value\":2}"), None);
+    }
+
+    #[test]
+    fn parse_records() {
+        let millis = |s: &Sample| Sample {
+            time: Timespec::new(s.time.sec, s.time.nsec / 1_000_000 * 1_000_000),
+            ..s.clone()
+        };
+        let samples = [sample("42\n"), sample("a\\b\nc\r\n"), sample("Battery 0: \"Full\", 100%"),
+                       sample("-4.5e3")];
+        for &format in &[Format::Text, Format::Csv, Format::JsonLines] {
+            for &timestamp in &[Timestamp::Epoch, Timestamp::EpochMillis, Timestamp::Rfc3339] {
+                for s in &samples {
+                    let record = format.record(timestamp, s);
+                    let record = record.strip_suffix('\n').unwrap();
+                    let (time, value) = format.parse_record(timestamp, record).unwrap();
+                    let expected = match timestamp {
+                        Timestamp::Epoch => Timespec::new(s.time.sec, 0),
+                        _ => millis(s).time,
+                    };
+                    assert_eq!(time, expected, "{:?} {:?} {}", format, timestamp, record);
+                    assert_eq!(value, s.value.trim_end_matches(['\n', '\r']));
+                }
+            }
+        }
+        assert!(Format::Csv.continues("1454328000,\"a"));
+        assert!(!Format::Csv.continues("1454328000,\"a\nb\""));
+        assert_eq!(Format::Text.parse_record(Timestamp::Epoch, "now 42"), None);
+    }
+
+    #[test]
+    fn keyed_records() {
+        let mut odd = sample("Battery 0: \"Full\", 100%");
+        odd.key = String::from("battery \"0\", status");
+        assert_eq!(Format::Csv.keyed_header(Timestamp::Epoch), "# antikoerper csv v1 epoch\ntime,key,value\n");
+        assert_eq!(Format::Csv.keyed_record(Timestamp::Epoch, &sample("42")), "1454328000,os.battery,42\n");
+        for &format in &[Format::Csv, Format::JsonLines] {
+            for s in &[sample("42\n"), odd.clone()] {
+                let record = format.keyed_record(Timestamp::Epoch, s);
+                let parsed = format.parse_keyed_record(Timestamp::Epoch, record.trim_end_matches('\n'));
+                assert_eq!(parsed, Some((s.key.clone(), Timespec::new(1454328000, 0),
+                                         String::from(s.value.trim_end()))), "{}", record);
+            }
+        }
+        assert_eq!(Format::Csv.parse_keyed_record(Timestamp::Epoch, "1454328000,42"), None);
+        assert_eq!(Format::Csv.parse_keyed_record(Timestamp::Epoch, "1454328000,\"os\"x,42"), None);
+    }
+}
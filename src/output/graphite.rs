@@ -0,0 +1,168 @@
+//! Sending samples to Graphite using its plaintext protocol
+//!
+//! Every sample becomes a line `<prefix>.<key> <value> <time>`. Values that are not numbers
+//! are not sent, Graphite only stores numbers.
+
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use output::Sample;
+use output::remote::{Failure, Sink};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The name of `key` with `prefix` prepended, whitespace replaced by underscores
+pub fn metric(prefix: &str, key: &str) -> String {
+    let name = if prefix.is_empty() {
+        key.to_owned()
+    } else {
+        format!("{}.{}", prefix.trim_end_matches('.'), key)
+    };
+    name.replace(char::is_whitespace, "_")
+}
+
+/// The sample as a line of the plaintext protocol, `None` if its value is not a number
+pub fn line(prefix: &str, sample: &Sample) -> Option<String> {
+    let value = sample.value.trim().parse::<f64>().ok().filter(|v| v.is_finite())?;
+    Some(format!("{} {} {}\n", metric(prefix, &sample.key), value, sample.time.sec))
+}
+
+/// Keeps a connection to Graphite, connecting again when it was lost
+#[derive(Debug)]
+pub struct GraphiteSink {
+    address: String,
+    prefix: String,
+    stream: Option<TcpStream>,
+}
+
+impl GraphiteSink {
+    pub fn new(address: String, prefix: String) -> GraphiteSink {
+        GraphiteSink {
+            address,
+            prefix,
+            stream: None,
+        }
+    }
+
+    /// Whether the server closed the connection. Writing to such a connection would
+    /// succeed once, losing what was written.
+    fn closed(stream: &TcpStream) -> bool {
+        if stream.set_nonblocking(true).is_err() {
+            return true;
+        }
+        let closed = match stream.peek(&mut [0; 1]) {
+            Ok(0) => true,
+            Ok(_) => false,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => false,
+            Err(_) => true,
+        };
+        closed || stream.set_nonblocking(false).is_err()
+    }
+
+    fn connect(&self) -> io::Result<TcpStream> {
+        let mut last = io::Error::new(io::ErrorKind::NotFound, format!("could not resolve {}", self.address));
+        for addr in self.address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, TIMEOUT) {
+                Ok(stream) => {
+                    stream.set_write_timeout(Some(TIMEOUT))?;
+                    return Ok(stream);
+                }
+                Err(e) => last = e,
+            }
+        }
+        Err(last)
+    }
+}
+
+impl Sink for GraphiteSink {
+    fn send(&mut self, samples: &[Sample]) -> Result<(), Failure> {
+        let lines = samples.iter().filter_map(|s| line(&self.prefix, s)).collect::<String>();
+        if lines.is_empty() {
+            return Ok(());
+        }
+        if self.stream.as_ref().is_some_and(GraphiteSink::closed) {
+            debug!("Graphite at {} closed the connection", self.address);
+            self.stream = None;
+        }
+        if self.stream.is_none() {
+            self.stream = Some(self.connect()?);
+        }
+        let result = self.stream.as_mut().unwrap().write_all(lines.as_bytes());
+        if let Err(e) = result {
+            self.stream = None;
+            return Err(Failure::Retry(e));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for GraphiteSink {
+    fn drop(&mut self) {
+        if let Some(mut stream) = self.stream.take() {
+            let _ = stream.flush();
+            let _ = stream.shutdown(::std::net::Shutdown::Write);
+            // Wait for the server to close its side, so nothing written is lost
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(1)));
+            let _ = stream.read(&mut [0; 64]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    use output::{Output, Sample};
+    use output::graphite::{line, GraphiteSink};
+    use output::remote::{Delivery, RemoteOutput};
+
+    fn sample(key: &str, value: &str) -> Sample {
+        Sample::new(key, 1454328000, value)
+    }
+
+    #[test]
+    fn lines() {
+        assert_eq!(line("laptop", &sample("os.battery", "99\n")),
+                   Some(String::from("laptop.os.battery 99 1454328000\n")));
+        assert_eq!(line("", &sample("os.load avg", "0.5")),
+                   Some(String::from("os.load_avg 0.5 1454328000\n")));
+        assert_eq!(line("laptop.", &sample("os.state", "Full")), None);
+    }
+
+    #[test]
+    fn reconnects() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let mut received = Vec::new();
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut buf = [0; 64];
+                let len = stream.read(&mut buf).unwrap();
+                received.push(String::from_utf8(buf[..len].to_vec()).unwrap());
+                // Closing the connection after every read, the sink has to notice
+            }
+            received
+        });
+
+        let delivery = Delivery {
+            batch_size: 1,
+            batch_interval: Duration::from_secs(3600),
+            max_queue: 10,
+            ..Delivery::default()
+        };
+        let sink = GraphiteSink::new(address, String::from("laptop"));
+        let mut output = RemoteOutput::start("graphite", Box::new(sink), delivery).unwrap();
+        output.write(&sample("os.battery", "99")).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        output.write(&sample("os.battery", "98")).unwrap();
+        drop(output);
+
+        assert_eq!(server.join().unwrap(), vec!["laptop.os.battery 99 1454328000\n",
+                                                "laptop.os.battery 98 1454328000\n"]);
+    }
+}
@@ -0,0 +1,222 @@
+//! Just enough HTTP/1.1 to post samples to a server, and to answer simple requests
+//!
+//! Only plain `http://` URLs are supported, every request uses a new connection.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// How long a server waits for each read and write of a request
+const SERVER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A parsed `http://host[:port][/path][?query]` URL
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Url {
+    pub host: String,
+    pub port: u16,
+    /// The path including the query, always starting with `/`
+    pub path: String,
+}
+
+impl Url {
+    pub fn parse(s: &str) -> Option<Url> {
+        let rest = s.strip_prefix("http://")?;
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(i) if rest[i..].starts_with('?') => (&rest[..i], format!("/{}", &rest[i..])),
+            Some(i) => (&rest[..i], rest[i..].to_owned()),
+            None => (rest, String::from("/")),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            // An IPv6 address without a port, like [::1]
+            Some((_, port)) if port.ends_with(']') => (authority, 80),
+            Some((host, port)) => (host, port.parse().ok()?),
+            None => (authority, 80),
+        };
+        if host.is_empty() || host.contains('@') || path.contains(char::is_whitespace) {
+            return None;
+        }
+        Some(Url {
+            host: host.to_owned(),
+            port,
+            path,
+        })
+    }
+}
+
+/// A response to a request, the body is not read
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub reason: String,
+}
+
+impl Response {
+    pub fn is_success(&self) -> bool {
+        self.status >= 200 && self.status < 300
+    }
+}
+
+/// Posts `body` to `url`, waiting at most `timeout` for connecting and each read and write
+pub fn post(url: &Url, headers: &[(&str, &str)], body: &[u8], timeout: Duration) -> io::Result<Response> {
+    let host = url.host.trim_start_matches('[').trim_end_matches(']');
+    let addr = (host, url.port).to_socket_addrs()?.next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("could not resolve {}", url.host)))?;
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let mut request = format!("POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Length: {}\r\nConnection: close\r\n",
+                              url.path, url.host, url.port, body.len());
+    for &(name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()?;
+
+    let mut reader = BufReader::new(stream.take(64 * 1024));
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.trim_end().splitn(3, ' ');
+    let status = match (parts.next(), parts.next().and_then(|s| s.parse().ok())) {
+        (Some(version), Some(status)) if version.starts_with("HTTP/") => status,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                       format!("invalid response: {}", line.trim_end()))),
+    };
+    Ok(Response {
+        status,
+        reason: parts.next().unwrap_or("").to_owned(),
+    })
+}
+
+/// A request to a server, the headers and the body are not kept
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// The query string without the `?`, empty if there is none
+    pub query: String,
+}
+
+impl Request {
+    /// Reads the request line and the headers of a request from `stream`
+    pub fn read(stream: &TcpStream) -> io::Result<Request> {
+        stream.set_read_timeout(Some(SERVER_TIMEOUT))?;
+        stream.set_write_timeout(Some(SERVER_TIMEOUT))?;
+        let mut reader = BufReader::new(stream.take(64 * 1024));
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+                break;
+            }
+        }
+
+        let mut parts = line.split_whitespace();
+        let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        Ok(Request {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            query: query.to_owned(),
+        })
+    }
+
+    /// Answers with `status`, like `200 OK`, and `body`, which is left out for HEAD requests
+    pub fn respond(&self, mut stream: &TcpStream, status: &str, headers: &[(&str, &str)], body: &[u8])
+                   -> io::Result<()>
+    {
+        let mut response = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+                                   status, body.len());
+        for &(name, value) in headers {
+            response.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        response.push_str("\r\n");
+        stream.write_all(response.as_bytes())?;
+        if self.method != "HEAD" {
+            stream.write_all(body)?;
+        }
+        stream.flush()
+    }
+}
+
+/// Accepts connections on a thread of its own, until it is dropped
+#[derive(Debug)]
+pub struct Server {
+    address: SocketAddr,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Server {
+    /// Listens on `listen` and passes every connection to `handle`, on a thread called `name`
+    pub fn start<F>(listen: &str, name: &str, mut handle: F) -> io::Result<Server>
+        where F: FnMut(TcpStream) + Send + 'static
+    {
+        let listener = TcpListener::bind(listen)?;
+        let address = listener.local_addr()?;
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let stop = stopped.clone();
+        thread::Builder::new().name(name.to_owned()).spawn(move || {
+            for stream in listener.incoming() {
+                if stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                match stream {
+                    Ok(s) => handle(s),
+                    Err(e) => debug!("Could not accept a connection on {}: {}", address, e),
+                }
+            }
+        })?;
+
+        Ok(Server {
+            address,
+            stopped,
+        })
+    }
+
+    /// The address actually listened on, which has the port if `listen` asked for any
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wakes up the listening thread, so it sees it is time to stop
+        let _ = TcpStream::connect(self.address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use output::http::Url;
+
+    #[test]
+    fn urls() {
+        assert_eq!(Url::parse("http://localhost:8086/write?db=antikoerper"), Some(Url {
+            host: String::from("localhost"),
+            port: 8086,
+            path: String::from("/write?db=antikoerper"),
+        }));
+        assert_eq!(Url::parse("http://[::1]?a=b"), Some(Url {
+            host: String::from("[::1]"),
+            port: 80,
+            path: String::from("/?a=b"),
+        }));
+        assert_eq!(Url::parse("http://example.com"), Some(Url {
+            host: String::from("example.com"),
+            port: 80,
+            path: String::from("/"),
+        }));
+        assert_eq!(Url::parse("https://example.com/"), None);
+        assert_eq!(Url::parse("http://example.com:http/"), None);
+        assert_eq!(Url::parse("http:///write"), None);
+    }
+}
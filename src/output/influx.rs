@@ -0,0 +1,229 @@
+//! Sending samples to InfluxDB using its line protocol
+//!
+//! The last part of a key is the field, the rest is the measurement: `os.battery` is written
+//! as the field `battery` of the measurement `os`. Keys without dots are written as the field
+//! `value`. Numbers are written as floats, all other values as strings.
+
+use std::io;
+use std::net::UdpSocket;
+use std::time::Duration;
+
+use output::Sample;
+use output::http::{self, Url};
+use output::remote::{self, Failure, Sink};
+
+/// Where the lines are sent to
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Endpoint {
+    /// Posted to an URL like `http://localhost:8086/write?db=antikoerper`
+    Http(Url),
+    /// Sent as datagrams to an address like `localhost:8089`
+    Udp(String),
+}
+
+impl Endpoint {
+    /// Parses `http://...` or `udp://host:port`
+    pub fn parse(s: &str) -> Option<Endpoint> {
+        if let Some(addr) = s.strip_prefix("udp://") {
+            return remote::parse_address(addr).map(Endpoint::Udp);
+        }
+        Url::parse(s).map(Endpoint::Http)
+    }
+}
+
+/// Escapes commas, spaces and, if `equals` is set, equal signs
+fn escape(s: &str, equals: bool) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            ',' | ' ' => escaped.push('\\'),
+            '=' if equals => escaped.push('\\'),
+            // Line breaks cannot be escaped
+            '\n' | '\r' => {
+                escaped.push(' ');
+                continue;
+            }
+            _ => (),
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// The sample as a line of the line protocol, including the newline
+pub fn line(sample: &Sample, tags: &[(String, String)]) -> String {
+    let (measurement, field) = match sample.key.rsplit_once('.') {
+        Some((measurement, field)) => (measurement, field),
+        None => (&sample.key[..], "value"),
+    };
+    let mut line = escape(measurement, false);
+    for (key, value) in tags {
+        line.push_str(&format!(",{}={}", escape(key, true), escape(value, true)));
+    }
+
+    let value = sample.value.trim_end_matches(['\n', '\r']);
+    let value = match value.trim().parse::<f64>() {
+        Ok(v) if v.is_finite() => format!("{:?}", v),
+        _ => {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+            format!("\"{}\"", value.replace('\n', "\\n"))
+        }
+    };
+    let time = sample.time.sec as i128 * 1_000_000_000 + sample.time.nsec as i128;
+    line.push_str(&format!(" {}={} {}\n", escape(field, true), value, time));
+    line
+}
+
+#[derive(Debug)]
+pub struct InfluxSink {
+    endpoint: Endpoint,
+    /// Sent as `Authorization: Token <token>`, as InfluxDB 2 expects it
+    token: Option<String>,
+    tags: Vec<(String, String)>,
+    socket: Option<UdpSocket>,
+}
+
+impl InfluxSink {
+    pub fn new(endpoint: Endpoint, token: Option<String>, tags: Vec<(String, String)>) -> InfluxSink {
+        InfluxSink {
+            endpoint,
+            token,
+            tags,
+            socket: None,
+        }
+    }
+}
+
+impl Sink for InfluxSink {
+    fn send(&mut self, samples: &[Sample]) -> Result<(), Failure> {
+        let lines = samples.iter().map(|s| line(s, &self.tags)).collect::<Vec<_>>();
+        match self.endpoint {
+            Endpoint::Http(ref url) => {
+                let authorization = self.token.as_ref().map(|t| format!("Token {}", t));
+                let mut headers = vec![("Content-Type", "text/plain; charset=utf-8")];
+                if let Some(ref a) = authorization {
+                    headers.push(("Authorization", a));
+                }
+                let response = http::post(url, &headers, lines.concat().as_bytes(), Duration::from_secs(10))?;
+                let error = || io::Error::other(format!("{} {}", response.status, response.reason));
+                match response.status {
+                    _ if response.is_success() => Ok(()),
+                    // Too many requests
+                    429 => Err(Failure::Retry(error())),
+                    400..=499 => Err(Failure::Rejected(error())),
+                    _ => Err(Failure::Retry(error())),
+                }
+            }
+            Endpoint::Udp(ref addr) => {
+                if self.socket.is_none() {
+                    self.socket = Some(remote::udp_socket(addr)?);
+                }
+                remote::send_datagrams(self.socket.as_ref().unwrap(), lines)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, UdpSocket};
+    use std::thread;
+    use std::time::Duration;
+
+    use output::{Output, Sample};
+    use output::http::Url;
+    use output::influx::{line, Endpoint, InfluxSink};
+    use output::remote::{Delivery, RemoteOutput};
+
+    fn sample(key: &str, value: &str) -> Sample {
+        let mut sample = Sample::new(key, 1454328000, value);
+        sample.time.nsec = 5;
+        sample
+    }
+
+    #[test]
+    fn lines() {
+        let tags = vec![(String::from("host"), String::from("my laptop"))];
+        assert_eq!(line(&sample("os.battery", "99\n"), &tags),
+                   "os,host=my\\ laptop battery=99.0 1454328000000000005\n");
+        assert_eq!(line(&sample("uptime", "1e3"), &[]), "uptime value=1000.0 1454328000000000005\n");
+        assert_eq!(line(&sample("os.bat,tery.state", "\"Full\"\n"), &[]),
+                   "os.bat\\,tery state=\"\\\"Full\\\"\" 1454328000000000005\n");
+
+        assert_eq!(Endpoint::parse("udp://localhost:8089"), Some(Endpoint::Udp(String::from("localhost:8089"))));
+        assert_eq!(Endpoint::parse("udp://localhost"), None);
+        assert_eq!(Endpoint::parse("tcp://localhost:8089"), None);
+    }
+
+    #[test]
+    fn http() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/write?db=test", listener.local_addr().unwrap())).unwrap();
+        let server = thread::spawn(move || {
+            let mut requests = Vec::new();
+            // The first request fails, so the batch is sent again
+            for status in &["503 Service Unavailable", "204 No Content"] {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut head = Vec::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if let Some(l) = line.strip_prefix("Content-Length: ") {
+                        length = l.trim().parse().unwrap();
+                    }
+                    head.push(line.trim_end().to_owned());
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                write!(reader.get_mut(), "HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+                requests.push((head, String::from_utf8(body).unwrap()));
+            }
+            requests
+        });
+
+        let sink = InfluxSink::new(Endpoint::Http(url), Some(String::from("secret")), Vec::new());
+        let delivery = Delivery {
+            batch_size: 2,
+            batch_interval: Duration::from_secs(3600),
+            max_queue: 10,
+            ..Delivery::default()
+        };
+        let mut output = RemoteOutput::start("influx", Box::new(sink), delivery).unwrap();
+        output.write(&sample("os.battery", "99\n")).unwrap();
+        output.write(&sample("os.state", "Full\n")).unwrap();
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0], requests[1]);
+        let (ref head, ref body) = requests[1];
+        assert_eq!(head[0], "POST /write?db=test HTTP/1.1");
+        assert!(head.contains(&String::from("Authorization: Token secret")));
+        assert_eq!(body, "os battery=99.0 1454328000000000005\nos state=\"Full\" 1454328000000000005\n");
+    }
+
+    #[test]
+    fn udp() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let endpoint = Endpoint::Udp(socket.local_addr().unwrap().to_string());
+        let sink = InfluxSink::new(endpoint, None, Vec::new());
+        let delivery = Delivery {
+            batch_size: 100,
+            batch_interval: Duration::from_secs(3600),
+            max_queue: 100,
+            ..Delivery::default()
+        };
+        let mut output = RemoteOutput::start("influx", Box::new(sink), delivery).unwrap();
+        output.write(&sample("os.battery", "99\n")).unwrap();
+        drop(output);
+
+        let mut buf = [0; 1500];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &b"os battery=99.0 1454328000000000005\n"[..]);
+    }
+}
@@ -0,0 +1,760 @@
+//! The places recorded values are written to
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::Debug;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use time::Timespec;
+use toml;
+
+use conf::General;
+use item::Item;
+use units;
+
+pub mod file;
+pub mod format;
+pub mod graphite;
+pub mod http;
+pub mod influx;
+pub mod mqtt;
+pub mod prometheus;
+pub mod remote;
+pub mod rotate;
+pub mod rrd;
+pub mod snapshot;
+pub mod spool;
+pub mod sqlite;
+pub mod statsd;
+
+use self::file::{Fsync, Layout, Writing};
+use self::format::{Format, Timestamp};
+use self::influx::Endpoint;
+use self::mqtt::{MqttConfig, Will};
+use self::remote::{Delivery, DirectOutput, RemoteOutput, Sink};
+use self::rotate::{Compression, Period, Rotation};
+use self::rrd::{Archive, Consolidation};
+use self::snapshot::SnapshotFormat;
+
+/// A single recorded value
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub time: Timespec,
+    pub key: String,
+    pub value: String,
+}
+
+#[cfg(test)]
+impl Sample {
+    /// A sample of `key` recorded `time` seconds after the epoch
+    pub fn new(key: &str, time: i64, value: &str) -> Sample {
+        Sample {
+            time: Timespec::new(time, 0),
+            key: String::from(key),
+            value: String::from(value),
+        }
+    }
+}
+
+/// Something samples can be written to
+pub trait Output: Debug {
+    fn write(&mut self, sample: &Sample) -> io::Result<()>;
+
+    /// Called regularly, outputs buffering samples write them out here when it is time to
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Writes out everything still buffered, when the output is not used any more. Unlike
+    /// writing out when dropped, this reports errors.
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum OutputErrorKind {
+    MissingName,
+    MissingType,
+    UnknownType,
+    InvalidDirectoryType,
+    UnknownFormat,
+    UnknownTimestamp,
+    UnknownLayout,
+    UnknownRotate,
+    InvalidMaxSize,
+    UnknownCompress,
+    InvalidMaxAge,
+    InvalidMaxTotal,
+    RetentionWithoutRotation,
+    InvalidPathType,
+    InvalidBatchSize,
+    InvalidBatchInterval,
+    InvalidArchives,
+    UnknownConsolidation,
+    InvalidMaxQueue,
+    InvalidUrl,
+    InvalidToken,
+    InvalidTags,
+    InvalidAddress,
+    InvalidPrefix,
+    InvalidListen,
+    InvalidMetricsPath,
+    InvalidNamespace,
+    InvalidTopic,
+    InvalidQos,
+    InvalidRetain,
+    InvalidClientId,
+    InvalidCredentials,
+    InvalidKeepAlive,
+    InvalidWill,
+    InvalidSpool,
+    InvalidMaxSpool,
+    InvalidFlushInterval,
+    UnknownFsync,
+    InvalidMaxOpen,
+    UnknownSnapshotFormat,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct OutputError {
+    name: String,
+    kind: OutputErrorKind,
+}
+
+impl OutputError {
+    fn new(name: String, k: OutputErrorKind) -> OutputError {
+        OutputError {
+            name,
+            kind: k,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        match self.kind {
+            OutputErrorKind::MissingName            => "missing name field",
+            OutputErrorKind::MissingType            => "missing type field",
+            OutputErrorKind::UnknownType            => "unknown type, you may only use file, sqlite, rrd, influxdb, graphite, statsd, prometheus, mqtt or snapshot",
+            OutputErrorKind::InvalidDirectoryType   => "directory has to be a path",
+            OutputErrorKind::UnknownFormat          => "unknown format, you may only use text, csv or jsonl",
+            OutputErrorKind::UnknownTimestamp       => "unknown timestamp, you may only use epoch, epoch_ms or rfc3339",
+            OutputErrorKind::UnknownLayout          => "unknown layout, you may only use flat or nested",
+            OutputErrorKind::UnknownRotate          => "unknown rotate, you may only use daily or weekly",
+            OutputErrorKind::InvalidMaxSize         => "max_size has to be a positive number of bytes or a size like 10M",
+            OutputErrorKind::UnknownCompress        => "unknown compress, you may only use gzip or zstd",
+            OutputErrorKind::InvalidMaxAge          => "max_age has to be a positive number of seconds or a duration like 30d",
+            OutputErrorKind::InvalidMaxTotal        => "max_total has to be a positive number of bytes or a size like 1G",
+            OutputErrorKind::RetentionWithoutRotation => "compress, max_age and max_total need rotate or max_size",
+            OutputErrorKind::InvalidPathType        => "path has to be a path",
+            OutputErrorKind::InvalidBatchSize       => "batch_size has to be a positive number",
+            OutputErrorKind::InvalidBatchInterval   => "batch_interval has to be a positive number of seconds or a duration like 1m",
+            OutputErrorKind::InvalidArchives        => "archives has to be a non-empty array of strings like \"1m:1w\"",
+            OutputErrorKind::UnknownConsolidation   => "consolidation has to be a non-empty array of avg, min, max or last",
+            OutputErrorKind::InvalidMaxQueue        => "max_queue has to be a positive number",
+            OutputErrorKind::InvalidUrl             => "url has to be like http://host:port/path or udp://host:port",
+            OutputErrorKind::InvalidToken           => "token has to be a string",
+            OutputErrorKind::InvalidTags            => "tags has to be a table of strings",
+            OutputErrorKind::InvalidAddress         => "address has to be like host:port",
+            OutputErrorKind::InvalidPrefix          => "prefix has to be a string",
+            OutputErrorKind::InvalidListen          => "listen has to be like host:port",
+            OutputErrorKind::InvalidMetricsPath     => "path has to be a string starting with /",
+            OutputErrorKind::InvalidNamespace       => "namespace has to be a string of letters, digits and underscores",
+            OutputErrorKind::InvalidTopic           => "topic has to be a non-empty string without + or #",
+            OutputErrorKind::InvalidQos             => "qos has to be 0, 1 or 2",
+            OutputErrorKind::InvalidRetain          => "retain has to be true or false",
+            OutputErrorKind::InvalidClientId        => "client_id has to be a non-empty string",
+            OutputErrorKind::InvalidCredentials     => "username and password have to be strings, password needs username",
+            OutputErrorKind::InvalidKeepAlive       => "keep_alive has to be a number of seconds below 65536 or a duration like 1m",
+            OutputErrorKind::InvalidWill            => "will has to be a table with topic, message and optionally qos and retain",
+            OutputErrorKind::InvalidSpool           => "spool has to be a path or a boolean",
+            OutputErrorKind::InvalidMaxSpool        => "max_spool has to be a positive number of bytes or a size like 100M",
+            OutputErrorKind::InvalidFlushInterval   => "flush_interval has to be a number of seconds or a duration like 5s",
+            OutputErrorKind::UnknownFsync           => "unknown fsync, you may only use never, interval or always",
+            OutputErrorKind::InvalidMaxOpen         => "max_open has to be a positive number",
+            OutputErrorKind::UnknownSnapshotFormat  => "unknown format, you may only use json or kv",
+        }
+    }
+}
+
+impl Error for OutputError {
+    fn description(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl ::std::fmt::Display for OutputError {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> Result<(), ::std::fmt::Error> {
+        write!(f, "output {}: {}", self.name, self.as_str())
+    }
+}
+
+/// The different kinds of outputs
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum OutputKind {
+    /// Appends the values of each key to a file named like the key
+    File {
+        /// The directory containing the files
+        directory: PathBuf,
+        /// How keys are mapped to files
+        layout: Layout,
+        /// How the samples are written
+        format: Format,
+        /// How the time of samples is written
+        timestamp: Timestamp,
+        /// When files are rotated and how long rotated segments are kept
+        rotation: Rotation,
+        /// How files are kept open and written
+        writing: Writing,
+    },
+    /// Stores all samples in a SQLite database
+    Sqlite {
+        /// The database file
+        path: PathBuf,
+        /// How many samples are inserted in a transaction at most
+        batch_size: usize,
+        /// How long samples are kept before they are inserted at most
+        batch_interval: Duration,
+        /// How many samples are kept at most while they cannot be inserted
+        max_queue: usize,
+    },
+    /// Keeps consolidated numeric values in a fixed size file per key
+    Rrd {
+        /// The directory containing the files
+        directory: PathBuf,
+        /// The resolutions and how long values are kept at each of them
+        archives: Vec<Archive>,
+        /// How the values in a step are consolidated
+        consolidations: Vec<Consolidation>,
+    },
+    /// Sends samples to InfluxDB using its line protocol
+    Influx {
+        endpoint: Endpoint,
+        /// The API token for InfluxDB 2
+        token: Option<String>,
+        /// Added to every sample
+        tags: Vec<(String, String)>,
+        delivery: Delivery,
+    },
+    /// Sends samples to Graphite using its plaintext protocol over TCP
+    Graphite {
+        /// Like `host:port`
+        address: String,
+        /// Prepended to every key
+        prefix: String,
+        delivery: Delivery,
+    },
+    /// Serves the latest values for Prometheus to scrape
+    Prometheus {
+        /// The address to listen on, like `127.0.0.1:9469`
+        listen: String,
+        /// The path the metrics are served at
+        path: String,
+        /// Prepended to metric names
+        namespace: String,
+    },
+    /// Sends samples to StatsD as gauges over UDP
+    Statsd {
+        /// Like `host:port`
+        address: String,
+        /// Prepended to every key
+        prefix: String,
+        delivery: Delivery,
+    },
+    /// Publishes samples to an MQTT broker
+    Mqtt {
+        config: MqttConfig,
+        delivery: Delivery,
+    },
+    /// Keeps the latest value of every key in a single file
+    Snapshot {
+        /// The file, replaced whenever values changed
+        path: PathBuf,
+        format: SnapshotFormat,
+        /// How the time of values is written
+        timestamp: Timestamp,
+    },
+}
+
+/// Whether `topic` can be published to, wildcards are only allowed when subscribing
+fn valid_topic(topic: &str) -> bool {
+    !topic.is_empty() && !topic.contains(['+', '#'])
+}
+
+/// The last will of an MQTT output, `None` if the table is not valid
+fn will_from_toml(table: &toml::Table) -> Option<Will> {
+    Some(Will {
+        topic: table.get("topic").and_then(|t| t.as_str()).filter(|t| valid_topic(t))?.to_owned(),
+        message: table.get("message").and_then(|m| m.as_str())?.to_owned(),
+        qos: match table.get("qos") {
+            Some(&toml::Value::Integer(q)) if (0..=2).contains(&q) => q as u8,
+            Some(_) => return None,
+            None => 0,
+        },
+        retain: match table.get("retain") {
+            Some(&toml::Value::Boolean(r)) => r,
+            Some(_) => return None,
+            None => false,
+        },
+    })
+}
+
+/// A configured output, referred to by items using its name
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct OutputConfig {
+    pub name: String,
+    pub kind: OutputKind,
+}
+
+impl OutputConfig {
+    /// The output used if none are configured, files in `general.output`
+    pub fn default(general: &General) -> OutputConfig {
+        OutputConfig {
+            name: String::from("default"),
+            kind: OutputKind::File {
+                directory: general.output.clone(),
+                layout: Layout::Flat,
+                format: Format::Text,
+                timestamp: Timestamp::Epoch,
+                rotation: Rotation::default(),
+                writing: Writing::default(),
+            },
+        }
+    }
+
+    pub fn from_toml(table: &toml::Table, general: &General) -> Result<OutputConfig, OutputError> {
+        let name = match table.get("name") {
+            Some(toml::Value::String(s)) => s.clone(),
+            _ => return Err(OutputError::new(String::new(), OutputErrorKind::MissingName)),
+        };
+
+        let kind = match table.get("type") {
+            Some(toml::Value::String(s)) if s == "file" => {
+                // Relative directories are relative to general.output
+                let directory = match table.get("directory") {
+                    Some(toml::Value::String(s)) => general.output.join(s),
+                    Some(_) => return Err(OutputError::new(name, OutputErrorKind::InvalidDirectoryType)),
+                    None => general.output.clone(),
+                };
+                let layout = match table.get("layout") {
+                    Some(toml::Value::String(s)) => Layout::from_name(s),
+                    Some(_) => None,
+                    None => Some(Layout::Flat),
+                }.ok_or_else(|| OutputError::new(name.clone(), OutputErrorKind::UnknownLayout))?;
+                let format = match table.get("format") {
+                    Some(toml::Value::String(s)) => Format::from_name(s),
+                    Some(_) => None,
+                    None => Some(Format::Text),
+                }.ok_or_else(|| OutputError::new(name.clone(), OutputErrorKind::UnknownFormat))?;
+                let timestamp = match table.get("timestamp") {
+                    Some(toml::Value::String(s)) => Timestamp::from_name(s),
+                    Some(_) => None,
+                    None => Some(Timestamp::Epoch),
+                }.ok_or_else(|| OutputError::new(name.clone(), OutputErrorKind::UnknownTimestamp))?;
+                let rotation = Rotation {
+                    period: match table.get("rotate") {
+                        Some(toml::Value::String(s)) => Some(Period::from_name(s)
+                            .ok_or_else(|| OutputError::new(name.clone(), OutputErrorKind::UnknownRotate))?),
+                        Some(_) => return Err(OutputError::new(name, OutputErrorKind::UnknownRotate)),
+                        None => None,
+                    },
+                    max_size: match table.get("max_size") {
+                        Some(v) => Some(units::size_from_toml(v)
+                            .ok_or_else(|| OutputError::new(name.clone(), OutputErrorKind::InvalidMaxSize))?),
+                        None => None,
+                    },
+                    compression: match table.get("compress") {
+                        Some(toml::Value::String(s)) => Some(Compression::from_name(s)
+                            .ok_or_else(|| OutputError::new(name.clone(), OutputErrorKind::UnknownCompress))?),
+                        Some(_) => return Err(OutputError::new(name, OutputErrorKind::UnknownCompress)),
+                        None => None,
+                    },
+                    max_age: match table.get("max_age") {
+                        Some(v) => Some(units::duration_from_toml(v)
+                            .ok_or_else(|| OutputError::new(name.clone(), OutputErrorKind::InvalidMaxAge))?),
+                        None => None,
+                    },
+                    max_total: match table.get("max_total") {
+                        Some(v) => Some(units::size_from_toml(v)
+                            .ok_or_else(|| OutputError::new(name.clone(), OutputErrorKind::InvalidMaxTotal))?),
+                        None => None,
+                    },
+                };
+                let retention = rotation.compression.is_some() || rotation.max_age.is_some()
+                    || rotation.max_total.is_some();
+                if retention && !rotation.is_enabled() {
+                    return Err(OutputError::new(name, OutputErrorKind::RetentionWithoutRotation));
+                }
+                let default = Writing::default();
+                let writing = Writing {
+                    flush_interval: match table.get("flush_interval") {
+                        Some(&toml::Value::Integer(0)) => Duration::from_secs(0),
+                        Some(v) => Duration::from_secs(units::duration_from_toml(v)
+                            .ok_or_else(|| OutputError::new(name.clone(), OutputErrorKind::InvalidFlushInterval))? as u64),
+                        None => default.flush_interval,
+                    },
+                    fsync: match table.get("fsync") {
+                        Some(toml::Value::String(s)) => Fsync::from_name(s),
+                        Some(_) => None,
+                        None => Some(default.fsync),
+                    }.ok_or_else(|| OutputError::new(name.clone(), OutputErrorKind::UnknownFsync))?,
+                    max_open: match table.get("max_open") {
+                        Some(&toml::Value::Integer(i)) if i > 0 => i as usize,
+                        Some(_) => return Err(OutputError::new(name, OutputErrorKind::InvalidMaxOpen)),
+                        None => default.max_open,
+                    },
+                };
+                OutputKind::File {
+                    directory,
+                    layout,
+                    format,
+                    timestamp,
+                    rotation,
+                    writing,
+                }
+            }
+            Some(toml::Value::String(s)) if s == "sqlite" => {
+                // Relative paths are relative to general.output
+                let path = match table.get("path") {
+                    Some(toml::Value::String(s)) => general.output.join(s),
+                    Some(_) => return Err(OutputError::new(name, OutputErrorKind::InvalidPathType)),
+                    None => general.output.join("antikoerper.db"),
+                };
+                let batch_size = match table.get("batch_size") {
+                    Some(&toml::Value::Integer(i)) if i > 0 => i as usize,
+                    Some(_) => return Err(OutputError::new(name, OutputErrorKind::InvalidBatchSize)),
+                    None => 100,
+                };
+                let batch_interval = match table.get("batch_interval") {
+                    Some(v) => units::duration_from_toml(v)
+                        .ok_or_else(|| OutputError::new(name.clone(), OutputErrorKind::InvalidBatchInterval))?,
+                    None => 5,
+                };
+                let max_queue = match table.get("max_queue") {
+                    Some(&toml::Value::Integer(i)) if i > 0 => i as usize,
+                    Some(_) => return Err(OutputError::new(name, OutputErrorKind::InvalidMaxQueue)),
+                    None => 100_000,
+                };
+                OutputKind::Sqlite {
+                    path,
+                    batch_size,
+                    batch_interval: Duration::from_secs(batch_interval as u64),
+                    max_queue,
+                }
+            }
+            Some(toml::Value::String(s)) if s == "rrd" => {
+                // Relative directories are relative to general.output
+                let directory = match table.get("directory") {
+                    Some(toml::Value::String(s)) => general.output.join(s),
+                    Some(_) => return Err(OutputError::new(name, OutputErrorKind::InvalidDirectoryType)),
+                    None => general.output.clone(),
+                };
+                let archives = match table.get("archives") {
+                    Some(toml::Value::Array(a)) if !a.is_empty() => a.iter()
+                        .map(|v| v.as_str().and_then(Archive::from_name))
+                        .collect::<Option<Vec<_>>>(),
+                    Some(_) => None,
+                    None => ["10s:1d", "1m:1w", "1h:1y"].iter().map(|a| Archive::from_name(a)).collect(),
+                }.ok_or_else(|| OutputError::new(name.clone(), OutputErrorKind::InvalidArchives))?;
+                let consolidations = match table.get("consolidation") {
+                    Some(toml::Value::Array(a)) if !a.is_empty() => a.iter()
+                        .map(|v| v.as_str().and_then(Consolidation::from_name))
+                        .collect::<Option<Vec<_>>>(),
+                    Some(_) => None,
+                    None => Some(vec![Consolidation::Average]),
+                }.ok_or_else(|| OutputError::new(name.clone(), OutputErrorKind::UnknownConsolidation))?;
+                OutputKind::Rrd {
+                    directory,
+                    archives,
+                    consolidations,
+                }
+            }
+            Some(toml::Value::String(s)) if s == "influxdb" => {
+                let endpoint = match table.get("url") {
+                    Some(toml::Value::String(s)) => Endpoint::parse(s),
+                    _ => None,
+                }.ok_or_else(|| OutputError::new(name.clone(), OutputErrorKind::InvalidUrl))?;
+                let token = match table.get("token") {
+                    Some(toml::Value::String(s)) => Some(s.clone()),
+                    Some(_) => return Err(OutputError::new(name, OutputErrorKind::InvalidToken)),
+                    None => None,
+                };
+                let tags = match table.get("tags") {
+                    Some(toml::Value::Table(t)) => t.iter()
+                        .map(|(k, v)| v.as_str().map(|v| (k.clone(), v.to_owned())))
+                        .collect::<Option<Vec<_>>>(),
+                    Some(_) => None,
+                    None => Some(Vec::new()),
+                }.ok_or_else(|| OutputError::new(name.clone(), OutputErrorKind::InvalidTags))?;
+                OutputKind::Influx {
+                    endpoint,
+                    token,
+                    tags,
+                    delivery: Delivery::from_toml(table, &name, &general.output)?,
+                }
+            }
+            Some(toml::Value::String(s)) if s == "graphite" || s == "statsd" => {
+                let address = match table.get("address") {
+                    Some(toml::Value::String(a)) => remote::parse_address(a),
+                    _ => None,
+                }.ok_or_else(|| OutputError::new(name.clone(), OutputErrorKind::InvalidAddress))?;
+                let prefix = match table.get("prefix") {
+                    Some(toml::Value::String(p)) => p.clone(),
+                    Some(_) => return Err(OutputError::new(name, OutputErrorKind::InvalidPrefix)),
+                    None => String::new(),
+                };
+                let delivery = Delivery::from_toml(table, &name, &general.output)?;
+                if s == "graphite" {
+                    OutputKind::Graphite { address, prefix, delivery }
+                } else {
+                    OutputKind::Statsd { address, prefix, delivery }
+                }
+            }
+            Some(toml::Value::String(s)) if s == "prometheus" => {
+                let listen = match table.get("listen") {
+                    Some(toml::Value::String(a)) => remote::parse_address(a),
+                    Some(_) => None,
+                    None => Some(String::from("127.0.0.1:9469")),
+                }.ok_or_else(|| OutputError::new(name.clone(), OutputErrorKind::InvalidListen))?;
+                let path = match table.get("path") {
+                    Some(toml::Value::String(p)) if p.starts_with('/') => p.clone(),
+                    Some(_) => return Err(OutputError::new(name, OutputErrorKind::InvalidMetricsPath)),
+                    None => String::from("/metrics"),
+                };
+                let namespace = match table.get("namespace") {
+                    Some(toml::Value::String(n)) if n.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') => {
+                        n.clone()
+                    }
+                    Some(_) => return Err(OutputError::new(name, OutputErrorKind::InvalidNamespace)),
+                    None => String::from("antikoerper"),
+                };
+                OutputKind::Prometheus {
+                    listen,
+                    path,
+                    namespace,
+                }
+            }
+            Some(toml::Value::String(s)) if s == "mqtt" => {
+                let address = match table.get("address") {
+                    // The port is optional, as there is a well known one
+                    Some(toml::Value::String(a)) if !a.contains(':') => Some(format!("{}:1883", a)),
+                    Some(toml::Value::String(a)) => remote::parse_address(a),
+                    _ => None,
+                }.ok_or_else(|| OutputError::new(name.clone(), OutputErrorKind::InvalidAddress))?;
+                let topic = match table.get("topic") {
+                    Some(toml::Value::String(t)) if valid_topic(t) => t.clone(),
+                    Some(_) => return Err(OutputError::new(name, OutputErrorKind::InvalidTopic)),
+                    None => String::from("antikoerper/{host}/{key}"),
+                };
+                let qos = match table.get("qos") {
+                    Some(&toml::Value::Integer(q)) if (0..=2).contains(&q) => q as u8,
+                    Some(_) => return Err(OutputError::new(name, OutputErrorKind::InvalidQos)),
+                    None => 0,
+                };
+                let retain = match table.get("retain") {
+                    Some(&toml::Value::Boolean(r)) => r,
+                    Some(_) => return Err(OutputError::new(name, OutputErrorKind::InvalidRetain)),
+                    None => false,
+                };
+                let client_id = match table.get("client_id") {
+                    Some(toml::Value::String(c)) if !c.is_empty() => Some(c.clone()),
+                    Some(_) => return Err(OutputError::new(name, OutputErrorKind::InvalidClientId)),
+                    None => None,
+                };
+                let (username, password) = match (table.get("username"), table.get("password")) {
+                    (Some(toml::Value::String(u)), Some(toml::Value::String(p))) => (Some(u.clone()), Some(p.clone())),
+                    (Some(toml::Value::String(u)), None) => (Some(u.clone()), None),
+                    (None, None) => (None, None),
+                    _ => return Err(OutputError::new(name, OutputErrorKind::InvalidCredentials)),
+                };
+                let keep_alive = match table.get("keep_alive") {
+                    Some(v) => units::duration_from_toml(v).filter(|&k| k < 65536)
+                        .ok_or_else(|| OutputError::new(name.clone(), OutputErrorKind::InvalidKeepAlive))?,
+                    None => 60,
+                };
+                let will = match table.get("will") {
+                    Some(toml::Value::Table(w)) => Some(will_from_toml(w)
+                        .ok_or_else(|| OutputError::new(name.clone(), OutputErrorKind::InvalidWill))?),
+                    Some(_) => return Err(OutputError::new(name, OutputErrorKind::InvalidWill)),
+                    None => None,
+                };
+                OutputKind::Mqtt {
+                    config: MqttConfig {
+                        address,
+                        client_id,
+                        username,
+                        password,
+                        topic,
+                        qos,
+                        retain,
+                        will,
+                        keep_alive: keep_alive as u16,
+                    },
+                    delivery: Delivery::from_toml(table, &name, &general.output)?,
+                }
+            }
+            Some(toml::Value::String(s)) if s == "snapshot" => {
+                let format = match table.get("format") {
+                    Some(toml::Value::String(s)) => SnapshotFormat::from_name(s),
+                    Some(_) => None,
+                    None => Some(SnapshotFormat::Json),
+                }.ok_or_else(|| OutputError::new(name.clone(), OutputErrorKind::UnknownSnapshotFormat))?;
+                // Relative paths are relative to general.output, the defaults start with a dot
+                // like no key does, so they never take the place of the file of a key
+                let path = match table.get("path") {
+                    Some(toml::Value::String(s)) => general.output.join(s),
+                    Some(_) => return Err(OutputError::new(name, OutputErrorKind::InvalidPathType)),
+                    None if format == SnapshotFormat::Json => general.output.join(".snapshot.json"),
+                    None => general.output.join(".snapshot"),
+                };
+                let timestamp = match table.get("timestamp") {
+                    Some(toml::Value::String(s)) => Timestamp::from_name(s),
+                    Some(_) => None,
+                    None => Some(Timestamp::Epoch),
+                }.ok_or_else(|| OutputError::new(name.clone(), OutputErrorKind::UnknownTimestamp))?;
+                OutputKind::Snapshot {
+                    path,
+                    format,
+                    timestamp,
+                }
+            }
+            Some(_) => return Err(OutputError::new(name, OutputErrorKind::UnknownType)),
+            None => return Err(OutputError::new(name, OutputErrorKind::MissingType)),
+        };
+
+        Ok(OutputConfig {
+            name,
+            kind,
+        })
+    }
+
+    /// Opens the output, `items` are all configured items
+    pub fn open(&self, items: &[Item]) -> io::Result<Box<dyn Output>> {
+        Ok(match self.kind {
+            OutputKind::File { ref directory, layout, format, timestamp, rotation, writing } => {
+                Box::new(file::FileOutput::new(directory, layout, format, timestamp, rotation, writing)?)
+            }
+            OutputKind::Sqlite { ref path, batch_size, batch_interval, max_queue } => {
+                Box::new(sqlite::SqliteOutput::open(path, batch_size, batch_interval, max_queue)?)
+            }
+            OutputKind::Rrd { ref directory, ref archives, ref consolidations } => {
+                Box::new(rrd::RrdOutput::new(directory, archives, consolidations)?)
+            }
+            OutputKind::Prometheus { ref listen, ref path, ref namespace } => {
+                Box::new(prometheus::PrometheusOutput::start(listen, path, namespace, items)?)
+            }
+            OutputKind::Snapshot { ref path, format, timestamp } => {
+                Box::new(snapshot::SnapshotOutput::new(path, format, timestamp)?)
+            }
+            OutputKind::Influx { .. } | OutputKind::Graphite { .. } | OutputKind::Statsd { .. }
+                | OutputKind::Mqtt { .. } => {
+                let (sink, delivery) = self.sink().unwrap();
+                Box::new(RemoteOutput::start(&self.name, sink, delivery.clone())?)
+            }
+        })
+    }
+
+    /// Like `open`, but network outputs send each batch right away and wait for it to be sent,
+    /// instead of queueing samples. For writing many samples at once without dropping any.
+    pub fn open_direct(&self, items: &[Item]) -> io::Result<Box<dyn Output>> {
+        match self.sink() {
+            Some((sink, delivery)) => Ok(Box::new(DirectOutput::new(&self.name, sink, delivery))),
+            None => self.open(items),
+        }
+    }
+
+    /// How samples are sent, for outputs sending them over the network
+    fn sink(&self) -> Option<(Box<dyn Sink>, &Delivery)> {
+        Some(match self.kind {
+            OutputKind::Influx { ref endpoint, ref token, ref tags, ref delivery } => {
+                (Box::new(influx::InfluxSink::new(endpoint.clone(), token.clone(), tags.clone())), delivery)
+            }
+            OutputKind::Graphite { ref address, ref prefix, ref delivery } => {
+                (Box::new(graphite::GraphiteSink::new(address.clone(), prefix.clone())), delivery)
+            }
+            OutputKind::Statsd { ref address, ref prefix, ref delivery } => {
+                (Box::new(statsd::StatsdSink::new(address.clone(), prefix.clone())), delivery)
+            }
+            OutputKind::Mqtt { ref config, ref delivery } => {
+                (Box::new(mqtt::MqttSink::new(config.clone())), delivery)
+            }
+            _ => return None,
+        })
+    }
+}
+
+/// All opened outputs, and which of them the values of each item go to
+#[derive(Debug)]
+pub struct Outputs {
+    outputs: Vec<(String, Box<dyn Output>)>,
+    /// Indices into `outputs` per item key, items not in here go to all outputs
+    routes: BTreeMap<String, Vec<usize>>,
+}
+
+impl Outputs {
+    /// Opens all outputs, names of outputs referred to by items are expected to exist
+    pub fn open<'a, I>(configs: &[OutputConfig], items: I) -> Result<Outputs, String>
+        where I: IntoIterator<Item = &'a Item>
+    {
+        let items = items.into_iter().cloned().collect::<Vec<_>>();
+        let mut outputs = Vec::new();
+        for config in configs {
+            let output = config.open(&items)
+                .map_err(|e| format!("Could not open output {}: {}", config.name, e))?;
+            outputs.push((config.name.clone(), output));
+        }
+
+        let mut routes = BTreeMap::new();
+        for item in items {
+            if let Some(ref names) = item.outputs {
+                let indices = names.iter()
+                    .filter_map(|n| outputs.iter().position(|(name, _)| name == n))
+                    .collect();
+                routes.insert(item.key.clone(), indices);
+            }
+        }
+
+        Ok(Outputs {
+            outputs,
+            routes,
+        })
+    }
+
+    /// Writes a sample recorded by the item with the key `item` to the outputs of that item
+    pub fn write(&mut self, item: &str, sample: &Sample) {
+        debug!("{}={}", sample.key, sample.value);
+        let all;
+        let indices = match self.routes.get(item) {
+            Some(indices) => indices,
+            None => {
+                all = (0..self.outputs.len()).collect::<Vec<_>>();
+                &all
+            }
+        };
+        for &index in indices {
+            let (ref name, ref mut output) = self.outputs[index];
+            if let Err(e) = output.write(sample) {
+                error!("Could not write {} to output {}: {}", sample.key, name, e);
+            }
+        }
+    }
+
+    /// Lets all outputs write out what they buffered if it is time to
+    pub fn flush(&mut self) {
+        for (ref name, ref mut output) in &mut self.outputs {
+            if let Err(e) = output.flush() {
+                error!("Could not flush output {}: {}", name, e);
+            }
+        }
+    }
+
+    /// Makes all outputs write out everything they buffered, as we are stopping
+    pub fn finish(&mut self) {
+        for (ref name, ref mut output) in &mut self.outputs {
+            if let Err(e) = output.finish() {
+                error!("Could not finish output {}: {}", name, e);
+            }
+        }
+    }
+}
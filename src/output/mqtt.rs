@@ -0,0 +1,370 @@
+//! Publishing samples to an MQTT broker, speaking just enough of MQTT 3.1.1
+//!
+//! Every sample is published to a topic made from a template, `{host}` in it is replaced by
+//! the hostname and `{key}` by the key with its dots turned into slashes. The payload is the
+//! value without trailing newlines. Only plain TCP connections are supported.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use output::Sample;
+use output::remote::{Failure, Sink};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const PUBREC: u8 = 0x50;
+const PUBREL: u8 = 0x62;
+const PUBCOMP: u8 = 0x70;
+const PINGREQ: u8 = 0xc0;
+
+/// The name of this machine, `localhost` if it is not known
+pub fn hostname() -> String {
+    let mut name = String::new();
+    for path in &["/proc/sys/kernel/hostname", "/etc/hostname"] {
+        if File::open(path).and_then(|mut f| f.read_to_string(&mut name)).is_ok() && !name.trim().is_empty() {
+            return name.trim().to_owned();
+        }
+        name.clear();
+    }
+    String::from("localhost")
+}
+
+/// The topic `key` is published to, wildcards in the key are replaced by underscores
+pub fn topic(template: &str, host: &str, key: &str) -> String {
+    let key = key.replace(['+', '#'], "_").replace('.', "/");
+    template.replace("{host}", host).replace("{key}", &key)
+}
+
+/// A message published by the broker when the connection to us is lost
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Will {
+    pub topic: String,
+    pub message: String,
+    pub qos: u8,
+    pub retain: bool,
+}
+
+/// How to connect to the broker and publish
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MqttConfig {
+    /// Like `localhost:1883`
+    pub address: String,
+    /// `antikoerper-<hostname>` if not set
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// The topic template, with `{host}` and `{key}`
+    pub topic: String,
+    pub qos: u8,
+    pub retain: bool,
+    pub will: Option<Will>,
+    /// Seconds after which the broker considers us gone if it heard nothing from us
+    pub keep_alive: u16,
+}
+
+fn put_string(packet: &mut Vec<u8>, s: &[u8]) {
+    packet.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    packet.extend_from_slice(s);
+}
+
+/// A packet with the fixed header `first` and the remaining length encoded in front of `body`
+fn packet(first: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![first];
+    let mut len = body.len();
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// Reads a packet, returns its first byte and its body
+fn read_packet<R: Read>(stream: &mut R) -> io::Result<(u8, Vec<u8>)> {
+    let mut byte = [0; 1];
+    stream.read_exact(&mut byte)?;
+    let first = byte[0];
+    let mut len = 0usize;
+    for shift in 0..4 {
+        stream.read_exact(&mut byte)?;
+        len |= ((byte[0] & 0x7f) as usize) << (7 * shift);
+        if byte[0] & 0x80 == 0 {
+            let mut body = vec![0; len];
+            stream.read_exact(&mut body)?;
+            return Ok((first, body));
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "invalid remaining length"))
+}
+
+fn packet_id(body: &[u8]) -> io::Result<u16> {
+    match body {
+        [a, b, ..] => Ok(u16::from_be_bytes([*a, *b])),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "packet without id")),
+    }
+}
+
+#[derive(Debug)]
+pub struct MqttSink {
+    config: MqttConfig,
+    host: String,
+    stream: Option<TcpStream>,
+    next_id: u16,
+    /// When we last sent something to the broker
+    last_sent: Instant,
+}
+
+impl MqttSink {
+    pub fn new(config: MqttConfig) -> MqttSink {
+        MqttSink {
+            config,
+            host: hostname(),
+            stream: None,
+            next_id: 1,
+            last_sent: Instant::now(),
+        }
+    }
+
+    fn connect_packet(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        put_string(&mut body, b"MQTT");
+        body.push(4);
+        // Clean session
+        let mut flags = 0x02;
+        if let Some(ref will) = self.config.will {
+            flags |= 0x04 | (will.qos << 3);
+            if will.retain {
+                flags |= 0x20;
+            }
+        }
+        if self.config.username.is_some() {
+            flags |= 0x80;
+        }
+        if self.config.password.is_some() {
+            flags |= 0x40;
+        }
+        body.push(flags);
+        body.extend_from_slice(&self.config.keep_alive.to_be_bytes());
+        let client_id = match self.config.client_id {
+            Some(ref id) => id.clone(),
+            None => format!("antikoerper-{}", self.host),
+        };
+        put_string(&mut body, client_id.as_bytes());
+        if let Some(ref will) = self.config.will {
+            put_string(&mut body, will.topic.replace("{host}", &self.host).as_bytes());
+            put_string(&mut body, will.message.as_bytes());
+        }
+        if let Some(ref username) = self.config.username {
+            put_string(&mut body, username.as_bytes());
+        }
+        if let Some(ref password) = self.config.password {
+            put_string(&mut body, password.as_bytes());
+        }
+        packet(CONNECT, &body)
+    }
+
+    fn connect(&mut self) -> io::Result<TcpStream> {
+        let addr = self.config.address.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("could not resolve {}", self.config.address))
+        })?;
+        let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        stream.write_all(&self.connect_packet())?;
+        self.last_sent = Instant::now();
+
+        let (first, body) = read_packet(&mut stream)?;
+        if first != CONNACK || body.len() != 2 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "expected CONNACK"));
+        }
+        let reason = match body[1] {
+            0 => return Ok(stream),
+            1 => "unacceptable protocol version",
+            2 => "client identifier rejected",
+            3 => "server unavailable",
+            4 => "bad user name or password",
+            5 => "not authorized",
+            _ => "unknown reason",
+        };
+        Err(io::Error::new(io::ErrorKind::ConnectionRefused, format!("broker refused connection: {}", reason)))
+    }
+
+    fn publish_packet(&mut self, sample: &Sample) -> (Vec<u8>, Option<u16>) {
+        let mut first = PUBLISH | (self.config.qos << 1);
+        if self.config.retain {
+            first |= 0x01;
+        }
+        let mut body = Vec::new();
+        put_string(&mut body, topic(&self.config.topic, &self.host, &sample.key).as_bytes());
+        let id = if self.config.qos > 0 {
+            let id = self.next_id;
+            self.next_id = self.next_id.checked_add(1).unwrap_or(1);
+            body.extend_from_slice(&id.to_be_bytes());
+            Some(id)
+        } else {
+            None
+        };
+        body.extend_from_slice(sample.value.trim_end_matches(['\n', '\r']).as_bytes());
+        (packet(first, &body), id)
+    }
+
+    /// Publishes all samples, then waits for the broker to acknowledge them
+    fn publish(&mut self, samples: &[Sample]) -> io::Result<()> {
+        if self.stream.is_none() {
+            self.stream = Some(self.connect()?);
+        }
+        let mut pending = Vec::new();
+        let mut packets = Vec::new();
+        for sample in samples {
+            let (packet, id) = self.publish_packet(sample);
+            packets.extend(packet);
+            pending.extend(id);
+        }
+        let qos = self.config.qos;
+        let stream = self.stream.as_mut().unwrap();
+        stream.write_all(&packets)?;
+        self.last_sent = Instant::now();
+
+        while !pending.is_empty() {
+            let (first, body) = read_packet(stream)?;
+            match first & 0xf0 {
+                PUBACK if qos == 1 => pending.retain(|&id| Some(id) != packet_id(&body).ok()),
+                PUBREC if qos == 2 => {
+                    let id = packet_id(&body)?;
+                    stream.write_all(&packet(PUBREL, &id.to_be_bytes()))?;
+                }
+                PUBCOMP if qos == 2 => pending.retain(|&id| Some(id) != packet_id(&body).ok()),
+                // Like answers to pings
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Sink for MqttSink {
+    fn send(&mut self, samples: &[Sample]) -> Result<(), Failure> {
+        let result = self.publish(samples);
+        if result.is_err() {
+            self.stream = None;
+        }
+        Ok(result?)
+    }
+
+    fn tick(&mut self) {
+        let keep_alive = Duration::from_secs(self.config.keep_alive as u64);
+        if self.config.keep_alive == 0 || self.last_sent.elapsed() < keep_alive / 2 {
+            return;
+        }
+        if let Some(ref mut stream) = self.stream {
+            // The answer is read and ignored when waiting for acknowledgements
+            if stream.write_all(&packet(PINGREQ, &[])).is_err() {
+                self.stream = None;
+            }
+            self.last_sent = Instant::now();
+        }
+    }
+}
+
+impl Drop for MqttSink {
+    fn drop(&mut self) {
+        if let Some(ref mut stream) = self.stream {
+            // Disconnecting cleanly, so the broker does not publish the will
+            let _ = stream.write_all(&[0xe0, 0x00]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    use output::{Output, Sample};
+    use output::mqtt::{read_packet, topic, MqttConfig, MqttSink, Will};
+    use output::remote::{Delivery, RemoteOutput};
+
+    #[test]
+    fn topics() {
+        assert_eq!(topic("antikoerper/{host}/{key}", "laptop", "os.battery"), "antikoerper/laptop/os/battery");
+        assert_eq!(topic("{key}", "laptop", "net.wlan+0#"), "net/wlan_0_");
+    }
+
+    #[test]
+    fn publishes_and_reconnects() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let broker = thread::spawn(move || {
+            let mut received = Vec::new();
+            // The connection is closed after the first message, the sink has to connect again
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().unwrap();
+                let (first, connect) = read_packet(&mut stream).unwrap();
+                assert_eq!(first, 0x10);
+                received.push(connect);
+                stream.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap();
+
+                let (first, publish) = read_packet(&mut stream).unwrap();
+                // QoS 1 and retained
+                assert_eq!(first, 0x33);
+                let id = &publish[publish.len() - 4..publish.len() - 2];
+                stream.write_all(&[0x40, 0x02, id[0], id[1]]).unwrap();
+                received.push(publish);
+            }
+            received
+        });
+
+        let config = MqttConfig {
+            address,
+            client_id: Some(String::from("antikoerper-test")),
+            username: Some(String::from("user")),
+            password: None,
+            topic: String::from("antikoerper/test/{key}"),
+            qos: 1,
+            retain: true,
+            will: Some(Will {
+                topic: String::from("antikoerper/test"),
+                message: String::from("offline"),
+                qos: 0,
+                retain: true,
+            }),
+            keep_alive: 60,
+        };
+        let delivery = Delivery {
+            batch_size: 1,
+            batch_interval: Duration::from_secs(3600),
+            max_queue: 10,
+            ..Delivery::default()
+        };
+        let mut output = RemoteOutput::start("mqtt", Box::new(MqttSink::new(config)), delivery).unwrap();
+        for value in &["99\n", "98\n"] {
+            output.write(&Sample::new("os.battery", 1454328000, value)).unwrap();
+        }
+
+        let received = broker.join().unwrap();
+        drop(output);
+        let connect = b"\0\x04MQTT\x04\xa6\0\x3c\0\x10antikoerper-test\0\x10antikoerper/test\0\x07offline\0\x04user";
+        assert_eq!(received[0], &connect[..]);
+        assert_eq!(received[2], &connect[..]);
+        // The packet id is not checked, a message sent again gets a new one
+        let topic = b"\0\x1bantikoerper/test/os/battery";
+        assert!(received[1].starts_with(topic) && received[1].ends_with(b"99"));
+        assert!(received[3].starts_with(topic) && received[3].ends_with(b"98"));
+        assert_eq!(received[1].len(), topic.len() + 4);
+    }
+}
@@ -0,0 +1,204 @@
+//! Serving the latest values in the Prometheus text exposition format
+//!
+//! Keys are turned into metric names by replacing everything but letters, digits, `_` and `:`
+//! with underscores, prefixed with a namespace: `os.battery` becomes
+//! `antikoerper_os_battery`. As different keys may end up with the same name, every value is
+//! labeled with its key, the item recording it and the kind of that item. Only values that
+//! are numbers are served, all of them as gauges.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+
+use item::Item;
+use output::{Output, Sample};
+use output::http::{Request, Server};
+
+/// What is known about a key
+#[derive(Debug, Clone, PartialEq)]
+struct Metric {
+    value: f64,
+    /// The item recording the key
+    item: String,
+    kind: &'static str,
+    help: String,
+}
+
+type Metrics = Arc<Mutex<BTreeMap<String, Metric>>>;
+
+/// A valid metric name for `key`
+pub fn metric_name(namespace: &str, key: &str) -> String {
+    let mut name = String::with_capacity(namespace.len() + key.len() + 1);
+    if !namespace.is_empty() {
+        name.push_str(namespace);
+        name.push('_');
+    }
+    for c in key.chars() {
+        name.push(if c.is_ascii_alphanumeric() || c == '_' || c == ':' { c } else { '_' });
+    }
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    name
+}
+
+/// Escapes a label value, or with `quotes` unset, the text of a HELP line
+fn escape(s: &str, quotes: bool) -> String {
+    let s = s.replace('\\', "\\\\").replace('\n', "\\n");
+    if quotes { s.replace('"', "\\\"") } else { s }
+}
+
+/// All metrics in the text exposition format
+fn exposition(namespace: &str, metrics: &BTreeMap<String, Metric>) -> String {
+    let mut names = BTreeMap::new();
+    for (key, metric) in metrics {
+        names.entry(metric_name(namespace, key)).or_insert_with(Vec::new).push((key, metric));
+    }
+
+    let mut text = String::new();
+    for (name, keys) in names {
+        text.push_str(&format!("# HELP {} {}\n", name, escape(&keys[0].1.help, false)));
+        text.push_str(&format!("# TYPE {} gauge\n", name));
+        for (key, metric) in keys {
+            text.push_str(&format!("{}{{key=\"{}\",item=\"{}\",kind=\"{}\"}} {}\n",
+                                   name, escape(key, true), escape(&metric.item, true),
+                                   metric.kind, metric.value));
+        }
+    }
+    text
+}
+
+/// Answers a single request
+fn serve(stream: TcpStream, path: &str, namespace: &str, metrics: &Metrics) -> io::Result<()> {
+    let request = Request::read(&stream)?;
+    let (status, content_type, body) = match &request.method[..] {
+        "GET" | "HEAD" if request.path == path => {
+            let body = exposition(namespace, &metrics.lock().unwrap());
+            ("200 OK", "text/plain; version=0.0.4; charset=utf-8", body)
+        }
+        "GET" | "HEAD" => ("404 Not Found", "text/plain", format!("Metrics are at {}\n", path)),
+        _ => ("405 Method Not Allowed", "text/plain", String::from("Only GET is allowed\n")),
+    };
+    request.respond(&stream, status, &[("Content-Type", content_type)], body.as_bytes())
+}
+
+/// Keeps the latest value of every key and serves them over HTTP
+#[derive(Debug)]
+pub struct PrometheusOutput {
+    items: Vec<Item>,
+    metrics: Metrics,
+    /// Serving until we are dropped
+    _server: Server,
+}
+
+impl PrometheusOutput {
+    /// Listens on `listen` and serves the metrics at `path`. `items` are used to describe
+    /// the keys.
+    pub fn start(listen: &str, path: &str, namespace: &str, items: &[Item]) -> io::Result<PrometheusOutput> {
+        let metrics = Metrics::default();
+        let (served, served_path, namespace) = (metrics.clone(), path.to_owned(), namespace.to_owned());
+        let server = Server::start(listen, "prometheus", move |stream| {
+            if let Err(e) = serve(stream, &served_path, &namespace, &served) {
+                debug!("Could not answer a request for metrics: {}", e);
+            }
+        })?;
+        info!("Serving metrics at http://{}{}", server.address(), path);
+
+        Ok(PrometheusOutput {
+            items: items.to_vec(),
+            metrics,
+            _server: server,
+        })
+    }
+
+    fn describe(&self, key: &str) -> (String, &'static str, String) {
+        match self.items.iter().find(|i| i.provides(key)) {
+            Some(item) => {
+                let help = match item.kind.name() {
+                    "derived" => format!("Value of {} recorded by antikoerper, derived from other values", key),
+                    kind => format!("Value of {} recorded by antikoerper, from a {} item run every {}s",
+                                    key, kind, item.interval),
+                };
+                (item.key.clone(), item.kind.name(), help)
+            }
+            None => (key.to_owned(), "unknown", format!("Value of {} recorded by antikoerper", key)),
+        }
+    }
+}
+
+impl Output for PrometheusOutput {
+    fn write(&mut self, sample: &Sample) -> io::Result<()> {
+        let value = match sample.value.trim().parse::<f64>() {
+            Ok(v) if v.is_finite() => v,
+            _ => return Ok(()),
+        };
+        let mut metrics = self.metrics.lock().unwrap();
+        if let Some(metric) = metrics.get_mut(&sample.key) {
+            metric.value = value;
+            return Ok(());
+        }
+        let (item, kind, help) = self.describe(&sample.key);
+        metrics.insert(sample.key.clone(), Metric {
+            value,
+            item,
+            kind,
+            help,
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    use item::{Item, ItemKind};
+    use output::{Output, Sample};
+    use output::prometheus::{metric_name, PrometheusOutput};
+
+    fn get(output: &PrometheusOutput, path: &str) -> String {
+        let mut stream = TcpStream::connect(output._server.address()).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(metric_name("antikoerper", "os.battery"), "antikoerper_os_battery");
+        assert_eq!(metric_name("", "9.load-avg"), "_9_load_avg");
+    }
+
+    #[test]
+    fn serves_metrics() {
+        let item = Item {
+            next_time: 0,
+            interval: 60,
+            key: String::from("os.battery"),
+            env: BTreeMap::new(),
+            kind: ItemKind::Shell(String::from("acpi")),
+            counter: None,
+            outputs: None,
+        };
+        let mut output = PrometheusOutput::start("127.0.0.1:0", "/metrics", "antikoerper", &[item]).unwrap();
+        for &(key, value) in &[("os.battery", "99\n"), ("os.battery", "98\n"), ("os_battery", "1"),
+                               ("os.state", "Full")] {
+            output.write(&Sample::new(key, 1454328000, value)).unwrap();
+        }
+
+        let response = get(&output, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        assert_eq!(body, "\
+# HELP antikoerper_os_battery Value of os.battery recorded by antikoerper, from a shell item run every 60s
+# TYPE antikoerper_os_battery gauge
+antikoerper_os_battery{key=\"os.battery\",item=\"os.battery\",kind=\"shell\"} 98
+antikoerper_os_battery{key=\"os_battery\",item=\"os_battery\",kind=\"unknown\"} 1
+");
+        assert!(get(&output, "/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
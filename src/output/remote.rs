@@ -0,0 +1,614 @@
+//! Outputs sending samples over the network
+//!
+//! Samples are handed to a thread per output, so a slow or unreachable server never holds up
+//! the main loop. The thread queues them and sends them in batches. If sending fails the
+//! batch stays queued and is retried, waiting twice as long after every failure up to a
+//! limit. The queue is bounded, when it is full the oldest samples are dropped. It is kept in
+//! memory, or in a spool on disk so samples not sent yet survive restarts.
+//!
+//! For writing large amounts of samples at once, like when importing, `DirectOutput` sends the
+//! batches in the calling thread instead, so nothing is dropped.
+
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::io;
+use std::net::UdpSocket;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use toml;
+
+use output::{Output, OutputError, OutputErrorKind, Sample};
+use output::spool::Spool;
+use units;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+/// How often `Sink::tick` is called at least
+const TICK: Duration = Duration::from_secs(1);
+/// The largest datagram we send, to stay below common MTUs
+const MAX_DATAGRAM: usize = 1400;
+
+/// Checks that `s` is like `host:port`
+pub fn parse_address(s: &str) -> Option<String> {
+    let (host, port) = s.rsplit_once(':')?;
+    if host.is_empty() || port.parse::<u16>().is_err() {
+        return None;
+    }
+    Some(s.to_owned())
+}
+
+/// Binds a socket sending datagrams to `addr`
+pub fn udp_socket(addr: &str) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(if addr.starts_with('[') { "[::]:0" } else { "0.0.0.0:0" })?;
+    socket.connect(addr)?;
+    Ok(socket)
+}
+
+/// Sends `lines` in as few datagrams as possible, without splitting lines
+pub fn send_datagrams<I>(socket: &UdpSocket, lines: I) -> io::Result<()>
+    where I: IntoIterator<Item = String>
+{
+    let mut datagram = String::new();
+    for line in lines {
+        if !datagram.is_empty() && datagram.len() + line.len() > MAX_DATAGRAM {
+            socket.send(datagram.as_bytes())?;
+            datagram.clear();
+        }
+        datagram.push_str(&line);
+    }
+    if !datagram.is_empty() {
+        socket.send(datagram.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Why a batch could not be sent
+#[derive(Debug)]
+pub enum Failure {
+    /// Sending may work later, the batch is kept
+    Retry(io::Error),
+    /// The server does not accept the batch, sending it again would not help
+    Rejected(io::Error),
+}
+
+impl From<io::Error> for Failure {
+    fn from(e: io::Error) -> Failure {
+        Failure::Retry(e)
+    }
+}
+
+/// A way of sending samples to a server
+pub trait Sink: Debug + Send {
+    /// Sends all of `samples`, if an error is returned none of them count as sent
+    fn send(&mut self, samples: &[Sample]) -> Result<(), Failure>;
+
+    /// Called regularly, also when there is nothing to send, for keeping connections alive
+    fn tick(&mut self) {}
+}
+
+/// How samples are batched and queued
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Delivery {
+    /// How many samples are sent at once at most
+    pub batch_size: usize,
+    /// How long samples wait for a batch to fill up at most
+    pub batch_interval: Duration,
+    /// How many samples are kept in memory at most while they cannot be sent
+    pub max_queue: usize,
+    /// The directory samples are kept in while they cannot be sent, instead of memory
+    pub spool: Option<PathBuf>,
+    /// How many bytes the spool may take at most
+    pub max_spool: u64,
+}
+
+impl Default for Delivery {
+    fn default() -> Delivery {
+        Delivery {
+            batch_size: 1000,
+            batch_interval: Duration::from_secs(10),
+            max_queue: 100_000,
+            spool: None,
+            max_spool: 100 * 1024 * 1024,
+        }
+    }
+}
+
+impl Delivery {
+    /// Reads `batch_size`, `batch_interval`, `max_queue`, `spool` and `max_spool` of the
+    /// output `name`. There is only a spool if it is configured, `true` puts it in
+    /// `output/.spool/<name>`, where no key can get in the way as keys cannot start with a dot.
+    pub fn from_toml(table: &toml::Table, name: &str, output: &Path) -> Result<Delivery, OutputError> {
+        let default = Delivery::default();
+        let batch_size = match table.get("batch_size") {
+            Some(&toml::Value::Integer(i)) if i > 0 => i as usize,
+            Some(_) => return Err(OutputError::new(name.to_owned(), OutputErrorKind::InvalidBatchSize)),
+            None => default.batch_size,
+        };
+        let batch_interval = match table.get("batch_interval") {
+            Some(v) => Duration::from_secs(units::duration_from_toml(v).ok_or_else(|| {
+                OutputError::new(name.to_owned(), OutputErrorKind::InvalidBatchInterval)
+            })? as u64),
+            None => default.batch_interval,
+        };
+        let max_queue = match table.get("max_queue") {
+            Some(&toml::Value::Integer(i)) if i > 0 => i as usize,
+            Some(_) => return Err(OutputError::new(name.to_owned(), OutputErrorKind::InvalidMaxQueue)),
+            None => default.max_queue,
+        };
+        // Relative directories are relative to general.output
+        let spool = match table.get("spool") {
+            Some(&toml::Value::Boolean(false)) | None => None,
+            Some(toml::Value::String(s)) => Some(output.join(s)),
+            Some(&toml::Value::Boolean(true)) => Some(output.join(".spool").join(name)),
+            Some(_) => return Err(OutputError::new(name.to_owned(), OutputErrorKind::InvalidSpool)),
+        };
+        let max_spool = match table.get("max_spool") {
+            Some(v) => units::size_from_toml(v).ok_or_else(|| {
+                OutputError::new(name.to_owned(), OutputErrorKind::InvalidMaxSpool)
+            })?,
+            None => default.max_spool,
+        };
+        Ok(Delivery {
+            batch_size,
+            batch_interval,
+            max_queue,
+            spool,
+            max_spool,
+        })
+    }
+}
+
+/// Hands samples to the thread sending them with a `Sink`
+#[derive(Debug)]
+pub struct RemoteOutput {
+    sender: Option<Sender<Sample>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl RemoteOutput {
+    pub fn start(name: &str, sink: Box<dyn Sink>, delivery: Delivery) -> io::Result<RemoteOutput> {
+        let queue = match delivery.spool {
+            Some(ref directory) => {
+                let spool = Spool::open(directory, delivery.max_spool)?;
+                if !spool.is_empty() {
+                    info!("Output {} has {} samples left to send in its spool", name, spool.len());
+                }
+                Queue::Spool(Box::new(spool))
+            }
+            None => Queue::Memory(VecDeque::new()),
+        };
+        let (sender, receiver) = mpsc::channel();
+        let name = name.to_owned();
+        let worker = thread::Builder::new()
+            .name(format!("output {}", name))
+            .spawn(move || Worker::new(name, sink, delivery, queue).run(receiver))?;
+        Ok(RemoteOutput {
+            sender: Some(sender),
+            worker: Some(worker),
+        })
+    }
+}
+
+impl Output for RemoteOutput {
+    fn write(&mut self, sample: &Sample) -> io::Result<()> {
+        match self.sender {
+            Some(ref s) => s.send(sample.clone())
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the sending thread stopped")),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for RemoteOutput {
+    /// Gives the thread a last chance to send what is queued
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Sends samples with a `Sink` in batches, waiting for each batch to be sent
+#[derive(Debug)]
+pub struct DirectOutput {
+    name: String,
+    sink: Box<dyn Sink>,
+    batch_size: usize,
+    batch: Vec<Sample>,
+}
+
+/// How often a batch is tried to be sent by `DirectOutput` before giving up
+const ATTEMPTS: u32 = 5;
+
+impl DirectOutput {
+    pub fn new(name: &str, sink: Box<dyn Sink>, delivery: &Delivery) -> DirectOutput {
+        DirectOutput {
+            name: name.to_owned(),
+            sink,
+            batch_size: delivery.batch_size,
+            batch: Vec::new(),
+        }
+    }
+
+    fn send(&mut self) -> io::Result<()> {
+        let mut wait = MIN_BACKOFF;
+        for attempt in 1..ATTEMPTS + 1 {
+            match self.sink.send(&self.batch) {
+                Ok(()) => {
+                    self.batch.clear();
+                    return Ok(());
+                }
+                Err(Failure::Rejected(e)) => return Err(e),
+                Err(Failure::Retry(e)) if attempt == ATTEMPTS => return Err(e),
+                Err(Failure::Retry(e)) => {
+                    warn!("Could not send to output {}, retrying in {}s: {}", self.name, wait.as_secs(), e);
+                    thread::sleep(wait);
+                    wait = (wait * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Output for DirectOutput {
+    fn write(&mut self, sample: &Sample) -> io::Result<()> {
+        self.batch.push(sample.clone());
+        if self.batch.len() >= self.batch_size {
+            self.send()?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        self.send()
+    }
+}
+
+/// Where samples wait until they are sent
+#[derive(Debug)]
+enum Queue {
+    Memory(VecDeque<Sample>),
+    Spool(Box<Spool>),
+}
+
+impl Queue {
+    fn len(&self) -> usize {
+        match *self {
+            Queue::Memory(ref samples) => samples.len(),
+            Queue::Spool(ref spool) => spool.len(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Appends a sample, returns how many samples were dropped to stay below the limit
+    fn push(&mut self, sample: Sample, delivery: &Delivery) -> io::Result<usize> {
+        match *self {
+            Queue::Memory(ref mut samples) => {
+                samples.push_back(sample);
+                let dropped = samples.len().saturating_sub(delivery.max_queue);
+                samples.drain(..dropped);
+                Ok(dropped)
+            }
+            Queue::Spool(ref mut spool) => spool.push(&sample),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Queue::Memory(_) => Ok(()),
+            Queue::Spool(ref mut spool) => spool.flush(),
+        }
+    }
+
+    /// The oldest `n` samples, fewer if there are not that many
+    fn front(&mut self, n: usize) -> io::Result<&[Sample]> {
+        match *self {
+            Queue::Memory(ref mut samples) => {
+                let n = n.min(samples.len());
+                Ok(&samples.make_contiguous()[..n])
+            }
+            Queue::Spool(ref mut spool) => spool.front(n),
+        }
+    }
+
+    /// Removes the oldest `n` samples
+    fn pop(&mut self, n: usize) -> io::Result<()> {
+        match *self {
+            Queue::Memory(ref mut samples) => {
+                samples.drain(..n);
+                Ok(())
+            }
+            Queue::Spool(ref mut spool) => spool.pop(n),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Worker {
+    name: String,
+    sink: Box<dyn Sink>,
+    delivery: Delivery,
+    queue: Queue,
+    /// Since when the samples in the queue are waiting
+    since: Instant,
+    /// How long to wait after the last failure, and when it happened
+    backoff: Option<(Duration, Instant)>,
+    /// How many samples were dropped since the last time we told
+    dropped: u64,
+}
+
+impl Worker {
+    fn new(name: String, sink: Box<dyn Sink>, delivery: Delivery, queue: Queue) -> Worker {
+        Worker {
+            name,
+            sink,
+            delivery,
+            queue,
+            since: Instant::now(),
+            backoff: None,
+            dropped: 0,
+        }
+    }
+
+    /// When the next batch should be sent, `None` if there is nothing to send
+    fn next_attempt(&self) -> Option<Instant> {
+        if self.queue.is_empty() {
+            return None;
+        }
+        let due = if self.queue.len() >= self.delivery.batch_size {
+            self.since
+        } else {
+            self.since + self.delivery.batch_interval
+        };
+        Some(match self.backoff {
+            Some((wait, failed)) => due.max(failed + wait),
+            None => due,
+        })
+    }
+
+    fn push(&mut self, sample: Sample) {
+        if self.queue.is_empty() {
+            self.since = Instant::now();
+        }
+        match self.queue.push(sample, &self.delivery) {
+            Ok(dropped) => self.dropped += dropped as u64,
+            Err(e) => {
+                error!("Could not queue a sample for output {}: {}", self.name, e);
+                self.dropped += 1;
+            }
+        }
+    }
+
+    /// Sends one batch, returns whether it is worth trying the next one right away
+    fn send_batch(&mut self) -> bool {
+        let result = match self.queue.front(self.delivery.batch_size) {
+            Ok(batch) => (batch.len(), self.sink.send(batch)),
+            Err(e) => (0, Err(Failure::Retry(e))),
+        };
+        let (len, result) = result;
+        match result {
+            Ok(()) => {
+                self.backoff = None;
+            }
+            Err(Failure::Rejected(e)) => {
+                error!("Output {} rejected {} samples, dropping them: {}", self.name, len, e);
+            }
+            Err(Failure::Retry(e)) => {
+                let wait = match self.backoff {
+                    Some((wait, _)) => (wait * 2).min(MAX_BACKOFF),
+                    None => MIN_BACKOFF,
+                };
+                warn!("Could not send to output {}, retrying in {}s: {}", self.name, wait.as_secs(), e);
+                self.backoff = Some((wait, Instant::now()));
+                return false;
+            }
+        }
+        if let Err(e) = self.queue.pop(len) {
+            error!("Could not remove sent samples from the queue of output {}: {}", self.name, e);
+            return false;
+        }
+        self.since = Instant::now();
+        !self.queue.is_empty()
+    }
+
+    fn run(mut self, receiver: mpsc::Receiver<Sample>) {
+        loop {
+            let timeout = match self.next_attempt() {
+                Some(at) => at.saturating_duration_since(Instant::now()).min(TICK),
+                None => TICK,
+            };
+            let received = receiver.recv_timeout(timeout);
+            match received {
+                Ok(sample) => {
+                    self.push(sample);
+                    // Take everything that is waiting before sending
+                    while let Ok(sample) = receiver.try_recv() {
+                        self.push(sample);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            if let Err(e) = self.queue.flush() {
+                error!("Could not write the spool of output {}: {}", self.name, e);
+            }
+            self.sink.tick();
+
+            if self.dropped > 0 {
+                warn!("Output {} dropped {} samples, its queue is full", self.name, self.dropped);
+                self.dropped = 0;
+            }
+            while self.next_attempt().is_some_and(|at| at <= Instant::now()) {
+                if !self.send_batch() {
+                    break;
+                }
+            }
+        }
+
+        // We are shutting down, try once to send what is left
+        self.backoff = None;
+        while !self.queue.is_empty() && self.send_batch() {}
+        match self.queue {
+            Queue::Memory(ref samples) if !samples.is_empty() => {
+                warn!("Output {} is stopping, {} samples were not sent", self.name, samples.len());
+            }
+            Queue::Spool(ref spool) if !spool.is_empty() => {
+                info!("Output {} is stopping, {} samples are kept in its spool", self.name, spool.len());
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::time::Duration;
+
+    use output::{Output, Sample};
+    use output::remote::{Delivery, DirectOutput, Failure, RemoteOutput, Sink};
+
+    /// Fails the first `failures` times, then remembers the batches it got
+    #[derive(Debug)]
+    struct Flaky {
+        failures: usize,
+        batches: Arc<Mutex<Vec<Vec<String>>>>,
+    }
+
+    impl Sink for Flaky {
+        fn send(&mut self, samples: &[Sample]) -> Result<(), Failure> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(Failure::Retry(io::Error::other("down")));
+            }
+            self.batches.lock().unwrap().push(samples.iter().map(|s| s.value.clone()).collect());
+            Ok(())
+        }
+    }
+
+    /// Tells about every attempt to send and waits to be released before handing it to `Flaky`
+    #[derive(Debug)]
+    struct Gated {
+        sink: Flaky,
+        attempts: Sender<Vec<String>>,
+        release: Receiver<()>,
+    }
+
+    impl Sink for Gated {
+        fn send(&mut self, samples: &[Sample]) -> Result<(), Failure> {
+            let _ = self.attempts.send(samples.iter().map(|s| s.value.clone()).collect());
+            let _ = self.release.recv();
+            self.sink.send(samples)
+        }
+    }
+
+    #[test]
+    fn batches_and_retries() {
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let (attempts, attempted) = mpsc::channel();
+        let (released, release) = mpsc::channel();
+        let sink = Gated {
+            sink: Flaky {
+                failures: 1,
+                batches: batches.clone(),
+            },
+            attempts,
+            release,
+        };
+        let delivery = Delivery {
+            batch_size: 2,
+            batch_interval: Duration::from_secs(3600),
+            max_queue: 3,
+            ..Delivery::default()
+        };
+        let mut output = RemoteOutput::start("test", Box::new(sink), delivery).unwrap();
+        let mut write = |i: i64| output.write(&Sample::new("os.battery", 1454328000 + i, &i.to_string())).unwrap();
+        write(0);
+        write(1);
+        // A full batch is sent right away, it fails once it is released
+        assert_eq!(attempted.recv().unwrap(), ["0", "1"]);
+        // Meanwhile the queue overflows and the oldest sample is dropped
+        write(2);
+        write(3);
+        released.send(()).unwrap();
+        // Later attempts do not wait
+        drop(released);
+        // Sends everything that is left when stopping
+        drop(output);
+
+        assert_eq!(*batches.lock().unwrap(), [vec!["1", "2"], vec!["3"]]);
+    }
+
+    #[test]
+    fn spools_across_restarts() {
+        let dir = env::temp_dir().join("antikoerper-remote-spool");
+        let _ = fs::remove_dir_all(&dir);
+        let delivery = Delivery {
+            batch_size: 2,
+            batch_interval: Duration::from_secs(3600),
+            spool: Some(dir.clone()),
+            ..Delivery::default()
+        };
+        let write = |output: &mut RemoteOutput, i: i64| {
+            output.write(&Sample::new("os.battery", 1454328000 + i, &i.to_string())).unwrap();
+        };
+
+        // The server is down the whole time
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let sink = Flaky {
+            failures: usize::MAX,
+            batches: batches.clone(),
+        };
+        let mut output = RemoteOutput::start("test", Box::new(sink), delivery.clone()).unwrap();
+        for i in 0..3 {
+            write(&mut output, i);
+        }
+        drop(output);
+        assert!(batches.lock().unwrap().is_empty());
+
+        let sink = Flaky {
+            failures: 0,
+            batches: batches.clone(),
+        };
+        let mut output = RemoteOutput::start("test", Box::new(sink), delivery).unwrap();
+        write(&mut output, 3);
+        drop(output);
+        assert_eq!(*batches.lock().unwrap(), [vec!["0", "1"], vec!["2", "3"]]);
+    }
+
+    #[test]
+    fn sends_directly() {
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let sink = Flaky {
+            failures: 1,
+            batches: batches.clone(),
+        };
+        let delivery = Delivery {
+            batch_size: 2,
+            max_queue: 1,
+            ..Delivery::default()
+        };
+        let mut output = DirectOutput::new("test", Box::new(sink), &delivery);
+        for i in 0..3 {
+            output.write(&Sample::new("os.battery", 1454328000 + i, &i.to_string())).unwrap();
+        }
+        assert_eq!(*batches.lock().unwrap(), [vec!["0", "1"]]);
+        output.finish().unwrap();
+        assert_eq!(*batches.lock().unwrap(), [vec!["0", "1"], vec!["2"]]);
+    }
+}
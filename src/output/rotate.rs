@@ -0,0 +1,451 @@
+//! Rotation of the files of the file output
+//!
+//! A file is rotated by renaming it to `<file>@<time>`, where `<time>` is the UTC time of the
+//! rotation like `20160201T120000Z`, so every segment only contains samples from before the
+//! time in its name. The next sample is written to a new file. Since rotating is a rename
+//! done between two appends nothing is lost. Compressing segments and removing old ones
+//! happens in the background: segments are compressed into a temporary file that is then
+//! renamed to `<file>@<time>.gz` or `<file>@<time>.zst`, and only after that is the
+//! uncompressed segment removed. Only one thread maintains the segments of a file at a time,
+//! rotations while it runs make it run again once it is done.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, Metadata};
+use std::io::{self, BufReader, BufWriter, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::UNIX_EPOCH;
+
+use flate2::Compression as GzLevel;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use time::{self, Timespec};
+use zstd;
+
+const STAMP: &str = "%Y%m%dT%H%M%SZ";
+
+/// The files whose segments are being maintained, with the time of a rotation that happened
+/// while they were
+static MAINTAINING: Mutex<BTreeMap<PathBuf, Option<i64>>> = Mutex::new(BTreeMap::new());
+
+/// When files are rotated based on the time
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Period {
+    /// At local midnight
+    Daily,
+    /// At local midnight from Sunday to Monday
+    Weekly,
+}
+
+impl Period {
+    pub fn from_name(s: &str) -> Option<Period> {
+        match s {
+            "daily" => Some(Period::Daily),
+            "weekly" => Some(Period::Weekly),
+            _ => None,
+        }
+    }
+
+    /// A number that is the same for all times in the same period
+    fn id(&self, time: i64) -> i64 {
+        let local = time + time::at(Timespec::new(time, 0)).tm_utcoff as i64;
+        let day = local.div_euclid(24 * 60 * 60);
+        match *self {
+            Period::Daily => day,
+            // The epoch was a Thursday
+            Period::Weekly => (day + 3).div_euclid(7),
+        }
+    }
+}
+
+/// How rotated segments are compressed
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub fn from_name(s: &str) -> Option<Compression> {
+        match s {
+            "gzip" => Some(Compression::Gzip),
+            "zstd" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match *self {
+            Compression::Gzip => "gz",
+            Compression::Zstd => "zst",
+        }
+    }
+
+    /// Compresses the file at `from` into a new file at `to`
+    pub fn compress(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut input = BufReader::new(File::open(from)?);
+        let output = BufWriter::new(File::create(to)?);
+        let output = match *self {
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(output, GzLevel::default());
+                io::copy(&mut input, &mut encoder)?;
+                encoder.finish()?
+            }
+            Compression::Zstd => {
+                let mut encoder = zstd::Encoder::new(output, 0)?;
+                io::copy(&mut input, &mut encoder)?;
+                encoder.finish()?
+            }
+        };
+        output.into_inner().map_err(|e| e.into_error())?.sync_all()
+    }
+
+    /// Reads the uncompressed content of `file`
+    pub fn reader(&self, file: File) -> io::Result<Box<dyn Read>> {
+        Ok(match *self {
+            Compression::Gzip => Box::new(GzDecoder::new(file)),
+            Compression::Zstd => Box::new(zstd::Decoder::new(file)?),
+        })
+    }
+}
+
+/// A rotated part of the file of a key
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Segment {
+    pub path: PathBuf,
+    /// The time the segment was rotated at, it only contains samples from before it
+    pub end: i64,
+    pub compression: Option<Compression>,
+}
+
+impl Segment {
+    /// Opens the segment for reading its uncompressed content
+    pub fn open(&self) -> io::Result<Box<dyn Read>> {
+        let file = match File::open(&self.path) {
+            Ok(f) => f,
+            Err(e) => {
+                // It may have been compressed since we looked
+                if e.kind() == io::ErrorKind::NotFound && self.compression.is_none() {
+                    for &compression in &[Compression::Gzip, Compression::Zstd] {
+                        let mut path = self.path.clone().into_os_string();
+                        path.push(".");
+                        path.push(compression.extension());
+                        let segment = Segment {
+                            path: PathBuf::from(path),
+                            end: self.end,
+                            compression: Some(compression),
+                        };
+                        if segment.path.exists() {
+                            return segment.open();
+                        }
+                    }
+                }
+                return Err(e);
+            }
+        };
+        match self.compression {
+            None => Ok(Box::new(file)),
+            Some(compression) => compression.reader(file),
+        }
+    }
+}
+
+/// The rotated segments of the file at `path`, oldest first.
+///
+/// If a segment exists both uncompressed and compressed, because it is being compressed right
+/// now, only the uncompressed one is returned.
+pub fn segments(path: &Path) -> io::Result<Vec<Segment>> {
+    let (directory, name) = match (path.parent(), path.file_name().and_then(|n| n.to_str())) {
+        (Some(d), Some(n)) => (d, n),
+        _ => return Ok(Vec::new()),
+    };
+    let prefix = format!("{}@", name);
+
+    let mut segments = Vec::new();
+    let entries = match fs::read_dir(directory) {
+        Ok(e) => e,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let rest = match file_name.to_str().and_then(|n| n.strip_prefix(&prefix)) {
+            Some(r) => r,
+            None => continue,
+        };
+        let (stamp, compression) = match rest.split_once('.') {
+            Some((stamp, "gz")) => (stamp, Some(Compression::Gzip)),
+            Some((stamp, "zst")) => (stamp, Some(Compression::Zstd)),
+            Some(_) => continue,
+            None => (rest, None),
+        };
+        let end = match time::strptime(stamp, STAMP) {
+            Ok(tm) => tm.to_timespec().sec,
+            Err(_) => continue,
+        };
+        segments.push(Segment {
+            path: entry.path(),
+            end,
+            compression,
+        });
+    }
+    segments.sort_by_key(|s| (s.end, s.compression.is_some()));
+    segments.dedup_by(|later, earlier| later.end == earlier.end);
+    Ok(segments)
+}
+
+/// When a file was modified, in seconds since the epoch
+pub fn modified(metadata: &Metadata) -> Option<i64> {
+    metadata.modified().ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64)
+}
+
+/// When files are rotated, how the segments are compressed and how long they are kept
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Rotation {
+    pub period: Option<Period>,
+    /// The size in bytes a file may not exceed
+    pub max_size: Option<u64>,
+    pub compression: Option<Compression>,
+    /// Segments that ended longer ago than this many seconds are removed
+    pub max_age: Option<i64>,
+    /// The oldest segments are removed while a key takes up more bytes than this
+    pub max_total: Option<u64>,
+}
+
+impl Rotation {
+    pub fn is_enabled(&self) -> bool {
+        self.period.is_some() || self.max_size.is_some()
+    }
+
+    /// Whether a file of `size` bytes, last written to at `modified`, has to be rotated before
+    /// appending `len` bytes at `time`
+    pub fn due(&self, size: u64, modified: Option<i64>, time: i64, len: u64) -> bool {
+        if size == 0 {
+            return false;
+        }
+        if let Some(max_size) = self.max_size {
+            if size + len > max_size {
+                return true;
+            }
+        }
+        match (self.period, modified) {
+            (Some(period), Some(modified)) => period.id(modified) != period.id(time),
+            _ => false,
+        }
+    }
+
+    /// Rotates the file at `path` at `time`, then compresses and removes segments in the
+    /// background
+    pub fn rotate(&self, path: &Path, time: i64) -> io::Result<thread::JoinHandle<()>> {
+        let mut end = time;
+        let segment = loop {
+            let segment = segment_path(path, end, None);
+            let taken = segment.exists()
+                || segment_path(path, end, Some(Compression::Gzip)).exists()
+                || segment_path(path, end, Some(Compression::Zstd)).exists();
+            if !taken {
+                break segment;
+            }
+            end += 1;
+        };
+        fs::rename(path, &segment)?;
+        debug!("Rotated {} to {}", path.display(), segment.display());
+
+        let rotation = *self;
+        let path = path.to_owned();
+        Ok(thread::spawn(move || {
+            {
+                let mut maintaining = MAINTAINING.lock().unwrap();
+                if let Some(again) = maintaining.get_mut(&path) {
+                    *again = Some(time);
+                    return;
+                }
+                maintaining.insert(path.clone(), None);
+            }
+            let mut time = time;
+            loop {
+                if let Err(e) = rotation.maintain(&path, time) {
+                    error!("Could not maintain segments of {}: {}", path.display(), e);
+                }
+                let mut maintaining = MAINTAINING.lock().unwrap();
+                match maintaining.get_mut(&path).and_then(Option::take) {
+                    Some(again) => time = again,
+                    None => {
+                        maintaining.remove(&path);
+                        return;
+                    }
+                }
+            }
+        }))
+    }
+
+    /// Compresses all uncompressed segments of the file at `path`, including those left over
+    /// from earlier runs, and removes the segments beyond the retention limits and the temporary
+    /// files of interrupted compressions
+    fn maintain(&self, path: &Path, now: i64) -> io::Result<()> {
+        for temporary in temporaries(path)? {
+            remove(&temporary)?;
+        }
+        if let Some(compression) = self.compression {
+            for segment in segments(path)? {
+                if segment.compression.is_some() {
+                    continue;
+                }
+                let compressed = segment_path(path, segment.end, Some(compression));
+                let mut temporary = compressed.clone().into_os_string();
+                temporary.push(".tmp");
+                compression.compress(&segment.path, Path::new(&temporary))?;
+                fs::rename(&temporary, &compressed)?;
+                fs::remove_file(&segment.path)?;
+            }
+        }
+
+        let mut segments = segments(path)?;
+        if let Some(max_age) = self.max_age {
+            for segment in segments.iter().filter(|s| s.end < now - max_age) {
+                remove(&segment.path)?;
+            }
+            segments.retain(|s| s.end >= now - max_age);
+        }
+        if let Some(max_total) = self.max_total {
+            let mut total = match fs::metadata(path) {
+                Ok(m) => m.len(),
+                Err(_) => 0,
+            };
+            let mut sizes = Vec::with_capacity(segments.len());
+            for segment in &segments {
+                let size = fs::metadata(&segment.path).map(|m| m.len()).unwrap_or(0);
+                total += size;
+                sizes.push(size);
+            }
+            for (segment, size) in segments.iter().zip(sizes) {
+                if total <= max_total {
+                    break;
+                }
+                remove(&segment.path)?;
+                total -= size;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The temporary files of compressions of segments of the file at `path` that were interrupted
+fn temporaries(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let (directory, name) = match (path.parent(), path.file_name().and_then(|n| n.to_str())) {
+        (Some(d), Some(n)) => (d, n),
+        _ => return Ok(Vec::new()),
+    };
+    let prefix = format!("{}@", name);
+    let mut temporaries = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let temporary = file_name.to_str()
+            .and_then(|n| n.strip_prefix(&prefix))
+            .is_some_and(|n| n.ends_with(".gz.tmp") || n.ends_with(".zst.tmp"));
+        if temporary {
+            temporaries.push(entry.path());
+        }
+    }
+    Ok(temporaries)
+}
+
+fn segment_path(path: &Path, end: i64, compression: Option<Compression>) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push("@");
+    name.push(time::at_utc(Timespec::new(end, 0)).strftime(STAMP).unwrap().to_string());
+    if let Some(c) = compression {
+        name.push(".");
+        name.push(c.extension());
+    }
+    PathBuf::from(name)
+}
+
+/// Removes a file that may have been removed already
+fn remove(path: &Path) -> io::Result<()> {
+    debug!("Removing {}", path.display());
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        r => r,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+
+    use flate2::read::GzDecoder;
+    use zstd;
+
+    use output::rotate::{segments, Compression, Period, Rotation};
+
+    #[test]
+    fn periods() {
+        // Ten minutes apart, but around midnight UTC
+        let (before, after) = (1454284500, 1454285100);
+        let utc = |t: i64| t - ::time::at(::time::Timespec::new(t, 0)).tm_utcoff as i64;
+        assert!(Period::Daily.id(utc(before)) != Period::Daily.id(utc(after)));
+        // 2016-02-01 was a Monday
+        assert!(Period::Weekly.id(utc(before)) != Period::Weekly.id(utc(after)));
+        assert_eq!(Period::Weekly.id(utc(after)), Period::Weekly.id(utc(after) + 6 * 24 * 60 * 60));
+    }
+
+    #[test]
+    fn rotate_compress_and_remove() {
+        let dir = env::temp_dir().join("antikoerper-rotate");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("os.battery");
+        // Left over from an earlier run that was stopped while compressing
+        let temporary = dir.join("os.battery@20160201T000000Z.gz.tmp");
+        File::create(&temporary).unwrap();
+
+        let mut rotation = Rotation {
+            max_size: Some(10),
+            compression: Some(Compression::Gzip),
+            ..Rotation::default()
+        };
+        for (i, &time) in [1454328000, 1454328000, 1454328060].iter().enumerate() {
+            writeln!(File::create(&path).unwrap(), "{} {}", time, i).unwrap();
+            rotation.rotate(&path, time).unwrap().join().unwrap();
+        }
+
+        assert!(!temporary.exists());
+        let found = segments(&path).unwrap();
+        assert_eq!(found.iter().map(|s| s.end).collect::<Vec<_>>(),
+                   vec![1454328000, 1454328001, 1454328060]);
+        assert!(found.iter().all(|s| s.compression == Some(Compression::Gzip)));
+        let mut content = String::new();
+        GzDecoder::new(File::open(&found[1].path).unwrap()).read_to_string(&mut content).unwrap();
+        assert_eq!(content, "1454328000 1\n");
+        assert_eq!(found[1].path, dir.join("os.battery@20160201T120001Z.gz"));
+
+        // Segments that ended more than an hour ago are removed
+        rotation.compression = Some(Compression::Zstd);
+        rotation.max_age = Some(60 * 60);
+        writeln!(File::create(&path).unwrap(), "1454331650 3").unwrap();
+        rotation.rotate(&path, 1454331650).unwrap().join().unwrap();
+        let found = segments(&path).unwrap();
+        assert_eq!(found.iter().map(|s| s.end).collect::<Vec<_>>(), vec![1454328060, 1454331650]);
+        let content = zstd::decode_all(File::open(&found[1].path).unwrap()).unwrap();
+        assert_eq!(content, b"1454331650 3\n");
+
+        // As are the oldest ones while there are too many bytes
+        rotation.compression = None;
+        rotation.max_total = Some(found[1].path.metadata().unwrap().len() + 13);
+        writeln!(File::create(&path).unwrap(), "1454331660 4").unwrap();
+        rotation.rotate(&path, 1454331660).unwrap().join().unwrap();
+        let found = segments(&path).unwrap();
+        assert_eq!(found.iter().map(|s| (s.end, s.compression)).collect::<Vec<_>>(),
+                   vec![(1454331650, Some(Compression::Zstd)), (1454331660, None)]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
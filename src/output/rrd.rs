@@ -0,0 +1,522 @@
+//! An output keeping consolidated values of each key in round-robin archives
+//!
+//! Every key gets a file `<key>.rrd` whose size is fixed when it is created. It holds a number
+//! of archives, each with a row per `step` seconds for a fixed number of rows. When a row is
+//! reused, the values it held are lost. Each row holds a value per consolidation function,
+//! computed from all samples that arrived during its step.
+//!
+//! All numbers are little endian. The file starts with a header:
+//!
+//! - the magic bytes `AKRRD`, followed by two zero bytes and the version, currently 1
+//! - the number of archives and of consolidation functions, as u32
+//! - the consolidation functions, as u32: 0 for avg, 1 for min, 2 for max, 3 for last
+//! - for each archive its step in seconds as i64 and its number of rows as u64
+//!
+//! It is followed by the state of each archive: the step currently filled as i64, which is
+//! the time divided by the step, or `i64::MIN` if there was no sample yet, the accumulated
+//! value of each consolidation function as f64 and the number of samples in the step as u64.
+//! Then come the rows of each archive, with the values of the consolidation functions as f64
+//! and NaN for unknown values. The row of a step is the step modulo the number of rows.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use item::valid_key;
+use output::{Output, Sample};
+use units;
+
+const MAGIC: &[u8; 8] = b"AKRRD\0\0\x01";
+const NONE: i64 = i64::MIN;
+
+/// How the samples arriving during a step are turned into a single value
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Consolidation {
+    Average,
+    Min,
+    Max,
+    Last,
+}
+
+impl Consolidation {
+    pub fn from_name(s: &str) -> Option<Consolidation> {
+        match s {
+            "avg" => Some(Consolidation::Average),
+            "min" => Some(Consolidation::Min),
+            "max" => Some(Consolidation::Max),
+            "last" => Some(Consolidation::Last),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Consolidation::Average => "avg",
+            Consolidation::Min => "min",
+            Consolidation::Max => "max",
+            Consolidation::Last => "last",
+        }
+    }
+
+    fn from_code(code: u32) -> Option<Consolidation> {
+        [Consolidation::Average, Consolidation::Min, Consolidation::Max, Consolidation::Last]
+            .get(code as usize).cloned()
+    }
+
+    fn code(&self) -> u32 {
+        match *self {
+            Consolidation::Average => 0,
+            Consolidation::Min => 1,
+            Consolidation::Max => 2,
+            Consolidation::Last => 3,
+        }
+    }
+
+    /// Adds `value` to what was accumulated from `count` samples before
+    fn accumulate(&self, accumulated: f64, count: u64, value: f64) -> f64 {
+        if count == 0 {
+            return value;
+        }
+        match *self {
+            Consolidation::Average => accumulated + value,
+            Consolidation::Min => accumulated.min(value),
+            Consolidation::Max => accumulated.max(value),
+            Consolidation::Last => value,
+        }
+    }
+
+    fn value(&self, accumulated: f64, count: u64) -> f64 {
+        match *self {
+            Consolidation::Average => accumulated / count as f64,
+            _ => accumulated,
+        }
+    }
+}
+
+/// How long values are kept at which resolution
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Archive {
+    /// The seconds consolidated into a row
+    pub step: i64,
+    pub rows: u64,
+}
+
+impl Archive {
+    /// Parses `<step>:<span>` like `1m:1w`, both durations as understood by `units`
+    pub fn from_name(s: &str) -> Option<Archive> {
+        let (step, span) = s.split_once(':')?;
+        let step = units::parse_duration(step)?;
+        let span = units::parse_duration(span)?;
+        if step <= 0 || span < step {
+            return None;
+        }
+        Some(Archive {
+            step,
+            rows: ((span + step - 1) / step) as u64,
+        })
+    }
+}
+
+/// The state of an archive in an opened file
+#[derive(Debug)]
+struct State {
+    archive: Archive,
+    step: i64,
+    accumulated: Vec<f64>,
+    count: u64,
+    /// Where the state and the rows are in the file
+    state_offset: u64,
+    rows_offset: u64,
+}
+
+/// An opened file of a key
+#[derive(Debug)]
+struct Rrd {
+    file: File,
+    consolidations: Vec<Consolidation>,
+    states: Vec<State>,
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn invalid(path: &Path, message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), message))
+}
+
+impl Rrd {
+    fn header(archives: &[Archive], consolidations: &[Consolidation]) -> Vec<u8> {
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&(archives.len() as u32).to_le_bytes());
+        header.extend_from_slice(&(consolidations.len() as u32).to_le_bytes());
+        for c in consolidations {
+            header.extend_from_slice(&c.code().to_le_bytes());
+        }
+        for a in archives {
+            header.extend_from_slice(&a.step.to_le_bytes());
+            header.extend_from_slice(&a.rows.to_le_bytes());
+        }
+        header
+    }
+
+    /// Reads the archives and consolidation functions a file was created with
+    fn read_header(file: &File, path: &Path) -> io::Result<(Vec<Archive>, Vec<Consolidation>)> {
+        let mut start = [0; 16];
+        file.read_exact_at(&mut start, 0)?;
+        if &start[..8] != MAGIC {
+            return Err(invalid(path, "not a round-robin file of version 1"));
+        }
+        let (archives, consolidations) = (read_u32(&start, 8) as usize, read_u32(&start, 12) as usize);
+        if archives > 1024 || consolidations > 4 {
+            return Err(invalid(path, "too many archives or consolidation functions"));
+        }
+        let mut rest = vec![0; consolidations * 4 + archives * 16];
+        file.read_exact_at(&mut rest, 16)?;
+
+        let consolidations = (0..consolidations)
+            .map(|i| Consolidation::from_code(read_u32(&rest, i * 4)))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| invalid(path, "unknown consolidation function"))?;
+        let offset = consolidations.len() * 4;
+        let archives = (0..archives)
+            .map(|i| Archive {
+                step: read_u64(&rest, offset + i * 16) as i64,
+                rows: read_u64(&rest, offset + i * 16 + 8),
+            })
+            .collect();
+        Ok((archives, consolidations))
+    }
+
+    /// The states of `archives` with where they and their rows are in a file, and the size of
+    /// the file
+    fn layout(archives: &[Archive], consolidations: &[Consolidation]) -> (Vec<State>, u64) {
+        let header_size = Rrd::header(archives, consolidations).len() as u64;
+        let state_size = 8 + 8 * consolidations.len() as u64 + 8;
+        let row_size = 8 * consolidations.len() as u64;
+
+        let mut states = Vec::with_capacity(archives.len());
+        let mut state_offset = header_size;
+        let mut rows_offset = state_offset + state_size * archives.len() as u64;
+        for &archive in archives {
+            states.push(State {
+                archive,
+                step: NONE,
+                accumulated: vec![0.0; consolidations.len()],
+                count: 0,
+                state_offset,
+                rows_offset,
+            });
+            state_offset += state_size;
+            rows_offset += row_size * archive.rows;
+        }
+        (states, rows_offset)
+    }
+
+    fn read_states(file: &File, states: &mut [State]) -> io::Result<()> {
+        for state in states {
+            let mut buf = vec![0; 8 + 8 * state.accumulated.len() + 8];
+            file.read_exact_at(&mut buf, state.state_offset)?;
+            state.step = read_u64(&buf, 0) as i64;
+            for (i, accumulated) in state.accumulated.iter_mut().enumerate() {
+                *accumulated = f64::from_bits(read_u64(&buf, 8 + i * 8));
+            }
+            state.count = read_u64(&buf, buf.len() - 8);
+        }
+        Ok(())
+    }
+
+    /// Opens the file at `path`, creating it with all its rows if it does not exist yet
+    fn open(path: &Path, archives: &[Archive], consolidations: &[Consolidation]) -> io::Result<Rrd> {
+        let (mut states, size) = Rrd::layout(archives, consolidations);
+        let file = match OpenOptions::new().read(true).write(true).open(path) {
+            Ok(file) => {
+                if Rrd::read_header(&file, path)? != (archives.to_vec(), consolidations.to_vec()) {
+                    return Err(invalid(path, "file has other archives or consolidation functions than configured"));
+                }
+                if file.metadata()?.len() != size {
+                    return Err(invalid(path, "file has the wrong size"));
+                }
+                file
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                Rrd::create(path, &Rrd::header(archives, consolidations), &states, size)?;
+                OpenOptions::new().read(true).write(true).open(path)?
+            }
+            Err(e) => return Err(e),
+        };
+        Rrd::read_states(&file, &mut states)?;
+
+        Ok(Rrd {
+            file,
+            consolidations: consolidations.to_vec(),
+            states,
+        })
+    }
+
+    /// Writes a new file to a temporary file first, so there never is a partial one at `path`
+    fn create(path: &Path, header: &[u8], states: &[State], size: u64) -> io::Result<()> {
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        let file = File::create(&temporary)?;
+        file.write_all_at(header, 0)?;
+
+        // Rows are written out, so the space is allocated right away
+        let nan = f64::NAN.to_bits().to_le_bytes();
+        let rows_offset = states.first().map(|s| s.rows_offset).unwrap_or(size);
+        let chunk = nan.iter().cycle().take(64 * 1024).cloned().collect::<Vec<_>>();
+        let mut offset = rows_offset;
+        while offset < size {
+            let len = chunk.len().min((size - offset) as usize);
+            file.write_all_at(&chunk[..len], offset)?;
+            offset += len as u64;
+        }
+        for state in states {
+            file.write_all_at(&NONE.to_le_bytes(), state.state_offset)?;
+        }
+        file.sync_all()?;
+        fs::rename(&temporary, path)
+    }
+
+    fn write_row(&self, state: &State, step: i64, values: &[f64]) -> io::Result<()> {
+        let row = step.rem_euclid(state.archive.rows as i64) as u64;
+        let bytes = values.iter().flat_map(|v| v.to_bits().to_le_bytes()).collect::<Vec<_>>();
+        self.file.write_all_at(&bytes, state.rows_offset + row * bytes.len() as u64)
+    }
+
+    /// Adds a sample to all archives. Samples older than the step an archive currently
+    /// fills are ignored by it.
+    fn update(&mut self, time: i64, value: f64) -> io::Result<()> {
+        let unknown = vec![f64::NAN; self.consolidations.len()];
+        for index in 0..self.states.len() {
+            let state = &self.states[index];
+            let step = time.div_euclid(state.archive.step);
+            if state.step != NONE && step < state.step {
+                continue;
+            }
+            if state.step != NONE && step > state.step {
+                // Steps without samples are unknown
+                let skipped = (state.step + 1).max(step - state.archive.rows as i64 + 1);
+                for s in skipped..step {
+                    self.write_row(state, s, &unknown)?;
+                }
+            }
+
+            let state = &mut self.states[index];
+            if step != state.step {
+                state.step = step;
+                state.count = 0;
+            }
+            for (c, accumulated) in self.consolidations.iter().zip(&mut state.accumulated) {
+                *accumulated = c.accumulate(*accumulated, state.count, value);
+            }
+            state.count += 1;
+
+            let state = &self.states[index];
+            let values = self.consolidations.iter().zip(&state.accumulated)
+                .map(|(c, &a)| c.value(a, state.count))
+                .collect::<Vec<_>>();
+            self.write_row(state, step, &values)?;
+
+            let mut bytes = state.step.to_le_bytes().to_vec();
+            for a in &state.accumulated {
+                bytes.extend_from_slice(&a.to_bits().to_le_bytes());
+            }
+            bytes.extend_from_slice(&state.count.to_le_bytes());
+            self.file.write_all_at(&bytes, state.state_offset)?;
+        }
+        Ok(())
+    }
+}
+
+/// The values of the file at `path` from `from` to before `to`, as the start of their step and
+/// the value of `consolidation`, or of the first consolidation function of the file if `None`.
+/// Of all archives, the one with the finest steps still reaching back to `from` is used, or
+/// if there is none the one reaching back furthest. Unknown values are left out.
+pub fn read(path: &Path, consolidation: Option<Consolidation>, from: i64, to: i64) -> io::Result<Vec<(i64, f64)>> {
+    let file = File::open(path)?;
+    let (archives, consolidations) = Rrd::read_header(&file, path)?;
+    let index = match consolidation {
+        Some(c) => consolidations.iter().position(|&x| x == c)
+            .ok_or_else(|| invalid(path, &format!("file has no {} values", c.as_str())))?,
+        None if consolidations.is_empty() => return Ok(Vec::new()),
+        None => 0,
+    };
+    let (mut states, size) = Rrd::layout(&archives, &consolidations);
+    if file.metadata()?.len() != size {
+        return Err(invalid(path, "file has the wrong size"));
+    }
+    Rrd::read_states(&file, &mut states)?;
+
+    // The first step still in an archive
+    let oldest = |s: &State| s.step - s.archive.rows as i64 + 1;
+    let states = states.iter().filter(|s| s.step != NONE).collect::<Vec<_>>();
+    let state = match states.iter().filter(|s| oldest(s) * s.archive.step <= from).min_by_key(|s| s.archive.step) {
+        Some(s) => s,
+        None => match states.iter().min_by_key(|s| oldest(s) * s.archive.step) {
+            Some(s) => s,
+            None => return Ok(Vec::new()),
+        },
+    };
+
+    let step = state.archive.step;
+    let from = from.max(oldest(state) * step);
+    // Steps starting at or after `from`
+    let first = (from + step - 1).div_euclid(step);
+    let last = state.step.min((to - 1).div_euclid(step));
+    let row_size = 8 * consolidations.len();
+    let mut values = Vec::new();
+    let mut s = first;
+    while s <= last {
+        // Rows are read in chunks, but not beyond the end of the archive
+        let row = s.rem_euclid(state.archive.rows as i64);
+        let len = (last - s + 1).min(state.archive.rows as i64 - row).min(4096);
+        let mut buf = vec![0; len as usize * row_size];
+        file.read_exact_at(&mut buf, state.rows_offset + row as u64 * row_size as u64)?;
+        for i in 0..len as usize {
+            let value = f64::from_bits(read_u64(&buf, i * row_size + index * 8));
+            if !value.is_nan() {
+                values.push(((s + i as i64) * step, value));
+            }
+        }
+        s += len;
+    }
+    Ok(values)
+}
+
+/// Keeps the numeric values of each key in a round-robin file
+#[derive(Debug)]
+pub struct RrdOutput {
+    directory: PathBuf,
+    archives: Vec<Archive>,
+    consolidations: Vec<Consolidation>,
+    files: BTreeMap<String, Rrd>,
+}
+
+impl RrdOutput {
+    pub fn new(directory: &Path, archives: &[Archive], consolidations: &[Consolidation])
+        -> io::Result<RrdOutput>
+    {
+        fs::create_dir_all(directory)?;
+        Ok(RrdOutput {
+            directory: directory.to_owned(),
+            archives: archives.to_vec(),
+            consolidations: consolidations.to_vec(),
+            files: BTreeMap::new(),
+        })
+    }
+}
+
+impl Output for RrdOutput {
+    fn write(&mut self, sample: &Sample) -> io::Result<()> {
+        let value = match sample.value.trim().parse::<f64>() {
+            Ok(v) if v.is_finite() => v,
+            _ => {
+                debug!("Not writing {} to round-robin file, it is not a number", sample.key);
+                return Ok(());
+            }
+        };
+        if !self.files.contains_key(&sample.key) {
+            if !valid_key(&sample.key) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          format!("invalid key {}", sample.key)));
+            }
+            let path = self.directory.join(format!("{}.rrd", sample.key));
+            let rrd = Rrd::open(&path, &self.archives, &self.consolidations)?;
+            self.files.insert(sample.key.clone(), rrd);
+        }
+        let path = self.directory.join(format!("{}.rrd", sample.key));
+        self.files.get_mut(&sample.key).unwrap().update(sample.time.sec, value)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::FileExt;
+
+    use output::{Output, Sample};
+    use output::rrd::{self, Archive, Consolidation, Rrd, RrdOutput};
+
+    /// The rows of all archives, oldest first
+    fn rows(rrd: &Rrd) -> Vec<Vec<Vec<f64>>> {
+        rrd.states.iter().map(|state| {
+            (0..state.archive.rows as i64).map(|i| {
+                let row = (state.step + 1 + i).rem_euclid(state.archive.rows as i64) as u64;
+                let size = 8 * rrd.consolidations.len();
+                let mut buf = vec![0; size];
+                rrd.file.read_exact_at(&mut buf, state.rows_offset + row * size as u64).unwrap();
+                buf.chunks(8).map(|b| {
+                    let mut bytes = [0; 8];
+                    bytes.copy_from_slice(b);
+                    f64::from_le_bytes(bytes)
+                }).collect()
+            }).collect()
+        }).collect()
+    }
+
+    #[test]
+    fn archives() {
+        assert_eq!(Archive::from_name("10s:1d"), Some(Archive { step: 10, rows: 8640 }));
+        assert_eq!(Archive::from_name("1h:90m"), Some(Archive { step: 3600, rows: 2 }));
+        assert_eq!(Archive::from_name("1h:1m"), None);
+        assert_eq!(Archive::from_name("1h"), None);
+    }
+
+    #[test]
+    fn consolidate() {
+        let dir = env::temp_dir().join("antikoerper-rrd-output");
+        let _ = fs::remove_dir_all(&dir);
+        let archives = [Archive { step: 10, rows: 3 }, Archive { step: 60, rows: 2 }];
+        let consolidations = [Consolidation::Average, Consolidation::Min, Consolidation::Max,
+                              Consolidation::Last];
+
+        let mut output = RrdOutput::new(&dir, &archives, &consolidations).unwrap();
+        for &(time, value) in &[(1454328000, "4\n"), (1454328005, "2\n"), (1454328011, "9\n"),
+                                (1454328001, "1\n"), (1454328031, "charging\n"),
+                                (1454328035, "6\n")] {
+            output.write(&Sample::new("os.battery", time, value)).unwrap();
+        }
+        let size = fs::metadata(dir.join("os.battery.rrd")).unwrap().len();
+        assert_eq!(size, 16 + 4 * 4 + 2 * 16 + 2 * (8 + 4 * 8 + 8) + (3 + 2) * 4 * 8);
+        drop(output);
+
+        // Reopening continues where we left off
+        let mut output = RrdOutput::new(&dir, &archives, &consolidations).unwrap();
+        output.write(&Sample::new("os.battery", 1454328039, "8\n")).unwrap();
+        let rrd = &output.files["os.battery"];
+        let rows = rows(rrd);
+        let known = |row: &Vec<f64>| if row[0].is_nan() { None } else { Some(row.clone()) };
+        assert_eq!(rows[0].iter().map(known).collect::<Vec<_>>(),
+                   vec![Some(vec![9.0, 9.0, 9.0, 9.0]), None, Some(vec![7.0, 6.0, 8.0, 8.0])]);
+        assert_eq!(rows[1].iter().map(known).collect::<Vec<_>>(),
+                   vec![None, Some(vec![5.0, 1.0, 9.0, 8.0])]);
+        assert_eq!(fs::metadata(dir.join("os.battery.rrd")).unwrap().len(), size);
+
+        // Reading back uses the finest archive reaching back far enough
+        let path = dir.join("os.battery.rrd");
+        assert_eq!(rrd::read(&path, None, 0, i64::MAX).unwrap(), vec![(1454328000, 5.0)]);
+        assert_eq!(rrd::read(&path, None, 1454328010, i64::MAX).unwrap(),
+                   vec![(1454328010, 9.0), (1454328030, 7.0)]);
+        assert_eq!(rrd::read(&path, Some(Consolidation::Min), 1454328010, 1454328030).unwrap(),
+                   vec![(1454328010, 9.0)]);
+
+        // Files created for other archives are not touched
+        let mut other = RrdOutput::new(&dir, &archives[..1], &consolidations).unwrap();
+        assert!(other.write(&Sample::new("os.battery", 1454328040, "1")).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,167 @@
+//! A single file with the latest value of every key, for status bars and the like
+//!
+//! The file is replaced as a whole whenever values changed: it is written to a temporary file
+//! next to it, which is then renamed, so readers always see a complete snapshot.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use rustc_serialize::json::Json;
+use time::Timespec;
+
+use output::{Output, Sample};
+use output::format::{self, Timestamp};
+
+/// How the snapshot is written
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SnapshotFormat {
+    /// `{"<key>":{"time":<time>,"value":<value>},...}`, with numeric values as JSON numbers
+    Json,
+    /// A line `<key>=<value>` and a line `<key>@time=<time>` per key, with backslashes,
+    /// newlines and carriage returns in the value escaped
+    KeyValue,
+}
+
+impl SnapshotFormat {
+    pub fn from_name(s: &str) -> Option<SnapshotFormat> {
+        match s {
+            "json" => Some(SnapshotFormat::Json),
+            "kv" => Some(SnapshotFormat::KeyValue),
+            _ => None,
+        }
+    }
+}
+
+/// The snapshot of `latest` in `format`
+fn render(format: SnapshotFormat, timestamp: Timestamp, latest: &BTreeMap<String, (Timespec, String)>) -> String {
+    let mut text = String::new();
+    match format {
+        SnapshotFormat::Json => {
+            text.push('{');
+            for (i, (key, &(time, ref value))) in latest.iter().enumerate() {
+                let time = match timestamp {
+                    Timestamp::Rfc3339 => Json::String(timestamp.format(time)).to_string(),
+                    _ => timestamp.format(time),
+                };
+                let value = if format::is_json_number(value.trim()) {
+                    value.trim().to_owned()
+                } else {
+                    Json::String(value.clone()).to_string()
+                };
+                text.push_str(&format!("{}{}:{{\"time\":{},\"value\":{}}}",
+                                       if i > 0 { "," } else { "" }, Json::String(key.clone()), time, value));
+            }
+            text.push_str("}\n");
+        }
+        SnapshotFormat::KeyValue => {
+            for (key, &(time, ref value)) in latest {
+                let value = value.replace('\\', "\\\\").replace('\n', "\\n").replace('\r', "\\r");
+                text.push_str(&format!("{}={}\n{}@time={}\n", key, value, key, timestamp.format(time)));
+            }
+        }
+    }
+    text
+}
+
+/// Keeps the latest value of every key in a file
+#[derive(Debug)]
+pub struct SnapshotOutput {
+    path: PathBuf,
+    format: SnapshotFormat,
+    timestamp: Timestamp,
+    latest: BTreeMap<String, (Timespec, String)>,
+    /// Whether values changed since the file was written
+    changed: bool,
+}
+
+impl SnapshotOutput {
+    pub fn new(path: &Path, format: SnapshotFormat, timestamp: Timestamp) -> io::Result<SnapshotOutput> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        Ok(SnapshotOutput {
+            path: path.to_owned(),
+            format,
+            timestamp,
+            latest: BTreeMap::new(),
+            changed: false,
+        })
+    }
+}
+
+impl Output for SnapshotOutput {
+    fn write(&mut self, sample: &Sample) -> io::Result<()> {
+        let value = sample.value.trim_end_matches(['\n', '\r']).to_owned();
+        self.latest.insert(sample.key.clone(), (sample.time, value));
+        self.changed = true;
+        Ok(())
+    }
+
+    /// Replaces the file if values changed, this is called after every run of an item
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.changed {
+            return Ok(());
+        }
+        replace(&self.path, render(self.format, self.timestamp, &self.latest).as_bytes())?;
+        self.changed = false;
+        Ok(())
+    }
+}
+
+/// Replaces `path` with `content` through `<path>.tmp`, so that it is either the old or the new
+/// file even if we crash while writing it
+pub fn replace(path: &Path, content: &[u8]) -> io::Result<()> {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".tmp");
+    let tmp = path.with_file_name(name);
+    let mut file = File::create(&tmp)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use output::{Output, Sample};
+    use output::format::Timestamp;
+    use output::snapshot::{SnapshotFormat, SnapshotOutput};
+
+    fn write(output: &mut SnapshotOutput, key: &str, value: &str) {
+        output.write(&Sample::new(key, 1454328000, value)).unwrap();
+    }
+
+    #[test]
+    fn snapshots() {
+        let dir = env::temp_dir().join("antikoerper-snapshot");
+        let _ = fs::remove_dir_all(&dir);
+
+        let path = dir.join("latest.json");
+        let mut json = SnapshotOutput::new(&path, SnapshotFormat::Json, Timestamp::Epoch).unwrap();
+        write(&mut json, "os.battery", "99\n");
+        write(&mut json, "os.state", "Full\n");
+        write(&mut json, "os.battery", "98\n");
+        json.flush().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{\"os.battery\":{\"time\":1454328000,\"value\":98},\
+                                                        \"os.state\":{\"time\":1454328000,\"value\":\"Full\"}}\n");
+        assert!(!dir.join("latest.json.tmp").exists());
+
+        let path = dir.join("latest");
+        let mut kv = SnapshotOutput::new(&path, SnapshotFormat::KeyValue, Timestamp::Rfc3339).unwrap();
+        write(&mut kv, "os.battery", "99\n");
+        write(&mut kv, "os.state", "Fully\ncharged\n");
+        kv.flush().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "\
+os.battery=99
+os.battery@time=2016-02-01T12:00:00.000Z
+os.state=Fully\\ncharged
+os.state@time=2016-02-01T12:00:00.000Z
+");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
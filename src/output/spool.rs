@@ -0,0 +1,393 @@
+//! Keeping the samples of network outputs on disk until they are sent
+//!
+//! Samples are appended to segment files in a directory, named by increasing numbers. A
+//! cursor file remembers up to where samples were sent, segments before it are removed. When
+//! the segments get larger than the limit, the oldest one is dropped. As everything is on
+//! disk, samples not sent when antikoerper stops are sent after it started again.
+//!
+//! A record is its length as u32, then the time as i64 seconds and i32 nanoseconds, the length
+//! of the key as u32, the key and the value, all little endian. A record cut off by a crash
+//! at the end of the last segment is removed when opening.
+
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use time::Timespec;
+
+use output::Sample;
+
+/// Segments are not made larger than this, so dropping one does not lose too much
+const MAX_SEGMENT: u64 = 16 * 1024 * 1024;
+/// Records larger than this are taken as a sign of a damaged segment
+const MAX_RECORD: usize = 16 * 1024 * 1024;
+const EXTENSION: &str = "spool";
+
+fn encode(sample: &Sample) -> Vec<u8> {
+    let len = 16 + sample.key.len() + sample.value.len();
+    let mut record = Vec::with_capacity(4 + len);
+    record.extend_from_slice(&(len as u32).to_le_bytes());
+    record.extend_from_slice(&sample.time.sec.to_le_bytes());
+    record.extend_from_slice(&sample.time.nsec.to_le_bytes());
+    record.extend_from_slice(&(sample.key.len() as u32).to_le_bytes());
+    record.extend_from_slice(sample.key.as_bytes());
+    record.extend_from_slice(sample.value.as_bytes());
+    record
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Reads a record, returns the sample and how many bytes it took. `None` at the end of the
+/// file, an error of kind `UnexpectedEof` if the record is cut off.
+fn read_record<R: Read>(reader: &mut R) -> io::Result<Option<(Sample, u64)>> {
+    let mut len = [0; 4];
+    if reader.read(&mut len[..1])? == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut len[1..])?;
+    let len = u32::from_le_bytes(len) as usize;
+    if !(16..=MAX_RECORD).contains(&len) {
+        return Err(invalid("invalid record length"));
+    }
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body)?;
+
+    let mut sec = [0; 8];
+    let mut nsec = [0; 4];
+    let mut key_len = [0; 4];
+    sec.copy_from_slice(&body[..8]);
+    nsec.copy_from_slice(&body[8..12]);
+    key_len.copy_from_slice(&body[12..16]);
+    let key_len = u32::from_le_bytes(key_len) as usize;
+    if key_len > len - 16 {
+        return Err(invalid("invalid key length"));
+    }
+    let (key, value) = body[16..].split_at(key_len);
+    let sample = Sample {
+        time: Timespec::new(i64::from_le_bytes(sec), i32::from_le_bytes(nsec)),
+        key: String::from_utf8_lossy(key).into_owned(),
+        value: String::from_utf8_lossy(value).into_owned(),
+    };
+    Ok(Some((sample, 4 + len as u64)))
+}
+
+#[derive(Debug)]
+struct Segment {
+    seq: u64,
+    size: u64,
+    /// How many samples in it were not sent yet
+    pending: usize,
+}
+
+/// A position in the spool, a segment and an offset in it
+type Position = (u64, u64);
+
+#[derive(Debug)]
+pub struct Spool {
+    directory: PathBuf,
+    max_size: u64,
+    segment_size: u64,
+    /// Oldest first, samples are appended to the last one
+    segments: VecDeque<Segment>,
+    writer: BufWriter<File>,
+    /// Where the next sample not read yet is, and a reader there if one is open
+    read: Position,
+    reader: Option<BufReader<File>>,
+    /// Samples read but not sent yet, and the position after each of them
+    front: VecDeque<Sample>,
+    positions: VecDeque<Position>,
+    /// Up to where samples were sent
+    cursor: Position,
+    pending: usize,
+}
+
+impl Spool {
+    /// Opens the spool in `directory`, creating it if needed. It is kept below `max_size`
+    /// bytes by dropping the oldest samples.
+    pub fn open(directory: &Path, max_size: u64) -> io::Result<Spool> {
+        fs::create_dir_all(directory)?;
+        let mut seqs = Vec::new();
+        for entry in fs::read_dir(directory)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == EXTENSION) {
+                if let Some(seq) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse::<u64>().ok()) {
+                    seqs.push(seq);
+                }
+            }
+        }
+        seqs.sort_unstable();
+
+        let mut cursor = Spool::read_cursor(directory);
+        // Segments before the cursor were sent, but could not be removed
+        for &seq in seqs.iter().filter(|&&seq| seq < cursor.0) {
+            fs::remove_file(Spool::segment_path(directory, seq))?;
+        }
+        seqs.retain(|&seq| seq >= cursor.0);
+        if seqs.first() != Some(&cursor.0) {
+            cursor = (seqs.first().cloned().unwrap_or(cursor.0), 0);
+        }
+
+        let mut segments = VecDeque::new();
+        for (i, &seq) in seqs.iter().enumerate() {
+            let path = Spool::segment_path(directory, seq);
+            let start = if seq == cursor.0 { cursor.1 } else { 0 };
+            let mut reader = BufReader::new(File::open(&path)?);
+            reader.seek(SeekFrom::Start(start))?;
+            let (mut end, mut pending) = (start, 0);
+            while let Ok(Some((_, len))) = read_record(&mut reader) {
+                end += len;
+                pending += 1;
+            }
+            let mut size = reader.get_ref().metadata()?.len();
+            if i == seqs.len() - 1 && end < size {
+                warn!("Removing {} bytes of a damaged record at the end of {}", size - end, path.display());
+                OpenOptions::new().write(true).open(&path)?.set_len(end)?;
+                size = end;
+            }
+            segments.push_back(Segment { seq, size, pending });
+        }
+        if segments.is_empty() {
+            File::create(Spool::segment_path(directory, cursor.0))?;
+            segments.push_back(Segment { seq: cursor.0, size: 0, pending: 0 });
+        }
+
+        let last = segments.back().unwrap().seq;
+        let writer = OpenOptions::new().append(true).open(Spool::segment_path(directory, last))?;
+        Ok(Spool {
+            directory: directory.to_owned(),
+            max_size,
+            segment_size: (max_size / 8).clamp(1, MAX_SEGMENT),
+            pending: segments.iter().map(|s| s.pending).sum(),
+            segments,
+            writer: BufWriter::new(writer),
+            read: cursor,
+            reader: None,
+            front: VecDeque::new(),
+            positions: VecDeque::new(),
+            cursor,
+        })
+    }
+
+    fn segment_path(directory: &Path, seq: u64) -> PathBuf {
+        directory.join(format!("{:020}.{}", seq, EXTENSION))
+    }
+
+    fn read_cursor(directory: &Path) -> Position {
+        let text = fs::read_to_string(directory.join("cursor")).unwrap_or_default();
+        let mut parts = text.split_whitespace().map(|p| p.parse::<u64>());
+        match (parts.next(), parts.next()) {
+            (Some(Ok(seq)), Some(Ok(offset))) => (seq, offset),
+            _ => (0, 0),
+        }
+    }
+
+    fn write_cursor(&self) -> io::Result<()> {
+        let tmp = self.directory.join("cursor.tmp");
+        fs::write(&tmp, format!("{} {}\n", self.cursor.0, self.cursor.1))?;
+        fs::rename(tmp, self.directory.join("cursor"))
+    }
+
+    /// How many samples were not sent yet
+    pub fn len(&self) -> usize {
+        self.pending
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending == 0
+    }
+
+    fn size(&self) -> u64 {
+        self.segments.iter().map(|s| s.size).sum()
+    }
+
+    /// Appends a sample, returns how many samples were dropped to stay below the limit
+    pub fn push(&mut self, sample: &Sample) -> io::Result<usize> {
+        if self.segments.back().unwrap().size >= self.segment_size {
+            self.writer.flush()?;
+            let seq = self.segments.back().unwrap().seq + 1;
+            self.writer = BufWriter::new(File::create(Spool::segment_path(&self.directory, seq))?);
+            self.segments.push_back(Segment { seq, size: 0, pending: 0 });
+        }
+        let record = encode(sample);
+        self.writer.write_all(&record)?;
+        let last = self.segments.back_mut().unwrap();
+        last.size += record.len() as u64;
+        last.pending += 1;
+        self.pending += 1;
+
+        let mut dropped = 0;
+        while self.size() > self.max_size && self.segments.len() > 1 {
+            dropped += self.drop_oldest()?;
+        }
+        Ok(dropped)
+    }
+
+    /// Removes the oldest segment, returns how many samples in it were not sent
+    fn drop_oldest(&mut self) -> io::Result<usize> {
+        let segment = self.segments.pop_front().unwrap();
+        fs::remove_file(Spool::segment_path(&self.directory, segment.seq))?;
+        self.pending -= segment.pending;
+        while self.positions.front().is_some_and(|p| p.0 == segment.seq) {
+            self.front.pop_front();
+            self.positions.pop_front();
+        }
+        let next = (self.segments[0].seq, 0);
+        if self.read.0 == segment.seq {
+            self.read = next;
+            self.reader = None;
+        }
+        if self.cursor.0 == segment.seq {
+            self.cursor = next;
+            self.write_cursor()?;
+        }
+        Ok(segment.pending)
+    }
+
+    /// Writes out what was pushed
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// The oldest `n` samples not sent yet, fewer if there are not that many
+    pub fn front(&mut self, n: usize) -> io::Result<&[Sample]> {
+        self.writer.flush()?;
+        while self.front.len() < n {
+            if self.reader.is_none() {
+                let mut file = File::open(Spool::segment_path(&self.directory, self.read.0))?;
+                file.seek(SeekFrom::Start(self.read.1))?;
+                self.reader = Some(BufReader::new(file));
+            }
+            match read_record(self.reader.as_mut().unwrap()) {
+                Ok(Some((sample, len))) => {
+                    self.read.1 += len;
+                    self.front.push_back(sample);
+                    self.positions.push_back(self.read);
+                    continue;
+                }
+                Ok(None) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => (),
+                Err(e) => self.skip_damaged(e)?,
+            }
+            // At the end of a segment, go on with the next one if there is one
+            self.reader = None;
+            match self.segments.iter().find(|s| s.seq > self.read.0) {
+                Some(next) => self.read = (next.seq, 0),
+                None => break,
+            }
+        }
+        let n = n.min(self.front.len());
+        Ok(&self.front.make_contiguous()[..n])
+    }
+
+    /// Gives up on the rest of the segment being read, as it is damaged
+    fn skip_damaged(&mut self, e: io::Error) -> io::Result<()> {
+        let seq = self.read.0;
+        let read = self.positions.iter().filter(|p| p.0 == seq).count();
+        let segment = self.segments.iter_mut().find(|s| s.seq == seq).unwrap();
+        warn!("Dropping {} samples in damaged spool segment {}: {}",
+              segment.pending - read, Spool::segment_path(&self.directory, seq).display(), e);
+        self.pending -= segment.pending - read;
+        segment.pending = read;
+        // Nothing may be appended behind the damage, as it could not be read
+        if self.segments.back().unwrap().seq == seq {
+            self.writer.flush()?;
+            let next = seq + 1;
+            self.writer = BufWriter::new(File::create(Spool::segment_path(&self.directory, next))?);
+            self.segments.push_back(Segment { seq: next, size: 0, pending: 0 });
+        }
+        Ok(())
+    }
+
+    /// Removes the oldest `n` samples, as they were sent
+    pub fn pop(&mut self, n: usize) -> io::Result<()> {
+        for _ in 0..n.min(self.front.len()) {
+            self.front.pop_front();
+            let position = self.positions.pop_front().unwrap();
+            if let Some(segment) = self.segments.iter_mut().find(|s| s.seq == position.0) {
+                segment.pending -= 1;
+            }
+            self.pending -= 1;
+            self.cursor = position;
+        }
+        while self.segments.len() > 1 && self.segments[0].seq < self.cursor.0 {
+            let segment = self.segments.pop_front().unwrap();
+            fs::remove_file(Spool::segment_path(&self.directory, segment.seq))?;
+        }
+        self.write_cursor()
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        if let Err(e) = self.writer.flush() {
+            error!("Could not write spool {}: {}", self.directory.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+
+    use output::Sample;
+    use output::spool::Spool;
+
+    fn sample(i: i64) -> Sample {
+        Sample::new("os.battery", 1454328000 + i, &format!("{}\n", i))
+    }
+
+    fn values(samples: &[Sample]) -> Vec<i64> {
+        samples.iter().map(|s| s.time.sec - 1454328000).collect()
+    }
+
+    #[test]
+    fn survives_restarts() {
+        let dir = env::temp_dir().join("antikoerper-spool");
+        let _ = fs::remove_dir_all(&dir);
+        {
+            let mut spool = Spool::open(&dir, 1024 * 1024).unwrap();
+            for i in 0..5 {
+                assert_eq!(spool.push(&sample(i)).unwrap(), 0);
+            }
+            assert_eq!(spool.front(2).unwrap(), &[sample(0), sample(1)]);
+            spool.pop(2).unwrap();
+            // Read, but not sent
+            assert_eq!(values(spool.front(2).unwrap()), [2, 3]);
+        }
+
+        // A record cut off while it was written
+        let segment = dir.join(format!("{:020}.spool", 0));
+        OpenOptions::new().append(true).open(&segment).unwrap().write_all(&[40, 0, 0, 0, 1]).unwrap();
+
+        let mut spool = Spool::open(&dir, 1024 * 1024).unwrap();
+        assert_eq!(spool.len(), 3);
+        spool.push(&sample(5)).unwrap();
+        assert_eq!(values(spool.front(10).unwrap()), [2, 3, 4, 5]);
+        spool.pop(4).unwrap();
+        assert!(spool.is_empty());
+        assert!(spool.front(10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn drops_oldest() {
+        let dir = env::temp_dir().join("antikoerper-spool-full");
+        let _ = fs::remove_dir_all(&dir);
+        // A record takes 32 bytes, so every segment takes one of them and the spool six
+        let mut spool = Spool::open(&dir, 200).unwrap();
+        assert_eq!(values(spool.front(1).unwrap()), []);
+        let dropped = (0..9).map(|i| spool.push(&sample(i)).unwrap()).sum::<usize>();
+        assert_eq!(dropped, 3);
+        assert_eq!(spool.len(), 6);
+        assert_eq!(values(spool.front(10).unwrap()), [3, 4, 5, 6, 7, 8]);
+        spool.pop(3).unwrap();
+        drop(spool);
+
+        let mut spool = Spool::open(&dir, 200).unwrap();
+        assert_eq!(values(spool.front(10).unwrap()), [6, 7, 8]);
+    }
+}
@@ -0,0 +1,335 @@
+//! An output storing all samples in a single SQLite database
+//!
+//! Keys are stored once in the table `keys`, samples refer to them from the table `samples`.
+//! Values that are numbers go into the column `value`, all others into `text`. The view
+//! `readings` joins both tables:
+//!
+//! ```sql
+//! SELECT time, value FROM readings WHERE key = 'os.battery' AND time >= 1454328000;
+//! ```
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use rusqlite::{self, Connection, OpenFlags, OptionalExtension};
+use time::Timespec;
+
+use output::{Output, Sample};
+
+/// The version of the schema, stored in `PRAGMA user_version`
+const SCHEMA_VERSION: i64 = 1;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS keys (
+        id INTEGER PRIMARY KEY,
+        key TEXT NOT NULL UNIQUE
+    );
+    CREATE TABLE IF NOT EXISTS samples (
+        key_id INTEGER NOT NULL REFERENCES keys (id),
+        time INTEGER NOT NULL,
+        value REAL,
+        text TEXT
+    );
+    CREATE INDEX IF NOT EXISTS samples_time ON samples (time);
+    CREATE INDEX IF NOT EXISTS samples_key_time ON samples (key_id, time);
+    CREATE VIEW IF NOT EXISTS readings AS
+        SELECT keys.key AS key, samples.time AS time, samples.value AS value, samples.text AS text
+        FROM samples JOIN keys ON keys.id = samples.key_id;
+";
+
+fn to_io(e: rusqlite::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+/// Writes samples to a SQLite database, several at a time in a transaction
+#[derive(Debug)]
+pub struct SqliteOutput {
+    conn: Connection,
+    /// The ids of the keys in the table `keys`
+    ids: BTreeMap<String, i64>,
+    pending: Vec<Sample>,
+    /// When the oldest pending sample was written
+    since: Option<Instant>,
+    batch_size: usize,
+    batch_interval: Duration,
+    /// How many samples are kept pending at most while they cannot be committed
+    max_queue: usize,
+}
+
+impl SqliteOutput {
+    /// Opens or creates the database at `path`. Samples are committed once `batch_size` of
+    /// them are pending, or when the oldest of them is `batch_interval` old. While committing
+    /// fails the oldest samples are dropped to keep at most `max_queue` of them.
+    pub fn open(path: &Path, batch_size: usize, batch_interval: Duration, max_queue: usize)
+        -> io::Result<SqliteOutput>
+    {
+        if let Some(parent) = path.parent() {
+            ::std::fs::create_dir_all(parent)?;
+        }
+        let conn = Connection::open(path).map_err(to_io)?;
+        conn.busy_timeout(Duration::from_secs(5)).map_err(to_io)?;
+        // Lets others read the database while we write to it
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(())).map_err(to_io)?;
+        let version: i64 = conn.pragma_query_value(None, "user_version", |r| r.get(0)).map_err(to_io)?;
+        if version > SCHEMA_VERSION {
+            return Err(io::Error::other(format!(
+                "{}: database has schema version {}, only up to {} is known",
+                path.display(), version, SCHEMA_VERSION)));
+        }
+        conn.execute_batch(SCHEMA).map_err(to_io)?;
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION).map_err(to_io)?;
+
+        Ok(SqliteOutput {
+            conn,
+            ids: BTreeMap::new(),
+            pending: Vec::new(),
+            since: None,
+            batch_size,
+            batch_interval,
+            max_queue,
+        })
+    }
+
+    /// Inserts all pending samples in a single transaction
+    fn commit(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let tx = self.conn.transaction().map_err(to_io)?;
+        let mut ids = BTreeMap::new();
+        {
+            let mut insert_key = tx.prepare_cached("INSERT OR IGNORE INTO keys (key) VALUES (?1)")
+                .map_err(to_io)?;
+            let mut select_key = tx.prepare_cached("SELECT id FROM keys WHERE key = ?1")
+                .map_err(to_io)?;
+            let mut insert = tx.prepare_cached(
+                "INSERT INTO samples (key_id, time, value, text) VALUES (?1, ?2, ?3, ?4)")
+                .map_err(to_io)?;
+
+            for sample in &self.pending {
+                let id = match self.ids.get(&sample.key).or_else(|| ids.get(&sample.key)) {
+                    Some(&id) => id,
+                    None => {
+                        insert_key.execute([&sample.key]).map_err(to_io)?;
+                        let id = select_key.query_row([&sample.key], |r| r.get(0)).map_err(to_io)?;
+                        ids.insert(sample.key.clone(), id);
+                        id
+                    }
+                };
+                let text = sample.value.trim_end_matches(['\n', '\r']);
+                let value = text.trim().parse::<f64>().ok().filter(|v| v.is_finite());
+                let text = if value.is_some() { None } else { Some(text) };
+                insert.execute(rusqlite::params![id, sample.time.sec, value, text]).map_err(to_io)?;
+            }
+        }
+        tx.commit().map_err(to_io)?;
+        // Only remember ids that made it into the database
+        self.ids.append(&mut ids);
+        self.pending.clear();
+        self.since = None;
+        Ok(())
+    }
+}
+
+impl Output for SqliteOutput {
+    fn write(&mut self, sample: &Sample) -> io::Result<()> {
+        self.pending.push(sample.clone());
+        self.since.get_or_insert_with(Instant::now);
+        let dropped = self.pending.len().saturating_sub(self.max_queue);
+        if dropped > 0 {
+            warn!("Dropping {} samples that could not be written to the database", dropped);
+            self.pending.drain(..dropped);
+        }
+        if self.pending.len() >= self.batch_size {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.since {
+            Some(since) if since.elapsed() >= self.batch_interval => self.commit(),
+            _ => Ok(()),
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.commit()
+    }
+}
+
+impl Drop for SqliteOutput {
+    fn drop(&mut self) {
+        if let Err(e) = self.commit() {
+            error!("Could not write samples to database: {}", e);
+        }
+    }
+}
+
+/// How many samples are read from the database at a time
+const PAGE: i64 = 1000;
+
+/// The samples of a key in a database, read page by page in order of time
+#[derive(Debug)]
+pub struct Readings {
+    conn: Connection,
+    key: String,
+    /// The id of the key, `None` if the database does not know it
+    id: Option<i64>,
+    to: i64,
+    /// The time and row of the last sample read
+    last: (i64, i64),
+    page: VecDeque<Sample>,
+    done: bool,
+}
+
+impl Readings {
+    /// Opens the database at `path` read-only for the samples of `key` from `from` to before `to`
+    pub fn open(path: &Path, key: &str, from: i64, to: i64) -> io::Result<Readings> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(to_io)?;
+        let id = conn.query_row("SELECT id FROM keys WHERE key = ?1", [key], |r| r.get(0))
+            .optional()
+            .map_err(to_io)?;
+        Ok(Readings {
+            conn,
+            key: key.to_owned(),
+            id,
+            to,
+            last: (from, i64::MIN),
+            page: VecDeque::new(),
+            done: id.is_none(),
+        })
+    }
+
+    fn next_page(&mut self) -> io::Result<()> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT time, rowid, value, text FROM samples
+             WHERE key_id = ?1 AND (time > ?2 OR (time = ?2 AND rowid > ?3)) AND time < ?4
+             ORDER BY time, rowid LIMIT ?5")
+            .map_err(to_io)?;
+        let rows = stmt.query_map(rusqlite::params![self.id, self.last.0, self.last.1, self.to, PAGE],
+                                  |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)))
+            .map_err(to_io)?;
+        for row in rows {
+            let (time, rowid, value, text): (i64, i64, Option<f64>, Option<String>) = row.map_err(to_io)?;
+            self.last = (time, rowid);
+            self.page.push_back(Sample {
+                time: Timespec::new(time, 0),
+                key: self.key.clone(),
+                value: match value {
+                    Some(value) => value.to_string(),
+                    None => text.unwrap_or_default(),
+                },
+            });
+        }
+        self.done = self.page.len() < PAGE as usize;
+        Ok(())
+    }
+}
+
+impl Iterator for Readings {
+    type Item = io::Result<Sample>;
+
+    fn next(&mut self) -> Option<io::Result<Sample>> {
+        if self.page.is_empty() && !self.done {
+            if let Err(e) = self.next_page() {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+        self.page.pop_front().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::time::Duration;
+
+    use rusqlite::Connection;
+
+    use output::{Output, Sample};
+    use output::sqlite::{Readings, SqliteOutput};
+
+    #[test]
+    fn batches() {
+        let dir = env::temp_dir().join("antikoerper-sqlite-output");
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("antikoerper.db");
+        let count = || -> i64 {
+            let conn = Connection::open(&path).unwrap();
+            conn.query_row("SELECT count(*) FROM samples", [], |r| r.get(0)).unwrap()
+        };
+
+        let mut output = SqliteOutput::open(&path, 3, Duration::from_secs(3600), 100).unwrap();
+        output.write(&Sample::new("os.battery", 1454328000, "100\n")).unwrap();
+        output.write(&Sample::new("os.state", 1454328000, "Charging\n")).unwrap();
+        output.flush().unwrap();
+        assert_eq!(count(), 0);
+        output.write(&Sample::new("os.battery", 1454328060, "99.5\n")).unwrap();
+        assert_eq!(count(), 3);
+        output.write(&Sample::new("os.battery", 1454328120, "99\n")).unwrap();
+        drop(output);
+        assert_eq!(count(), 4);
+
+        // Reopening keeps the keys
+        let mut output = SqliteOutput::open(&path, 1, Duration::from_secs(3600), 100).unwrap();
+        output.write(&Sample::new("os.state", 1454328060, "Full")).unwrap();
+        drop(output);
+
+        let conn = Connection::open(&path).unwrap();
+        let keys: i64 = conn.query_row("SELECT count(*) FROM keys", [], |r| r.get(0)).unwrap();
+        assert_eq!(keys, 2);
+        let mut stmt = conn.prepare("SELECT key, time, value, text FROM readings ORDER BY time, key")
+            .unwrap();
+        let readings = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)))
+            .unwrap()
+            .collect::<Result<Vec<(String, i64, Option<f64>, Option<String>)>, _>>()
+            .unwrap();
+        assert_eq!(readings, vec![
+            (String::from("os.battery"), 1454328000, Some(100.0), None),
+            (String::from("os.state"), 1454328000, None, Some(String::from("Charging"))),
+            (String::from("os.battery"), 1454328060, Some(99.5), None),
+            (String::from("os.state"), 1454328060, None, Some(String::from("Full"))),
+            (String::from("os.battery"), 1454328120, Some(99.0), None),
+        ]);
+
+        let read = |key: &str, from: i64, to: i64| Readings::open(&path, key, from, to).unwrap()
+            .map(|s| s.map(|s| (s.time.sec, s.value)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(read("os.battery", 1454328060, i64::MAX),
+                   vec![(1454328060, String::from("99.5")), (1454328120, String::from("99"))]);
+        assert_eq!(read("os.state", i64::MIN, 1454328060), vec![(1454328000, String::from("Charging"))]);
+        assert_eq!(read("os.unknown", i64::MIN, i64::MAX), vec![]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn drops_oldest() {
+        let dir = env::temp_dir().join("antikoerper-sqlite-drops");
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("antikoerper.db");
+
+        let mut output = SqliteOutput::open(&path, 1, Duration::from_secs(3600), 2).unwrap();
+        // Committing fails while the table is gone
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch("ALTER TABLE samples RENAME TO hidden").unwrap();
+        for time in 0..4 {
+            assert!(output.write(&Sample::new("os.battery", time, "100")).is_err());
+        }
+        conn.execute_batch("ALTER TABLE hidden RENAME TO samples").unwrap();
+        output.finish().unwrap();
+
+        let mut stmt = conn.prepare("SELECT time FROM samples ORDER BY time").unwrap();
+        let times = stmt.query_map([], |r| r.get(0)).unwrap().collect::<Result<Vec<i64>, _>>().unwrap();
+        assert_eq!(times, vec![2, 3]);
+
+        drop(output);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
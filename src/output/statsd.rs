@@ -0,0 +1,89 @@
+//! Sending samples to StatsD as gauges
+//!
+//! Every sample sets the gauge `<prefix>.<key>` to its value. StatsD reads negative values with
+//! a sign as a change of the gauge, so those are sent as setting it to 0 first. Values that
+//! are not numbers are not sent.
+
+use std::net::UdpSocket;
+
+use output::Sample;
+use output::graphite;
+use output::remote::{self, Failure, Sink};
+
+/// The sample as one or two lines setting a gauge, `None` if its value is not a number
+pub fn gauge(prefix: &str, sample: &Sample) -> Option<String> {
+    let value = sample.value.trim().parse::<f64>().ok().filter(|v| v.is_finite())?;
+    let name = graphite::metric(prefix, &sample.key).replace([':', '|', '@'], "_");
+    Some(if value < 0.0 {
+        format!("{}:0|g\n{}:{}|g\n", name, name, value)
+    } else {
+        format!("{}:{}|g\n", name, value)
+    })
+}
+
+#[derive(Debug)]
+pub struct StatsdSink {
+    address: String,
+    prefix: String,
+    socket: Option<UdpSocket>,
+}
+
+impl StatsdSink {
+    pub fn new(address: String, prefix: String) -> StatsdSink {
+        StatsdSink {
+            address,
+            prefix,
+            socket: None,
+        }
+    }
+}
+
+impl Sink for StatsdSink {
+    fn send(&mut self, samples: &[Sample]) -> Result<(), Failure> {
+        if self.socket.is_none() {
+            self.socket = Some(remote::udp_socket(&self.address)?);
+        }
+        let gauges = samples.iter().filter_map(|s| gauge(&self.prefix, s));
+        remote::send_datagrams(self.socket.as_ref().unwrap(), gauges)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    use output::{Output, Sample};
+    use output::remote::{Delivery, RemoteOutput};
+    use output::statsd::{gauge, StatsdSink};
+
+    fn sample(key: &str, value: &str) -> Sample {
+        Sample::new(key, 1454328000, value)
+    }
+
+    #[test]
+    fn gauges() {
+        assert_eq!(gauge("laptop", &sample("os.battery", "99\n")), Some(String::from("laptop.os.battery:99|g\n")));
+        assert_eq!(gauge("", &sample("temp:out", "-2.5")), Some(String::from("temp_out:0|g\ntemp_out:-2.5|g\n")));
+        assert_eq!(gauge("", &sample("os.state", "Full")), None);
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sink = StatsdSink::new(socket.local_addr().unwrap().to_string(), String::new());
+        let delivery = Delivery {
+            batch_size: 10,
+            batch_interval: Duration::from_secs(3600),
+            max_queue: 10,
+            ..Delivery::default()
+        };
+        let mut output = RemoteOutput::start("statsd", Box::new(sink), delivery).unwrap();
+        output.write(&sample("os.battery", "99")).unwrap();
+        output.write(&sample("os.state", "Full")).unwrap();
+        output.write(&sample("os.load", "0.5")).unwrap();
+        drop(output);
+
+        let mut buf = [0; 1500];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &b"os.battery:99|g\nos.load:0.5|g\n"[..]);
+    }
+}
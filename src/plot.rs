@@ -0,0 +1,318 @@
+//! The `plot` subcommand, drawing the stored values of keys in the terminal
+//!
+//! Charts are drawn with Braille characters, which have 2 by 4 dots each, so a chart has twice
+//! as many points across as it is wide in characters. Values are averaged per point, the same
+//! way `query --aggregate avg --step` does.
+
+use std::env;
+use std::io::{self, BufWriter, IsTerminal, Write};
+use std::slice;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use time::{self, Timespec};
+
+use aggregate::{Buckets, Function};
+use conf::Config;
+use query;
+use series;
+
+/// The colors keys are drawn in, as ANSI codes
+const COLORS: [u8; 6] = [34, 31, 32, 33, 35, 36];
+
+/// How a sparkline shows values, from lowest to highest
+const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// How far back charts go if no time range is given
+const DEFAULT_RANGE: i64 = 24 * 60 * 60;
+
+pub fn subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("plot")
+        .about("Draws the stored values of keys as a chart")
+        .arg(Arg::with_name("key")
+             .value_name("KEY")
+             .help("The keys to draw, all in the same chart")
+             .required(true)
+             .multiple(true))
+        .args(&query::range_args())
+        .arg(Arg::with_name("sparkline")
+             .long("sparkline")
+             .help("Draws a line of bars per key instead of a chart"))
+        .arg(Arg::with_name("width")
+             .long("width")
+             .value_name("COLUMNS")
+             .help("How wide the chart is, by default as wide as the terminal or 80 columns")
+             .takes_value(true))
+        .arg(Arg::with_name("height")
+             .long("height")
+             .value_name("LINES")
+             .help("How high the chart is, without axes")
+             .takes_value(true)
+             .default_value("15"))
+        .arg(Arg::with_name("source")
+             .long("source")
+             .value_name("OUTPUT")
+             .help("The output to read from, by default the first one that can be read back")
+             .takes_value(true))
+}
+
+/// The values of a key averaged per bucket, as the middle of the bucket and the average
+pub type Points = Vec<(i64, f64)>;
+
+/// The values of `key` from `from` to before `to` averaged in buckets of `step` seconds, read
+/// from the output named `source` or the one `series::source` picks
+pub fn points(config: &Config, key: &str, source: Option<&str>, from: i64, to: i64, step: i64)
+              -> Result<Points, String> {
+    let output = series::source(config, key, source)?;
+    let samples = series::read(output, key, from, to)
+        .map_err(|e| format!("Could not read {} from output {}: {}", key, output.name, e))?;
+    Buckets::new(samples, &[Function::Avg], Some(step), from)
+        .map(|b| b.map(|(start, values)| (start + step / 2, values[0])))
+        .collect::<io::Result<Points>>()
+        .map_err(|e| format!("Could not read {} from output {}: {}", key, output.name, e))
+}
+
+/// A grid of Braille characters that dots can be set in, each in a color
+#[derive(Debug)]
+struct Canvas {
+    width: usize,
+    height: usize,
+    /// The dots set in each character, as the bits of its Braille pattern
+    cells: Vec<u8>,
+    /// The color of whatever was drawn last into each character
+    colors: Vec<usize>,
+}
+
+impl Canvas {
+    fn new(width: usize, height: usize) -> Canvas {
+        Canvas {
+            width,
+            height,
+            cells: vec![0; width * height],
+            colors: vec![0; width * height],
+        }
+    }
+
+    /// Sets the dot `x` from the left and `y` from the top
+    fn set(&mut self, x: usize, y: usize, color: usize) {
+        const BITS: [[u8; 4]; 2] = [[0x01, 0x02, 0x04, 0x40], [0x08, 0x10, 0x20, 0x80]];
+        if x >= self.width * 2 || y >= self.height * 4 {
+            return;
+        }
+        let cell = y / 4 * self.width + x / 2;
+        self.cells[cell] |= BITS[x % 2][y % 4];
+        self.colors[cell] = color;
+    }
+
+    /// Sets the dots on the line between two dots
+    fn line(&mut self, (x0, y0): (usize, usize), (x1, y1): (usize, usize), color: usize) {
+        let (x0, y0, x1, y1) = (x0 as i64, y0 as i64, x1 as i64, y1 as i64);
+        let (dx, dy) = ((x1 - x0).abs(), -(y1 - y0).abs());
+        let (sx, sy) = ((x1 - x0).signum(), (y1 - y0).signum());
+        let (mut x, mut y, mut error) = (x0, y0, dx + dy);
+        loop {
+            self.set(x as usize, y as usize, color);
+            if x == x1 && y == y1 {
+                return;
+            }
+            let e2 = 2 * error;
+            if e2 >= dy {
+                error += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                error += dx;
+                y += sy;
+            }
+        }
+    }
+
+    /// The lines of characters, with ANSI colors if `colored`
+    fn lines(&self, colored: bool) -> Vec<String> {
+        self.cells.chunks(self.width).zip(self.colors.chunks(self.width)).map(|(cells, colors)| {
+            let mut line = String::new();
+            for (&cell, &color) in cells.iter().zip(colors) {
+                let c = if cell == 0 { ' ' } else { char::from_u32(0x2800 + cell as u32).unwrap() };
+                if colored && cell != 0 {
+                    line.push_str(&format!("\x1b[{}m{}\x1b[0m", COLORS[color % COLORS.len()], c));
+                } else {
+                    line.push(c);
+                }
+            }
+            line
+        }).collect()
+    }
+}
+
+/// `value` with at most three decimals
+pub fn label(value: f64) -> String {
+    ((value * 1000.0).round() / 1000.0).to_string()
+}
+
+/// The lowest and highest of all values, apart so that they can be scaled between
+pub fn bounds(series: &[Points]) -> Option<(f64, f64)> {
+    let values = series.iter().flat_map(|p| p.iter().map(|&(_, v)| v));
+    let (min, max) = values.fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), v| (min.min(v), max.max(v)));
+    if min > max {
+        return None;
+    }
+    if min == max {
+        let pad = if min == 0.0 { 1.0 } else { min.abs() / 10.0 };
+        return Some((min - pad, max + pad));
+    }
+    Some((min, max))
+}
+
+/// A chart of all `series` from `from` to `to`, `width` characters wide including the axis
+fn chart(keys: &[&str], series: &[Points], from: i64, to: i64, width: usize, height: usize,
+         colored: bool) -> Vec<String> {
+    let (min, max) = match bounds(series) {
+        Some(b) => b,
+        None => return vec![String::from("No values in the time range")],
+    };
+    let labels = [label(max), label((min + max) / 2.0), label(min)];
+    let label_width = labels.iter().map(String::len).max().unwrap_or(0);
+    let columns = width.saturating_sub(label_width + 2).max(1);
+
+    let mut canvas = Canvas::new(columns, height);
+    let (dots_x, dots_y) = ((columns * 2 - 1) as f64, (height * 4 - 1) as f64);
+    for (color, points) in series.iter().enumerate() {
+        let dots = points.iter().map(|&(time, value)| {
+            let x = (time - from) as f64 / (to - from) as f64 * dots_x;
+            let y = (max - value) / (max - min) * dots_y;
+            (x.round().clamp(0.0, dots_x) as usize, y.round().clamp(0.0, dots_y) as usize)
+        }).collect::<Vec<_>>();
+        match dots.len() {
+            1 => canvas.set(dots[0].0, dots[0].1, color),
+            _ => for pair in dots.windows(2) {
+                canvas.line(pair[0], pair[1], color);
+            },
+        }
+    }
+
+    let mut lines = Vec::new();
+    for (i, line) in canvas.lines(colored).into_iter().enumerate() {
+        let label = if i == 0 {
+            &labels[0][..]
+        } else if i == height / 2 && height > 2 {
+            &labels[1][..]
+        } else if i == height - 1 {
+            &labels[2][..]
+        } else {
+            ""
+        };
+        let axis = if label.is_empty() { '│' } else { '┤' };
+        lines.push(format!("{:>w$} {}{}", label, axis, line, w = label_width));
+    }
+    lines.push(format!("{:w$} └{}", "", "─".repeat(columns), w = label_width));
+
+    let (start, end) = (local(from), local(to));
+    let gap = columns.saturating_sub(start.len() + end.len()).max(1);
+    lines.push(format!("{:w$}  {}{}{}", "", start, " ".repeat(gap), end, w = label_width));
+
+    let legend = keys.iter().enumerate().map(|(color, key)| {
+        if colored {
+            format!("\x1b[{}m⣿\x1b[0m {}", COLORS[color % COLORS.len()], key)
+        } else {
+            format!("⣿ {}", key)
+        }
+    }).collect::<Vec<_>>();
+    lines.push(format!("{:w$}  {}", "", legend.join("   "), w = label_width));
+    lines
+}
+
+/// A line of bars per key, each `width` characters wide including the key and the bounds
+fn sparklines(keys: &[&str], series: &[Points], from: i64, to: i64, width: usize) -> Vec<String> {
+    let key_width = keys.iter().map(|k| k.chars().count()).max().unwrap_or(0);
+    keys.iter().zip(series).map(|(key, points)| {
+        let (min, max) = match bounds(slice::from_ref(points)) {
+            Some(b) => b,
+            None => return format!("{:w$}  no values", key, w = key_width),
+        };
+        let real = points.iter().fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), &(_, v)| (lo.min(v), hi.max(v)));
+        let bounds = format!("{} .. {}", label(real.0), label(real.1));
+        let columns = width.saturating_sub(key_width + bounds.len() + 4).max(1);
+
+        let mut bars = vec![None; columns];
+        for &(time, value) in points {
+            let x = ((time - from) as f64 / (to - from) as f64 * (columns - 1) as f64).round() as usize;
+            let bar = ((value - min) / (max - min) * (BARS.len() - 1) as f64).round() as usize;
+            bars[x.min(columns - 1)] = Some(BARS[bar.min(BARS.len() - 1)]);
+        }
+        let bars = bars.into_iter().map(|b| b.unwrap_or(' ')).collect::<String>();
+        format!("{:w$}  {}  {}", key, bars, bounds, w = key_width)
+    }).collect()
+}
+
+/// `sec` seconds since the epoch in local time, without seconds
+pub fn local(sec: i64) -> String {
+    time::at(Timespec::new(sec, 0)).strftime("%Y-%m-%d %H:%M").unwrap().to_string()
+}
+
+pub fn run(config: &Config, matches: &ArgMatches) -> Result<(), String> {
+    let keys = matches.values_of("key").unwrap().collect::<Vec<_>>();
+    let now = time::get_time().sec;
+    let (from, to) = query::range(matches, now)?;
+    let to = to.min(now);
+    let from = if from == i64::MIN { to - DEFAULT_RANGE } else { from };
+    if from >= to {
+        return Err(String::from("The time range is empty"));
+    }
+
+    let width = match matches.value_of("width") {
+        Some(w) => w.parse().ok().filter(|&w| w > 10).ok_or_else(|| format!("Invalid width: {}", w))?,
+        None => env::var("COLUMNS").ok().and_then(|c| c.parse().ok()).unwrap_or(80),
+    };
+    let height = matches.value_of("height").unwrap().parse::<usize>().ok().filter(|&h| h > 0)
+        .ok_or_else(|| String::from("Invalid height"))?;
+    let sparkline = matches.is_present("sparkline");
+
+    // Twice as many points as columns fit in a chart, as a character is two dots wide
+    let count = if sparkline { width } else { width * 2 };
+    let step = ((to - from) as f64 / count as f64).ceil().max(1.0) as i64;
+    let series = keys.iter()
+        .map(|key| points(config, key, matches.value_of("source"), from, to, step))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let lines = if sparkline {
+        sparklines(&keys, &series, from, to, width)
+    } else {
+        chart(&keys, &series, from, to, width, height, io::stdout().is_terminal())
+    };
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let written = lines.iter().try_for_each(|line| writeln!(out, "{}", line)).and_then(|_| out.flush());
+    match written {
+        Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        Err(e) => Err(format!("Could not draw chart: {}", e)),
+        Ok(()) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use plot::{chart, sparklines, Canvas};
+
+    #[test]
+    fn canvas() {
+        let mut canvas = Canvas::new(3, 1);
+        canvas.line((0, 3), (5, 0), 0);
+        assert_eq!(canvas.lines(false), vec!["⡠⠔⠊"]);
+        assert_eq!(canvas.lines(true)[0], "\x1b[34m⡠\x1b[0m\x1b[34m⠔\x1b[0m\x1b[34m⠊\x1b[0m");
+    }
+
+    #[test]
+    fn charts() {
+        let series = vec![vec![(0, 1.0), (50, 3.0), (100, 2.0)]];
+        let lines = chart(&["os.usage"], &series, 0, 100, 16, 2, false);
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].starts_with("3 ┤"));
+        assert!(lines[1].starts_with("1 ┤"));
+        assert_eq!(lines[2], "  └".to_owned() + &"─".repeat(13));
+        assert!(lines[4].ends_with("⣿ os.usage"));
+        assert_eq!(chart(&["os.usage"], &[vec![]], 0, 100, 16, 2, false),
+                   vec![String::from("No values in the time range")]);
+
+        let lines = sparklines(&["a", "bc"], &[vec![(0, 1.0), (50, 8.0), (100, 4.5)], vec![]], 0, 100, 16);
+        assert_eq!(lines, vec![String::from("a   ▁ █▅  1 .. 8"), String::from("bc  no values")]);
+    }
+}
@@ -0,0 +1,210 @@
+//! The `query` subcommand, printing the stored values of a key for a time range
+
+use std::io::{self, BufWriter, Write};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use time::{self, Timespec};
+
+use aggregate::{Buckets, Function};
+use conf::Config;
+use output::Sample;
+use output::format::{Format, Timestamp};
+use series::{self, Series};
+use units;
+
+/// The arguments selecting a time range, shared by all subcommands reading series
+pub fn range_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("since")
+            .long("since")
+            .value_name("DURATION")
+            .help("Only values of the last DURATION, like 2h or 7d")
+            .takes_value(true)
+            .conflicts_with("from"),
+        Arg::with_name("from")
+            .long("from")
+            .value_name("TIME")
+            .help("Only values from TIME on, like 2016-02-01 12:00:00 or 1454328000")
+            .takes_value(true),
+        Arg::with_name("to")
+            .long("to")
+            .value_name("TIME")
+            .help("Only values from before TIME")
+            .takes_value(true),
+    ]
+}
+
+/// The time range given by the arguments of `range_args`, everything if none are given
+pub fn range(matches: &ArgMatches, now: i64) -> Result<(i64, i64), String> {
+    parse_range(matches.value_of("since"), matches.value_of("from"), matches.value_of("to"), now)
+}
+
+/// The time range of the last `since`, or from `from` to before `to`, unbounded where they are
+/// not given
+pub fn parse_range(since: Option<&str>, from: Option<&str>, to: Option<&str>, now: i64) -> Result<(i64, i64), String> {
+    let time = |name: &str, value: Option<&str>, default: i64| match value {
+        Some(s) => units::parse_time(s, now).ok_or_else(|| format!("Invalid time for {}: {}", name, s)),
+        None => Ok(default),
+    };
+    let from = match since {
+        Some(s) => now - units::parse_duration(s).ok_or_else(|| format!("Invalid duration for since: {}", s))?,
+        None => time("from", from, i64::MIN)?,
+    };
+    let to = time("to", to, i64::MAX)?;
+    if from >= to {
+        return Err(String::from("The time range is empty"));
+    }
+    Ok((from, to))
+}
+
+pub fn subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("query")
+        .about("Prints the stored values of a key")
+        .arg(Arg::with_name("key")
+             .value_name("KEY")
+             .help("The key to print the values of")
+             .required(true))
+        .args(&range_args())
+        .arg(Arg::with_name("aggregate")
+             .long("aggregate")
+             .value_name("FUNCTIONS")
+             .help("Prints min, max, avg, sum, count, median or percentiles like p95 of numeric values \
+                    instead of the values, separated by commas")
+             .takes_value(true)
+             .use_delimiter(true))
+        .arg(Arg::with_name("step")
+             .long("step")
+             .value_name("DURATION")
+             .help("Aggregates values in buckets of DURATION, like 5m, instead of all at once")
+             .takes_value(true)
+             .requires("aggregate"))
+        .arg(Arg::with_name("format")
+             .long("format")
+             .value_name("FORMAT")
+             .help("How values are printed")
+             .possible_values(&["table", "csv", "json"])
+             .default_value("table"))
+        .arg(Arg::with_name("source")
+             .long("source")
+             .value_name("OUTPUT")
+             .help("The output to read from, by default the first one that can be read back")
+             .takes_value(true))
+}
+
+/// Prints the samples of `series` in `format` to `out`
+pub fn print(out: &mut dyn Write, format: &str, series: Series) -> io::Result<()> {
+    match format {
+        "csv" => out.write_all(b"time,value\n")?,
+        "json" => out.write_all(b"[")?,
+        _ => {}
+    }
+    for (i, sample) in series.enumerate() {
+        let sample = sample?;
+        match format {
+            "csv" => out.write_all(Format::Csv.record(Timestamp::Epoch, &sample).as_bytes())?,
+            "json" => {
+                let record = Format::JsonLines.record(Timestamp::Epoch, &sample);
+                write!(out, "{}\n{}", if i > 0 { "," } else { "" }, record.trim_end())?;
+            }
+            _ => writeln!(out, "{}", row(&sample))?,
+        }
+    }
+    if format == "json" {
+        out.write_all(b"\n]\n")?;
+    }
+    Ok(())
+}
+
+/// Prints the aggregated values of `buckets` in `format` to `out`
+pub fn print_buckets(out: &mut dyn Write, format: &str, functions: &[Function], buckets: Buckets) -> io::Result<()> {
+    let names = functions.iter().map(Function::name).collect::<Vec<_>>();
+    match format {
+        "csv" => writeln!(out, "time,{}", names.join(","))?,
+        "json" => out.write_all(b"[")?,
+        _ => {
+            let names = names.iter().map(|n| format!("{:>12}", n)).collect::<String>();
+            writeln!(out, "{:19}{}", "time", names)?;
+        }
+    }
+    for (i, bucket) in buckets.enumerate() {
+        let (start, values) = bucket?;
+        match format {
+            "csv" => {
+                let values = values.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+                writeln!(out, "{},{}", start, values.join(","))?;
+            }
+            "json" => {
+                let fields = names.iter().zip(&values)
+                    .map(|(name, value)| format!(",\"{}\":{}", name, json_number(*value)))
+                    .collect::<String>();
+                write!(out, "{}\n{{\"time\":{}{}}}", if i > 0 { "," } else { "" }, start, fields)?;
+            }
+            _ => {
+                let values = values.iter().map(|v| format!("{:>12}", round(*v))).collect::<String>();
+                writeln!(out, "{}{}", local(start), values)?;
+            }
+        }
+    }
+    if format == "json" {
+        out.write_all(b"\n]\n")?;
+    }
+    Ok(())
+}
+
+/// `value` as a JSON number, `null` if it is none
+fn json_number(value: f64) -> String {
+    if value.is_finite() {
+        value.to_string()
+    } else {
+        String::from("null")
+    }
+}
+
+/// `value` with at most three decimals, for tables
+fn round(value: f64) -> f64 {
+    (value * 1000.0).round() / 1000.0
+}
+
+/// `sec` seconds since the epoch in local time
+fn local(sec: i64) -> String {
+    time::at(Timespec::new(sec, 0)).strftime("%Y-%m-%d %H:%M:%S").unwrap().to_string()
+}
+
+/// A line of the table, the local time and the value
+fn row(sample: &Sample) -> String {
+    let value = sample.value.trim_end_matches(['\n', '\r']).replace('\n', "\\n").replace('\r', "\\r");
+    format!("{}  {}", local(sample.time.sec), value)
+}
+
+pub fn run(config: &Config, matches: &ArgMatches) -> Result<(), String> {
+    let key = matches.value_of("key").unwrap();
+    let (from, to) = range(matches, time::get_time().sec)?;
+    let functions = match matches.values_of("aggregate") {
+        Some(names) => names.map(|n| Function::from_name(n).ok_or_else(|| format!("Unknown aggregate function: {}", n)))
+            .collect::<Result<Vec<_>, _>>()?,
+        None => Vec::new(),
+    };
+    let step = match matches.value_of("step") {
+        Some(s) => Some(units::parse_duration(s).filter(|&d| d > 0)
+                        .ok_or_else(|| format!("Invalid duration for --step: {}", s))?),
+        None => None,
+    };
+    let output = series::source(config, key, matches.value_of("source"))?;
+    let series = series::read(output, key, from, to)
+        .map_err(|e| format!("Could not read {} from output {}: {}", key, output.name, e))?;
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let format = matches.value_of("format").unwrap();
+    let printed = if functions.is_empty() {
+        print(&mut out, format, series)
+    } else {
+        print_buckets(&mut out, format, &functions, Buckets::new(series, &functions, step, from))
+    };
+    match printed.and_then(|_| out.flush()) {
+        // Whoever reads our output, like head, has seen enough
+        Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        Err(e) => Err(format!("Could not read {} from output {}: {}", key, output.name, e)),
+        Ok(()) => Ok(()),
+    }
+}
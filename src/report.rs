@@ -0,0 +1,420 @@
+//! The `report` subcommand, writing self-contained HTML pages with charts of stored values
+//!
+//! Reports are configured in the `reports` section. A page has no scripts and loads nothing,
+//! its charts are inline SVG, so it can be opened anywhere and sent around as a single file.
+
+use std::error::Error;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use time;
+use toml;
+
+use aggregate::{Buckets, Function};
+use conf::{Config, General};
+use output::snapshot;
+use item::valid_key;
+use plot::{self, Points};
+use series;
+use units;
+
+/// How many points a chart has at most if its step is not given
+const POINTS: i64 = 300;
+
+/// The colors keys are drawn in
+const COLORS: [&str; 6] = ["#1f77b4", "#d62728", "#2ca02c", "#ff7f0e", "#9467bd", "#17becf"];
+
+/// The size of a chart and the space around its plot for labels
+const WIDTH: f64 = 800.0;
+const HEIGHT: f64 = 240.0;
+const LEFT: f64 = 60.0;
+const RIGHT: f64 = 10.0;
+const TOP: f64 = 10.0;
+const BOTTOM: f64 = 30.0;
+
+const STYLE: &str = "
+body { font-family: sans-serif; max-width: 820px; margin: 2em auto; color: #222; }
+h2 { margin-bottom: 0.2em; }
+.range { color: #666; margin-top: 0; }
+svg text { font-size: 11px; fill: #666; }
+svg .grid { stroke: #ddd; }
+table { border-collapse: collapse; margin: 0.5em 0 2em; }
+th, td { padding: 0.2em 0.8em; text-align: right; }
+th:first-child, td:first-child { text-align: left; }
+.key { display: inline-block; width: 0.8em; height: 0.8em; margin-right: 0.4em; }
+";
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum ReportErrorKind {
+    MissingName,
+    InvalidTitle,
+    InvalidPath,
+    InvalidSince,
+    MissingCharts,
+    InvalidKeys,
+    InvalidStep,
+    InvalidSource,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ReportError {
+    name: String,
+    kind: ReportErrorKind,
+}
+
+impl ReportError {
+    fn new(name: String, k: ReportErrorKind) -> ReportError {
+        ReportError {
+            name,
+            kind: k,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        match self.kind {
+            ReportErrorKind::MissingName   => "missing name field",
+            ReportErrorKind::InvalidTitle  => "title has to be a string",
+            ReportErrorKind::InvalidPath   => "path has to be a path",
+            ReportErrorKind::InvalidSince  => "since has to be a positive number of seconds or a duration like 1d",
+            ReportErrorKind::MissingCharts => "charts has to be a non-empty array of tables",
+            ReportErrorKind::InvalidKeys   => "keys of a chart has to be a non-empty array of keys",
+            ReportErrorKind::InvalidStep   => "step has to be a positive number of seconds or a duration like 5m",
+            ReportErrorKind::InvalidSource => "source has to be the name of an output",
+        }
+    }
+}
+
+impl Error for ReportError {
+    fn description(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl ::std::fmt::Display for ReportError {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> Result<(), ::std::fmt::Error> {
+        write!(f, "report {}: {}", self.name, self.as_str())
+    }
+}
+
+/// A chart of a report, with all its keys drawn together
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ChartConfig {
+    pub title: String,
+    pub keys: Vec<String>,
+    /// How far back the chart goes, in seconds
+    pub since: i64,
+    /// How many seconds of values are averaged into a point, by default so that there are
+    /// about 300 points
+    pub step: Option<i64>,
+    /// The name of the output values are read from, by default the one `query` would use
+    pub source: Option<String>,
+}
+
+/// A configured report
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ReportConfig {
+    pub name: String,
+    pub title: String,
+    /// Where the page is written to
+    pub path: PathBuf,
+    pub charts: Vec<ChartConfig>,
+}
+
+impl ReportConfig {
+    pub fn from_toml(table: &toml::Table, general: &General) -> Result<ReportConfig, ReportError> {
+        let name = match table.get("name") {
+            Some(toml::Value::String(s)) => s.clone(),
+            _ => return Err(ReportError::new(String::new(), ReportErrorKind::MissingName)),
+        };
+        let error = |kind| ReportError::new(name.clone(), kind);
+
+        let title = match table.get("title") {
+            Some(toml::Value::String(s)) => s.clone(),
+            Some(_) => return Err(error(ReportErrorKind::InvalidTitle)),
+            None => name.clone(),
+        };
+        // Relative paths are relative to general.output, the default directory starts with a dot
+        // like no key does, so it never takes the place of the file of a key
+        let path = match table.get("path") {
+            Some(toml::Value::String(s)) => general.output.join(s),
+            Some(_) => return Err(error(ReportErrorKind::InvalidPath)),
+            None => general.output.join(".reports").join(format!("{}.html", name)),
+        };
+        let since = match table.get("since") {
+            Some(v) => units::duration_from_toml(v).ok_or_else(|| error(ReportErrorKind::InvalidSince))?,
+            None => 24 * 60 * 60,
+        };
+
+        let tables = match table.get("charts") {
+            Some(toml::Value::Array(a)) if !a.is_empty() => a,
+            _ => return Err(error(ReportErrorKind::MissingCharts)),
+        };
+        let mut charts = Vec::with_capacity(tables.len());
+        for chart in tables {
+            let chart = match *chart {
+                toml::Value::Table(ref t) => t,
+                _ => return Err(error(ReportErrorKind::MissingCharts)),
+            };
+            let keys = match chart.get("keys") {
+                Some(toml::Value::Array(a)) if !a.is_empty() => a.iter().map(|k| match *k {
+                    toml::Value::String(ref s) if valid_key(s) => Some(s.clone()),
+                    _ => None,
+                }).collect::<Option<Vec<_>>>(),
+                _ => None,
+            }.ok_or_else(|| error(ReportErrorKind::InvalidKeys))?;
+            charts.push(ChartConfig {
+                title: match chart.get("title") {
+                    Some(toml::Value::String(s)) => s.clone(),
+                    Some(_) => return Err(error(ReportErrorKind::InvalidTitle)),
+                    None => keys.join(", "),
+                },
+                keys,
+                since: match chart.get("since") {
+                    Some(v) => units::duration_from_toml(v).ok_or_else(|| error(ReportErrorKind::InvalidSince))?,
+                    None => since,
+                },
+                step: match chart.get("step") {
+                    Some(v) => Some(units::duration_from_toml(v).ok_or_else(|| error(ReportErrorKind::InvalidStep))?),
+                    None => None,
+                },
+                source: match chart.get("source") {
+                    Some(toml::Value::String(s)) => Some(s.clone()),
+                    Some(_) => return Err(error(ReportErrorKind::InvalidSource)),
+                    None => None,
+                },
+            });
+        }
+
+        Ok(ReportConfig {
+            name,
+            title,
+            path,
+            charts,
+        })
+    }
+}
+
+/// The lowest, average and highest value of a key in a chart, and how many values there were
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Summary {
+    min: f64,
+    avg: f64,
+    max: f64,
+    count: u64,
+}
+
+/// What a chart shows
+#[derive(Debug, Clone, PartialEq)]
+struct Chart {
+    from: i64,
+    to: i64,
+    /// The averaged values of each key
+    series: Vec<Points>,
+    summaries: Vec<Option<Summary>>,
+}
+
+/// Reads the values of the keys of `chart` up to `to`
+fn read(config: &Config, chart: &ChartConfig, to: i64) -> Result<Chart, String> {
+    let from = to - chart.since;
+    let step = chart.step.unwrap_or_else(|| (chart.since + POINTS - 1) / POINTS).max(1);
+    let functions = [Function::Avg, Function::Min, Function::Max, Function::Count];
+
+    let mut series = Vec::with_capacity(chart.keys.len());
+    let mut summaries = Vec::with_capacity(chart.keys.len());
+    for key in &chart.keys {
+        let output = series::source(config, key, chart.source.as_ref().map(|s| &s[..]))?;
+        let samples = series::read(output, key, from, to)
+            .map_err(|e| format!("Could not read {} from output {}: {}", key, output.name, e))?;
+        let mut points = Vec::new();
+        let mut summary: Option<Summary> = None;
+        for bucket in Buckets::new(samples, &functions, Some(step), from) {
+            let (start, values) = bucket
+                .map_err(|e| format!("Could not read {} from output {}: {}", key, output.name, e))?;
+            let (avg, min, max, count) = (values[0], values[1], values[2], values[3] as u64);
+            points.push((start + step / 2, avg));
+            summary = Some(match summary {
+                Some(s) => Summary {
+                    min: s.min.min(min),
+                    avg: (s.avg * s.count as f64 + avg * count as f64) / (s.count + count) as f64,
+                    max: s.max.max(max),
+                    count: s.count + count,
+                },
+                None => Summary { min, avg, max, count },
+            });
+        }
+        series.push(points);
+        summaries.push(summary);
+    }
+    Ok(Chart { from, to, series, summaries })
+}
+
+/// `s` with the characters HTML treats specially escaped
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// The SVG of a chart of `series` from `from` to `to`, one line per key
+fn svg(series: &[Points], from: i64, to: i64) -> String {
+    let mut svg = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {} {}\" width=\"100%\">\n",
+                          WIDTH, HEIGHT);
+    let (width, height) = (WIDTH - LEFT - RIGHT, HEIGHT - TOP - BOTTOM);
+    let (min, max) = match plot::bounds(series) {
+        Some(b) => b,
+        None => {
+            svg.push_str(&format!("<text x=\"{}\" y=\"{}\" text-anchor=\"middle\">No values in the time range</text>\n",
+                                  WIDTH / 2.0, HEIGHT / 2.0));
+            svg.push_str("</svg>");
+            return svg;
+        }
+    };
+
+    for i in 0..5 {
+        let y = TOP + height - height * i as f64 / 4.0;
+        svg.push_str(&format!("<line class=\"grid\" x1=\"{}\" y1=\"{:.1}\" x2=\"{}\" y2=\"{:.1}\"/>\n",
+                              LEFT, y, WIDTH - RIGHT, y));
+        svg.push_str(&format!("<text x=\"{}\" y=\"{:.1}\" text-anchor=\"end\">{}</text>\n",
+                              LEFT - 6.0, y + 4.0, plot::label(min + (max - min) * i as f64 / 4.0)));
+    }
+    for &(time, anchor) in &[(from, "start"), ((from + to) / 2, "middle"), (to, "end")] {
+        let x = LEFT + (time - from) as f64 / (to - from) as f64 * width;
+        svg.push_str(&format!("<text x=\"{:.1}\" y=\"{}\" text-anchor=\"{}\">{}</text>\n",
+                              x, HEIGHT - 10.0, anchor, plot::local(time)));
+    }
+
+    for (i, points) in series.iter().enumerate().filter(|&(_, p)| !p.is_empty()) {
+        let coordinates = points.iter().map(|&(time, value)| {
+            let x = LEFT + (time - from) as f64 / (to - from) as f64 * width;
+            let y = TOP + (max - value) / (max - min) * height;
+            format!("{:.1},{:.1}", x, y)
+        }).collect::<Vec<_>>();
+        svg.push_str(&format!("<polyline fill=\"none\" stroke=\"{}\" stroke-width=\"1.5\" points=\"{}\"/>\n",
+                              COLORS[i % COLORS.len()], coordinates.join(" ")));
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+/// The page of `report` with what its charts show, generated at `now`
+fn html(report: &ReportConfig, charts: &[Chart], now: i64) -> String {
+    let mut html = format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n\
+                            <style>{}</style>\n</head>\n<body>\n<h1>{}</h1>\n<p class=\"range\">Generated {}</p>\n",
+                           escape(&report.title), STYLE, escape(&report.title), plot::local(now));
+    for (config, chart) in report.charts.iter().zip(charts) {
+        html.push_str(&format!("<section>\n<h2>{}</h2>\n<p class=\"range\">{} to {}</p>\n",
+                               escape(&config.title), plot::local(chart.from), plot::local(chart.to)));
+        html.push_str(&svg(&chart.series, chart.from, chart.to));
+        html.push_str("\n<table>\n<tr><th>Key</th><th>Min</th><th>Avg</th><th>Max</th><th>Values</th></tr>\n");
+        for (i, (key, summary)) in config.keys.iter().zip(&chart.summaries).enumerate() {
+            let key = format!("<span class=\"key\" style=\"background: {}\"></span>{}",
+                              COLORS[i % COLORS.len()], escape(key));
+            html.push_str(&match *summary {
+                Some(s) => format!("<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                                   key, plot::label(s.min), plot::label(s.avg), plot::label(s.max), s.count),
+                None => format!("<tr><td>{}</td><td colspan=\"4\">no values</td></tr>\n", key),
+            });
+        }
+        html.push_str("</table>\n</section>\n");
+    }
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+/// Writes `content` to `path`, replacing it only once it is complete
+fn write(path: &Path, content: &str) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    snapshot::replace(path, content.as_bytes())
+}
+
+pub fn subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("report")
+        .about("Writes the configured reports as HTML pages")
+        .arg(Arg::with_name("name")
+             .value_name("NAME")
+             .help("The reports to write, all if none are given")
+             .multiple(true))
+        .arg(Arg::with_name("to")
+             .long("to")
+             .value_name("TIME")
+             .help("Where the time ranges of the charts end instead of now, like 2016-02-02")
+             .takes_value(true))
+        .arg(Arg::with_name("file")
+             .long("file")
+             .value_name("FILE")
+             .help("Writes the report to FILE instead of its configured path, - for stdout")
+             .takes_value(true))
+}
+
+pub fn run(config: &Config, matches: &ArgMatches) -> Result<(), String> {
+    let reports = match matches.values_of("name") {
+        Some(names) => names.map(|n| config.reports.iter().find(|r| r.name == n)
+                                 .ok_or_else(|| format!("There is no report named {}", n)))
+            .collect::<Result<Vec<_>, _>>()?,
+        None => config.reports.iter().collect(),
+    };
+    if reports.is_empty() {
+        return Err(String::from("There are no reports in the configuration"));
+    }
+    let file = matches.value_of("file");
+    if file.is_some() && reports.len() > 1 {
+        return Err(String::from("--file can only be used with a single report"));
+    }
+
+    let now = time::get_time().sec;
+    let to = match matches.value_of("to") {
+        Some(s) => units::parse_time(s, now).ok_or_else(|| format!("Invalid time for --to: {}", s))?,
+        None => now,
+    };
+    for report in reports {
+        let charts = report.charts.iter().map(|c| read(config, c, to)).collect::<Result<Vec<_>, _>>()?;
+        let page = html(report, &charts, now);
+        let written = match file {
+            Some("-") => io::stdout().write_all(page.as_bytes()),
+            Some(path) => write(Path::new(path), &page),
+            None => write(&report.path, &page),
+        };
+        written.map_err(|e| format!("Could not write report {}: {}", report.name, e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use report::{html, svg, Chart, ChartConfig, ReportConfig, Summary};
+
+    #[test]
+    fn pages() {
+        let report = ReportConfig {
+            name: String::from("daily"),
+            title: String::from("Battery & load"),
+            path: PathBuf::from("/tmp/daily.html"),
+            charts: vec![ChartConfig {
+                title: String::from("<Battery>"),
+                keys: vec![String::from("os.battery"), String::from("os.usage")],
+                since: 100,
+                step: None,
+                source: None,
+            }],
+        };
+        let chart = Chart {
+            from: 1454328000,
+            to: 1454328100,
+            series: vec![vec![(1454328000, 100.0), (1454328100, 50.0)], vec![]],
+            summaries: vec![Some(Summary { min: 50.0, avg: 75.0, max: 100.0, count: 2 }), None],
+        };
+        let page = html(&report, &[chart], 1454328100);
+        assert!(page.contains("<title>Battery &amp; load</title>"));
+        assert!(page.contains("<h2>&lt;Battery&gt;</h2>"));
+        assert!(page.contains("points=\"60.0,10.0 790.0,210.0\""));
+        assert!(page.contains("os.battery</td><td>50</td><td>75</td><td>100</td><td>2</td>"));
+        assert!(page.contains("os.usage</td><td colspan=\"4\">no values</td>"));
+        assert!(!page.contains("<script"));
+
+        assert!(svg(&[vec![]], 0, 100).contains("No values in the time range"));
+    }
+}
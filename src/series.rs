@@ -0,0 +1,168 @@
+//! Reading stored series back from the outputs that keep them
+//!
+//! Files (including their rotated segments), SQLite databases and round-robin files can be
+//! read back. Network outputs, snapshots and Prometheus only ever hold the latest values, if
+//! anything, so there is nothing to read from them.
+
+use std::io::{self, BufReader};
+use std::iter;
+
+use time::Timespec;
+
+use conf::Config;
+use item::valid_key;
+use output::{OutputConfig, OutputKind, Sample};
+use output::file::Records;
+use output::rotate::{self, Segment};
+use output::rrd;
+use output::sqlite::Readings;
+
+/// Samples of a key in order of time, as far as they were written in order
+pub type Series = Box<dyn Iterator<Item = io::Result<Sample>>>;
+
+/// Whether values written to an output of `kind` can be read back
+pub fn readable(kind: &OutputKind) -> bool {
+    matches!(*kind, OutputKind::File { .. } | OutputKind::Sqlite { .. } | OutputKind::Rrd { .. })
+}
+
+/// The output to read `key` from: the one named `name`, or else the first one that can be read
+/// back of those the item recording `key` writes to
+pub fn source<'a>(config: &'a Config, key: &str, name: Option<&str>) -> Result<&'a OutputConfig, String> {
+    if let Some(name) = name {
+        let output = config.outputs.iter().find(|o| o.name == name)
+            .ok_or_else(|| format!("There is no output named {}", name))?;
+        if !readable(&output.kind) {
+            return Err(format!("Values written to output {} cannot be read back", name));
+        }
+        return Ok(output);
+    }
+
+    let item = config.items.iter().chain(config.derived.iter()).find(|i| i.provides(key));
+    let routed = item.and_then(|i| i.outputs.as_ref());
+    config.outputs.iter()
+        .filter(|o| routed.is_none_or(|names| names.contains(&o.name)))
+        .find(|o| readable(&o.kind))
+        .ok_or_else(|| format!("None of the outputs of {} keep values that can be read back", key))
+}
+
+/// The samples of `key` in `output` from `from` to before `to`, in seconds since the epoch
+pub fn read(output: &OutputConfig, key: &str, from: i64, to: i64) -> io::Result<Series> {
+    let invalid_key = || io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a valid key", key));
+    let series: Series = match output.kind {
+        OutputKind::File { ref directory, layout, .. } => {
+            let path = layout.path(directory, key).ok_or_else(invalid_key)?;
+            let segments = rotate::segments(&path)?;
+            if segments.is_empty() && !path.exists() {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} does not exist", path.display())));
+            }
+
+            // A segment only has samples from between the end of the one before it and its own
+            // end, the file itself has all after the last segment
+            let mut parts = Vec::new();
+            let mut start = i64::MIN;
+            for segment in segments.into_iter().chain(iter::once(Segment {
+                path,
+                end: i64::MAX,
+                compression: None,
+            })) {
+                let wanted = segment.end > from && start < to;
+                start = segment.end;
+                if wanted {
+                    parts.push(segment);
+                }
+            }
+
+            let key = key.to_owned();
+            Box::new(parts.into_iter().flat_map(move |segment| -> Series {
+                match segment.open().and_then(|r| Records::new(BufReader::new(r), &key)) {
+                    Ok(records) => Box::new(records),
+                    // The file is only created once the next value is written after rotating
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound && segment.end == i64::MAX => {
+                        Box::new(iter::empty())
+                    }
+                    Err(e) => Box::new(iter::once(Err(e))),
+                }
+            }))
+        }
+        OutputKind::Sqlite { ref path, .. } => Box::new(Readings::open(path, key, from, to)?),
+        OutputKind::Rrd { ref directory, .. } => {
+            if !valid_key(key) {
+                return Err(invalid_key());
+            }
+            let key = key.to_owned();
+            let values = rrd::read(&directory.join(format!("{}.rrd", key)), None, from, to)?;
+            Box::new(values.into_iter().map(move |(time, value)| Ok(Sample {
+                time: Timespec::new(time, 0),
+                key: key.clone(),
+                value: value.to_string(),
+            })))
+        }
+        _ => {
+            return Err(io::Error::new(io::ErrorKind::Unsupported,
+                                      format!("values written to output {} cannot be read back", output.name)));
+        }
+    };
+    Ok(Box::new(series.filter(move |s| match *s {
+        Ok(ref s) => s.time.sec >= from && s.time.sec < to,
+        Err(_) => true,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    use flate2::Compression as GzLevel;
+    use flate2::write::GzEncoder;
+
+    use output::{OutputConfig, OutputKind};
+    use output::file::{Layout, Writing};
+    use output::format::{Format, Timestamp};
+    use output::rotate::Rotation;
+    use series;
+
+    #[test]
+    fn reads_segments() {
+        let dir = env::temp_dir().join("antikoerper-series");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut gz = GzEncoder::new(File::create(dir.join("os.battery@20160201T120000Z.gz")).unwrap(),
+                                    GzLevel::default());
+        gz.write_all(b"1454327940 100\n1454327970 99\n").unwrap();
+        gz.finish().unwrap();
+        fs::write(dir.join("os.battery@20160201T120100Z"),
+                  "# antikoerper csv v1 epoch\ntime,value\n1454328000,98\n1454328030,97\n").unwrap();
+        fs::write(dir.join("os.battery"), "# antikoerper text v1 rfc3339\n2016-02-01T12:01:00.000Z 96\n")
+            .unwrap();
+
+        let output = OutputConfig {
+            name: String::from("files"),
+            kind: OutputKind::File {
+                directory: dir.clone(),
+                layout: Layout::Flat,
+                format: Format::Text,
+                timestamp: Timestamp::Epoch,
+                rotation: Rotation::default(),
+                writing: Writing::default(),
+            },
+        };
+        let read = |from: i64, to: i64| series::read(&output, "os.battery", from, to).unwrap()
+            .map(|s| s.map(|s| (s.time.sec, s.value)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(read(i64::MIN, i64::MAX).len(), 5);
+        assert_eq!(read(1454327970, 1454328060), vec![(1454327970, String::from("99")),
+                                                       (1454328000, String::from("98")),
+                                                       (1454328030, String::from("97"))]);
+        // The compressed segment is not even opened
+        fs::write(dir.join("os.battery@20160201T120000Z.gz"), "garbage").unwrap();
+        assert_eq!(read(1454328030, i64::MAX), vec![(1454328030, String::from("97")),
+                                                     (1454328060, String::from("96"))]);
+        assert!(series::read(&output, "os.unknown", i64::MIN, i64::MAX).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
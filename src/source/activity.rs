@@ -0,0 +1,240 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::Mutex;
+
+use glob::Pattern;
+
+/// The sub-keys of the item key values are recorded under
+pub const SUB_KEYS: &[&str] = &["idle", "active", "sessions"];
+
+/// Interrupt count and the time it last changed, per item key
+static INTERRUPTS: Mutex<BTreeMap<String, (u64, i64)>> = Mutex::new(BTreeMap::new());
+
+/// Where to look for signs of user activity
+#[derive(Debug, Clone, Copy)]
+pub struct Sources<'a> {
+    /// Usually `/proc/interrupts`
+    pub interrupts_file: &'a Path,
+    /// Interrupt names of input devices, like `i8042`
+    pub interrupts: &'a [String],
+    /// Input device nodes, like `/dev/input/event*`
+    pub devices: &'a Pattern,
+    /// Usually `/run/systemd/sessions`
+    pub sessions: &'a Path,
+    /// The directory session TTYs are relative to, usually `/dev`
+    pub tty_dir: &'a Path,
+}
+
+/// Derives how long the user has been idle from the given sources.
+///
+/// The last activity is the most recent of
+///
+/// - the last time the interrupt count of the input devices changed between two runs
+/// - the last access or modification of the input device nodes
+/// - the last access of the TTY of an active logind session
+///
+/// The results are recorded under these sub-keys of `key`:
+///
+/// - `idle`, seconds since the last activity
+/// - `active`, 1 if `idle` is less than `idle_after`, 0 otherwise
+/// - `sessions`, the number of active logind sessions
+///
+/// Nothing is recorded as long as there is no sign of activity at all, e.g. on the first run
+/// when only interrupts can be read.
+pub fn idle(key: &str, now: i64, sources: &Sources, idle_after: i64)
+    -> Result<Vec<(String, String)>, String>
+{
+    let mut last = None;
+    let mut readable = false;
+
+    if !sources.interrupts.is_empty() {
+        if let Some(count) = count_interrupts(sources.interrupts_file, sources.interrupts) {
+            readable = true;
+            let mut state = INTERRUPTS.lock().unwrap();
+            let changed = match state.get(key) {
+                Some(&(previous, _)) if previous == count => None,
+                Some(_) => Some(now),
+                None => None,
+            };
+            let entry = state.entry(key.to_owned()).or_insert((count, i64::MIN));
+            entry.0 = count;
+            if let Some(c) = changed {
+                entry.1 = c;
+            }
+            if entry.1 != i64::MIN {
+                last = latest(last, entry.1);
+            }
+        }
+    }
+
+    let devices = glob::glob(sources.devices.as_str())
+        .map_err(|e| format!("Invalid device pattern: {}\n{}", sources.devices, e))?;
+    for device in devices.filter_map(Result::ok) {
+        if let Ok(meta) = fs::metadata(&device) {
+            readable = true;
+            last = latest(last, meta.atime().max(meta.mtime()));
+        }
+    }
+
+    let mut sessions = 0;
+    if let Ok(entries) = fs::read_dir(sources.sessions) {
+        readable = true;
+        for entry in entries.filter_map(Result::ok) {
+            let session = match read_session(&entry.path()) {
+                Some(s) => s,
+                None => continue,
+            };
+            if session.get("ACTIVE").map(|s| &s[..]) != Some("1") {
+                continue;
+            }
+            sessions += 1;
+            if let Some(tty) = session.get("TTY") {
+                if let Ok(meta) = fs::metadata(sources.tty_dir.join(tty)) {
+                    last = latest(last, meta.atime());
+                }
+            }
+        }
+    }
+
+    if !readable {
+        return Err(String::from("Could not read any source of user activity"));
+    }
+
+    let last = match last {
+        Some(l) => l,
+        None => {
+            debug!("No activity seen yet for {}", key);
+            return Ok(Vec::new());
+        }
+    };
+    let idle = (now - last).max(0);
+
+    Ok(vec![
+        (format!("{}.idle", key), format!("{}\n", idle)),
+        (format!("{}.active", key), format!("{}\n", if idle < idle_after { 1 } else { 0 })),
+        (format!("{}.sessions", key), format!("{}\n", sessions)),
+    ])
+}
+
+fn latest(last: Option<i64>, time: i64) -> Option<i64> {
+    Some(last.map_or(time, |l| l.max(time)))
+}
+
+/// Sums up the counts on all CPUs of interrupts whose description contains one of `names`
+fn count_interrupts(path: &Path, names: &[String]) -> Option<u64> {
+    let mut content = String::new();
+    if let Err(e) = File::open(path).and_then(|mut f| f.read_to_string(&mut content)) {
+        warn!("Could not read interrupts: {}\n{}", path.display(), e);
+        return None;
+    }
+
+    let mut lines = content.lines();
+    let cpus = lines.next()?.split_whitespace().count();
+    let mut total = 0;
+    for line in lines {
+        let mut columns = match line.split_once(':') {
+            Some((_, rest)) => rest.split_whitespace(),
+            None => continue,
+        };
+        let counts = columns.by_ref().take(cpus).map(|c| c.parse::<u64>().unwrap_or(0)).sum::<u64>();
+        let description = columns.collect::<Vec<_>>().join(" ");
+        if names.iter().any(|n| description.contains(&n[..])) {
+            total += counts;
+        }
+    }
+    Some(total)
+}
+
+/// Parses a logind session file, which consists of `KEY=value` lines
+fn read_session(path: &Path) -> Option<BTreeMap<String, String>> {
+    let mut content = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut content)).ok()?;
+    Some(content.lines()
+         .filter_map(|l| l.split_once('='))
+         .map(|(k, v)| (k.to_owned(), v.to_owned()))
+         .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File, FileTimes};
+    use std::io::Write;
+    use std::path::Path;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use glob::Pattern;
+
+    use source::activity::{self, Sources};
+
+    fn touch(path: &Path, time: u64) {
+        let time = UNIX_EPOCH + Duration::from_secs(time);
+        File::create(path).unwrap()
+            .set_times(FileTimes::new().set_accessed(time).set_modified(time)).unwrap();
+    }
+
+    fn value(results: &[(String, String)], key: &str) -> String {
+        results.iter().find(|r| r.0 == key).unwrap().1.clone()
+    }
+
+    #[test]
+    fn idle_from_fixtures() {
+        let root = env::temp_dir().join("antikoerper-activity");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("input")).unwrap();
+        fs::create_dir_all(root.join("sessions")).unwrap();
+
+        let interrupts = root.join("interrupts");
+        let write_interrupts = |keyboard: u64| {
+            write!(File::create(&interrupts).unwrap(),
+                   "           CPU0       CPU1\n\
+                    \x20 1:   {}   5   IO-APIC   1-edge      i8042\n\
+                    \x20 8:   7    3   IO-APIC   8-edge      rtc0\n\
+                    NMI:      0    0   Non-maskable interrupts\n", keyboard).unwrap();
+        };
+        write_interrupts(10);
+
+        let names = vec![String::from("i8042")];
+        let devices = Pattern::new(root.join("input/event*").to_str().unwrap()).unwrap();
+        let sessions = root.join("sessions");
+        let sources = Sources {
+            interrupts_file: &interrupts,
+            interrupts: &names,
+            devices: &devices,
+            sessions: &sessions,
+            tty_dir: &root,
+        };
+
+        // Only the interrupt count as source, nothing known yet
+        assert!(activity::idle("user", 1000, &sources, 300).unwrap().is_empty());
+        write_interrupts(12);
+        let results = activity::idle("user", 1010, &sources, 300).unwrap();
+        assert_eq!(value(&results, "user.idle"), "0\n");
+        let results = activity::idle("user", 1400, &sources, 300).unwrap();
+        assert_eq!(value(&results, "user.idle"), "390\n");
+        assert_eq!(value(&results, "user.active"), "0\n");
+
+        touch(&root.join("input/event0"), 1350);
+        touch(&root.join("tty2"), 1390);
+        write!(File::create(root.join("sessions/1")).unwrap(), "ACTIVE=1\nTTY=tty2\n").unwrap();
+        write!(File::create(root.join("sessions/2")).unwrap(), "ACTIVE=0\nTTY=tty3\n").unwrap();
+        let results = activity::idle("user", 1400, &sources, 300).unwrap();
+        assert_eq!(value(&results, "user.idle"), "10\n");
+        assert_eq!(value(&results, "user.active"), "1\n");
+        assert_eq!(value(&results, "user.sessions"), "1\n");
+
+        let missing = root.join("missing");
+        let nothing = Sources {
+            interrupts_file: &missing,
+            interrupts: &names,
+            devices: &Pattern::new(missing.join("*").to_str().unwrap()).unwrap(),
+            sessions: &missing,
+            tty_dir: &missing,
+        };
+        assert!(activity::idle("nothing", 1400, &nothing, 300).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,77 @@
+//! Gathering of values for the different kinds of items
+
+use std::fs::File;
+use std::io::Read;
+use std::process::Command;
+
+use item::{Item, ItemKind};
+
+pub mod activity;
+pub mod path_stat;
+pub mod sqlite;
+
+/// Runs `item` once and returns the keys and values it recorded, or a description of what
+/// went wrong. `shell` is used to run shell items.
+pub fn gather(item: &Item, shell: &str, cur_time: i64) -> Result<Vec<(String, String)>, String> {
+    let mut result = String::new();
+    match item.kind {
+        ItemKind::File(ref path) => {
+            let mut f = match File::open(path) {
+                Ok(f) => f,
+                Err(e) => return Err(format!("Could not open file: {}\n{}", path.display(), e)),
+            };
+            match f.read_to_string(&mut result) {
+                Ok(_) => (),
+                Err(e) => return Err(format!("Could read output from file: {},\n{}", path.display(), e)),
+            }
+            Ok(vec![(item.key.clone(), result)])
+        }
+        ItemKind::Command(ref path, ref args) => {
+            let mut output = Command::new(path);
+            output.args(args);
+            output.envs(&item.env);
+            let output = match output.output() {
+                Ok(f) => f,
+                Err(e) => return Err(format!("Could not run command: {}\n{}", path.display(), e))
+            };
+            result = match String::from_utf8(output.stdout) {
+                Ok(r) => r,
+                Err(e) => return Err(format!("Could not read output from command: {}\n{}", path.display(), e))
+            };
+            Ok(vec![(item.key.clone(), result)])
+        }
+        ItemKind::Shell(ref command) => {
+            let mut output = Command::new(shell);
+            output.arg("-c");
+            output.arg(command);
+            output.envs(&item.env);
+            let output = match output.output() {
+                Ok(f) => f,
+                Err(e) => return Err(format!("Could not run shell command: {}\n{}", command, e))
+            };
+            result = match String::from_utf8(output.stdout) {
+                Ok(r) => r,
+                Err(e) => return Err(format!("Could not read output from shell command: {}\n{}", command, e))
+            };
+            Ok(vec![(item.key.clone(), result)])
+        }
+        ItemKind::Sqlite { ref path, ref query, busy_timeout } => {
+            sqlite::query(&item.key, path, query, busy_timeout)
+        }
+        ItemKind::PathStat { ref path, ref include, ref exclude, max_depth } => {
+            path_stat::stat(&item.key, path, include, exclude, max_depth)
+        }
+        ItemKind::Activity { ref interrupts_file, ref interrupts, ref devices, ref sessions,
+                             ref tty_dir, idle_after } => {
+            let sources = activity::Sources {
+                interrupts_file,
+                interrupts,
+                devices,
+                sessions,
+                tty_dir,
+            };
+            activity::idle(&item.key, cur_time, &sources, idle_after)
+        }
+        ItemKind::Derived(_) => unreachable!("derived items are not scheduled"),
+    }
+}
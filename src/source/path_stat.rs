@@ -0,0 +1,159 @@
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+use std::os::unix::fs::MetadataExt;
+
+use glob::Pattern;
+
+/// The sub-keys of the item key values are recorded under
+pub const SUB_KEYS: &[&str] = &["files", "bytes", "usage", "age"];
+
+#[derive(Debug, Default)]
+struct Stat {
+    files: u64,
+    bytes: u64,
+    usage: u64,
+    newest: Option<SystemTime>,
+}
+
+impl Stat {
+    fn add(&mut self, meta: &fs::Metadata) {
+        self.files += 1;
+        self.bytes += meta.len();
+        self.usage += meta.blocks() * 512;
+        if let Ok(modified) = meta.modified() {
+            if self.newest.is_none_or(|n| modified > n) {
+                self.newest = Some(modified);
+            }
+        }
+    }
+}
+
+/// Collects statistics about the file or directory tree at `path`, without following symlinks.
+///
+/// Files are only counted if their path relative to `path` matches one of `include` (or
+/// `include` is empty) and none of `exclude`. Directories matching `exclude` are not descended
+/// into, neither are directories deeper than `max_depth`, the entries of `path` being at depth 1.
+///
+/// The results are recorded under these sub-keys of `key`:
+///
+/// - `files`, the number of counted files
+/// - `bytes`, their total size
+/// - `usage`, the disk space they use
+/// - `age`, seconds since the most recently modified of them was changed, or `path` itself if
+///   nothing was counted
+pub fn stat(key: &str, path: &Path, include: &[Pattern], exclude: &[Pattern], max_depth: Option<u64>)
+    -> Result<Vec<(String, String)>, String>
+{
+    let meta = fs::symlink_metadata(path)
+        .map_err(|e| format!("Could not stat path: {}\n{}", path.display(), e))?;
+
+    let mut stat = Stat::default();
+    if meta.is_dir() {
+        walk(path, path, 1, include, exclude, max_depth, &mut stat);
+    } else {
+        stat.add(&meta);
+    }
+
+    let newest = match stat.newest {
+        Some(n) => n,
+        None => meta.modified()
+            .map_err(|e| format!("Could not read modification time: {}\n{}", path.display(), e))?,
+    };
+    let age = SystemTime::now().duration_since(newest).map(|d| d.as_secs()).unwrap_or(0);
+
+    Ok(vec![
+        (format!("{}.files", key), format!("{}\n", stat.files)),
+        (format!("{}.bytes", key), format!("{}\n", stat.bytes)),
+        (format!("{}.usage", key), format!("{}\n", stat.usage)),
+        (format!("{}.age", key), format!("{}\n", age)),
+    ])
+}
+
+fn walk(root: &Path, dir: &Path, depth: u64, include: &[Pattern], exclude: &[Pattern],
+        max_depth: Option<u64>, stat: &mut Stat)
+{
+    if max_depth.is_some_and(|m| depth > m) {
+        return;
+    }
+
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) => return warn!("Could not read directory: {}\n{}", dir.display(), e),
+    };
+
+    for entry in entries {
+        let entry = match entry {
+            Ok(e) => e,
+            Err(e) => {
+                warn!("Could not read directory entry in: {}\n{}", dir.display(), e);
+                continue;
+            }
+        };
+        let path = entry.path();
+        let relative = path.strip_prefix(root).unwrap_or(&path);
+        if exclude.iter().any(|p| p.matches_path(relative)) {
+            continue;
+        }
+
+        let meta = match entry.metadata() {
+            Ok(m) => m,
+            Err(e) => {
+                warn!("Could not stat path: {}\n{}", path.display(), e);
+                continue;
+            }
+        };
+
+        if meta.is_dir() {
+            walk(root, &path, depth + 1, include, exclude, max_depth, stat);
+        } else if include.is_empty() || include.iter().any(|p| p.matches_path(relative)) {
+            stat.add(&meta);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+
+    use glob::Pattern;
+
+    use source::path_stat;
+
+    fn value(results: &[(String, String)], key: &str) -> String {
+        results.iter().find(|r| r.0 == key).unwrap().1.clone()
+    }
+
+    #[test]
+    fn directory_tree() {
+        let root = env::temp_dir().join("antikoerper-path-stat");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("sub/deeper")).unwrap();
+        fs::File::create(root.join("a.iso")).unwrap().write_all(&[0; 100]).unwrap();
+        fs::File::create(root.join("b.part")).unwrap().write_all(&[0; 10]).unwrap();
+        fs::File::create(root.join("sub/c.iso")).unwrap().write_all(&[0; 20]).unwrap();
+        fs::File::create(root.join("sub/deeper/d.iso")).unwrap().write_all(&[0; 5]).unwrap();
+
+        let all = path_stat::stat("dl", &root, &[], &[], None).unwrap();
+        assert_eq!(value(&all, "dl.files"), "4\n");
+        assert_eq!(value(&all, "dl.bytes"), "135\n");
+        assert_eq!(value(&all, "dl.age"), "0\n");
+
+        let exclude = vec![Pattern::new("*.part").unwrap(), Pattern::new("sub/deeper").unwrap()];
+        let filtered = path_stat::stat("dl", &root, &[], &exclude, None).unwrap();
+        assert_eq!(value(&filtered, "dl.files"), "2\n");
+        assert_eq!(value(&filtered, "dl.bytes"), "120\n");
+
+        let include = vec![Pattern::new("*.iso").unwrap()];
+        let shallow = path_stat::stat("dl", &root, &include, &[], Some(2)).unwrap();
+        assert_eq!(value(&shallow, "dl.files"), "2\n");
+
+        let file = path_stat::stat("dl", &root.join("b.part"), &include, &[], None).unwrap();
+        assert_eq!(value(&file, "dl.bytes"), "10\n");
+
+        assert!(path_stat::stat("dl", &root.join("missing"), &[], &[], None).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
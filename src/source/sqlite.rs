@@ -0,0 +1,92 @@
+use std::path::Path;
+use std::time::Duration;
+
+use rusqlite::{Connection, OpenFlags};
+use rusqlite::types::ValueRef;
+
+/// Runs `query` against the database at `path` and returns the values of the first row.
+///
+/// A query returning a single column is recorded under `key` itself, if there are several
+/// columns each of them is recorded under `key.<column name>`, with slashes and dots in column
+/// names replaced by underscores. NULL values are skipped.
+/// The database is opened read-only, if it is locked we wait at most `busy_timeout`
+/// milliseconds for it.
+pub fn query(key: &str, path: &Path, query: &str, busy_timeout: u64)
+    -> Result<Vec<(String, String)>, String>
+{
+    let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+    let conn = Connection::open_with_flags(path, flags)
+        .map_err(|e| format!("Could not open database: {}\n{}", path.display(), e))?;
+    conn.busy_timeout(Duration::from_millis(busy_timeout))
+        .map_err(|e| format!("Could not set busy timeout on database: {}\n{}", path.display(), e))?;
+
+    let mut stmt = conn.prepare(query)
+        .map_err(|e| format!("Could not prepare query: {}\n{}", query, e))?;
+    let columns = stmt.column_names().iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    let mut rows = stmt.query([])
+        .map_err(|e| format!("Could not run query: {}\n{}", query, e))?;
+
+    let row = match rows.next() {
+        Ok(Some(row)) => row,
+        Ok(None) => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Could not read row from query: {}\n{}", query, e)),
+    };
+
+    let mut results = Vec::new();
+    for (i, column) in columns.iter().enumerate() {
+        let value = match row.get_ref(i) {
+            Ok(ValueRef::Null) => continue,
+            Ok(ValueRef::Integer(x)) => x.to_string(),
+            Ok(ValueRef::Real(x)) => x.to_string(),
+            Ok(ValueRef::Text(x)) => String::from_utf8_lossy(x).into_owned(),
+            Ok(ValueRef::Blob(_)) => return Err(format!("Column '{}' is a blob, which cannot be recorded", column)),
+            Err(e) => return Err(format!("Could not read column '{}': {}", column, e)),
+        };
+        let key = if columns.len() == 1 {
+            key.to_owned()
+        } else if column.is_empty() {
+            format!("{}.column{}", key, i)
+        } else {
+            // Column names can contain anything, but must not point outside the output
+            format!("{}.{}", key, column.replace(['/', '.', '@', '\0'], "_"))
+        };
+        results.push((key, value + "\n"));
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use rusqlite::Connection;
+
+    use source::sqlite;
+
+    #[test]
+    fn scalar_and_columns() {
+        let path = env::temp_dir().join("antikoerper-sqlite-source.db");
+        let _ = fs::remove_file(&path);
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch("CREATE TABLE tasks (done INTEGER, title TEXT);
+                                INSERT INTO tasks VALUES (1, 'a'), (0, 'b'), (1, NULL);").unwrap();
+        }
+
+        let scalar = sqlite::query("tasks.done", &path, "SELECT sum(done) FROM tasks", 100).unwrap();
+        assert_eq!(scalar, vec![(String::from("tasks.done"), String::from("2\n"))]);
+
+        let columns = sqlite::query("tasks", &path,
+                                    "SELECT count(*) AS total, max(title) AS 'last/../x', NULL AS missing FROM tasks",
+                                    100).unwrap();
+        assert_eq!(columns, vec![(String::from("tasks.total"), String::from("3\n")),
+                                 (String::from("tasks.last____x"), String::from("b\n"))]);
+
+        let empty = sqlite::query("tasks", &path, "SELECT done FROM tasks WHERE done > 1", 100).unwrap();
+        assert!(empty.is_empty());
+
+        assert!(sqlite::query("tasks", &path, "DELETE FROM tasks", 100).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
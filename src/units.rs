@@ -0,0 +1,110 @@
+//! Parsing of sizes, durations and times given in the configuration or on the commandline
+
+use time::{self, Timespec};
+use toml;
+
+/// Parses a number of bytes like `512`, `64K`, `10M` or `1G`, in powers of 1024
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (number, factor) = match s.char_indices().last()? {
+        (i, 'K') | (i, 'k') => (&s[..i], 1 << 10),
+        (i, 'M') | (i, 'm') => (&s[..i], 1 << 20),
+        (i, 'G') | (i, 'g') => (&s[..i], 1 << 30),
+        (i, 'T') | (i, 't') => (&s[..i], 1 << 40),
+        _ => (s, 1),
+    };
+    number.trim().parse::<u64>().ok()?.checked_mul(factor)
+}
+
+/// Parses a number of seconds like `90`, `90s`, `15m`, `2h`, `7d`, `4w` or `1y`, a year
+/// being 365 days
+pub fn parse_duration(s: &str) -> Option<i64> {
+    let s = s.trim();
+    let (number, factor) = match s.char_indices().last()? {
+        (i, 's') => (&s[..i], 1),
+        (i, 'm') => (&s[..i], 60),
+        (i, 'h') => (&s[..i], 60 * 60),
+        (i, 'd') => (&s[..i], 24 * 60 * 60),
+        (i, 'w') => (&s[..i], 7 * 24 * 60 * 60),
+        (i, 'y') => (&s[..i], 365 * 24 * 60 * 60),
+        _ => (s, 1),
+    };
+    let number = number.trim().parse::<i64>().ok()?;
+    if number < 0 {
+        return None;
+    }
+    number.checked_mul(factor)
+}
+
+/// Parses a point in time like `now`, `1454328000` (seconds since the epoch),
+/// `2016-02-01T12:00:00Z` (UTC), `2016-02-01 13:00:00` or `2016-02-01` (both local time)
+pub fn parse_time(s: &str, now: i64) -> Option<i64> {
+    let s = s.trim();
+    if s == "now" {
+        return Some(now);
+    }
+    if !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()) {
+        return s.parse().ok();
+    }
+    if let Some(utc) = s.strip_suffix('Z') {
+        return time::strptime(utc, "%Y-%m-%dT%H:%M:%S").ok().map(|tm| tm.to_timespec().sec);
+    }
+    let tm = time::strptime(s, "%Y-%m-%d %H:%M:%S")
+        .or_else(|_| time::strptime(s, "%Y-%m-%d"))
+        .ok()?;
+    // Parsed as if it was UTC, so the offset of local time around then is taken off
+    let sec = tm.to_timespec().sec;
+    Some(sec - time::at(Timespec::new(sec, 0)).tm_utcoff as i64)
+}
+
+/// A size given either as a positive integer of bytes or as a string for `parse_size`
+pub fn size_from_toml(value: &toml::Value) -> Option<u64> {
+    match *value {
+        toml::Value::Integer(i) if i > 0 => Some(i as u64),
+        toml::Value::String(ref s) => parse_size(s).and_then(|s| if s > 0 { Some(s) } else { None }),
+        _ => None,
+    }
+}
+
+/// A duration given either as a positive integer of seconds or as a string for
+/// `parse_duration`
+pub fn duration_from_toml(value: &toml::Value) -> Option<i64> {
+    match *value {
+        toml::Value::Integer(i) if i > 0 => Some(i),
+        toml::Value::String(ref s) => parse_duration(s).and_then(|d| if d > 0 { Some(d) } else { None }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use units::{parse_duration, parse_size, parse_time};
+
+    #[test]
+    fn sizes_and_durations() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("64K"), Some(64 * 1024));
+        assert_eq!(parse_size("10 M"), Some(10 * 1024 * 1024));
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size("-1"), None);
+
+        assert_eq!(parse_duration("90"), Some(90));
+        assert_eq!(parse_duration("15m"), Some(15 * 60));
+        assert_eq!(parse_duration("7d"), Some(7 * 24 * 60 * 60));
+        assert_eq!(parse_duration("1y"), Some(365 * 24 * 60 * 60));
+        assert_eq!(parse_duration("-2h"), None);
+        assert_eq!(parse_duration("2x"), None);
+    }
+
+    #[test]
+    fn times() {
+        let now = 1454328000;
+        assert_eq!(parse_time("now", now), Some(now));
+        assert_eq!(parse_time("1454320000", now), Some(1454320000));
+        assert_eq!(parse_time("2016-02-01T12:00:00Z", now), Some(1454328000));
+        let day = parse_time("2016-02-01", now).unwrap();
+        assert_eq!(parse_time("2016-02-01 12:00:00", now), Some(day + 12 * 60 * 60));
+        assert_eq!(parse_time("yesterday", now), None);
+        assert_eq!(parse_time("-5", now), None);
+    }
+}